@@ -38,11 +38,16 @@ defmodule AshTypst do
 
       subgraph "Compiled Output"
         PD["PagedDocument (cached)"]
+        HD["HtmlDocument (cached)"]
       end
 
       PD -->|render_svg| SVG[SVG string]
       PD -->|export_pdf| PDF[PDF binary]
-      W  -->|export_html| HTML[HTML string]
+      PD -->|render_raster / render_region| IMG[PNG / JPEG / WebP]
+      PD -->|write_pdf / write_svg / write_raster| FILE[File on disk]
+      PD -->|export_bundle| BUNDLE[Files or ZIP]
+      HD -->|export_html| HTML[HTML string]
+      HD -->|export_epub| EPUB[EPUB binary]
     end
   ```
 
@@ -50,8 +55,9 @@ defmodule AshTypst do
 
   - The `TypstContext` is a Rust NIF resource held as an opaque reference in Elixir.
   - Fonts are scanned once at context creation and reused for every compile.
-  - `compile/1` stores a `PagedDocument`; `render_svg/2` and `export_pdf/2` read from it without recompiling.
-  - `export_html/1` performs its own compilation (HTML uses a different document type internally).
+  - `compile/2` stores a `PagedDocument`, an `HtmlDocument`, or both (`target: :both`) from one compile cycle.
+  - `render_svg/2` and `export_pdf/2` read the paged document, and `export_html/2` the HTML document, without recompiling.
+  - `export_html/2` and `export_epub/2` compile the HTML document on demand if it was not compiled yet.
   - Virtual files and `sys.inputs` persist across compiles until explicitly changed.
 
   ## Quick start
@@ -67,7 +73,7 @@ defmodule AshTypst do
       {:ok, svg} = AshTypst.Context.render_svg(ctx, page: 0)
 
       # Export the full document as PDF
-      {:ok, pdf_binary} = AshTypst.Context.export_pdf(ctx)
+      {:ok, %AshTypst.PDFResult{data: pdf_binary}} = AshTypst.Context.export_pdf(ctx)
 
   ## Data injection
 
@@ -93,6 +99,15 @@ defmodule AshTypst do
 
       AshTypst.Context.set_inputs(ctx, %{"theme" => "dark", "locale" => "en"})
 
+  ### Globals
+
+  Shared constants and Typst helpers can be added to the standard library's
+  global scope, so templates use them without an import:
+
+      AshTypst.Context.set_global(ctx, "company", %{name: "Acme Corp", vat: 0.19})
+      AshTypst.Context.set_global_module(ctx, "#let shout(x) = upper(x)")
+      AshTypst.Context.set_markup(ctx, "#shout(company.name)")
+
   ## Data encoding
 
   The `AshTypst.Code` protocol converts Elixir values to Typst source syntax.
@@ -0,0 +1,27 @@
+defmodule AshTypst.BundleOutput do
+  @moduledoc """
+  One requested output of `AshTypst.Context.export_bundle/3`.
+
+  `pages` is a 1-indexed page range string like `"1-3,5"` and applies to
+  page outputs (`:svg`, `:png`, `:jpeg`, `:webp`), which produce one file per
+  page. Their `name` may contain `{page}`, which is replaced by the page
+  number. `pdf_options` applies to `:pdf` outputs, `svg_options` to `:svg`
+  outputs, and `raster_options` to image outputs, whose `format` must match
+  the output's.
+  """
+  defstruct format: :pdf,
+            name: nil,
+            pages: nil,
+            pdf_options: nil,
+            svg_options: nil,
+            raster_options: nil
+
+  @type t :: %__MODULE__{
+          format: :pdf | :svg | :png | :jpeg | :webp,
+          name: String.t() | nil,
+          pages: String.t() | nil,
+          pdf_options: AshTypst.PDFOptions.t() | nil,
+          svg_options: AshTypst.SVGOptions.t() | nil,
+          raster_options: AshTypst.RasterOptions.t() | nil
+        }
+end
@@ -0,0 +1,15 @@
+defmodule AshTypst.BundleResult do
+  @moduledoc """
+  Result of `AshTypst.Context.export_bundle/3`.
+
+  Holds the outputs in `files`, keyed by name, or, with `zip: true`, a single
+  ZIP `archive` containing them.
+  """
+  defstruct files: %{}, archive: nil, warnings: []
+
+  @type t :: %__MODULE__{
+          files: %{String.t() => binary()},
+          archive: binary() | nil,
+          warnings: [AshTypst.Diagnostic.t()]
+        }
+end
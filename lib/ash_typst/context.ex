@@ -7,9 +7,10 @@ defmodule AshTypst.Context do
 
   1. `new/1` — create a context (scans fonts, sets root path)
   2. `set_markup/2` — load a Typst template
-  3. Optionally inject data via `set_virtual_file/3`, `stream_virtual_file/4`, or `set_inputs/2`
-  4. `compile/1` — compile the markup into a paged document
-  5. `render_svg/2` or `export_pdf/2` — render output from the compiled document
+  3. Optionally inject data via `set_virtual_file/3`, `stream_virtual_file/4`, `set_inputs/2`,
+     or `set_global/4`
+  4. `compile/2` — compile the markup into a paged and/or HTML document
+  5. `render_svg/2`, `export_pdf/2`, or `export_html/2` — render output from the compiled document
 
   Steps 2-5 can be repeated without re-creating the context. Fonts and
   virtual files persist until explicitly changed.
@@ -35,18 +36,40 @@ defmodule AshTypst.Context do
     * `:root` — root path for template resolution (default `"."`)
     * `:font_paths` — additional font directories to search
     * `:ignore_system_fonts` — skip system fonts (default `false`)
+    * `:default_styles` — house style inherited by every template, as a
+      keyword list or `AshTypst.DefaultStyles` struct
+    * `:features` — Typst features to enable (default `[:html]`); available
+      features are `:html` and `:a11y_extras`
+
+  Returns `{:error, %CompileError{}}` if the default styles are invalid,
+  e.g. an unknown font family or paper size, or if a feature is unknown.
   """
-  @spec new(keyword() | AshTypst.Context.Options.t()) :: {:ok, t()}
+  @spec new(keyword() | AshTypst.Context.Options.t()) ::
+          {:ok, t()} | {:error, AshTypst.CompileError.t()}
   def new(opts \\ [])
 
   def new(%AshTypst.Context.Options{} = opts) do
-    {:ok, NIF.context_new(opts)}
+    opts
+    |> Map.update!(:default_styles, &normalize_styles/1)
+    |> Map.update!(:features, &Enum.map(&1, &to_string/1))
+    |> NIF.context_new()
   end
 
   def new(opts) when is_list(opts) do
     new(struct!(AshTypst.Context.Options, opts))
   end
 
+  defp normalize_styles(styles) when is_list(styles) do
+    normalize_styles(struct!(AshTypst.DefaultStyles, styles))
+  end
+
+  defp normalize_styles(%AshTypst.DefaultStyles{margin: margin} = styles)
+       when is_integer(margin) do
+    %{styles | margin: margin / 1}
+  end
+
+  defp normalize_styles(%AshTypst.DefaultStyles{} = styles), do: styles
+
   @doc "Set the main Typst markup. Invalidates any compiled document."
   @spec set_markup(t(), String.t()) :: :ok
   def set_markup(ctx, markup) when is_binary(markup) do
@@ -58,10 +81,18 @@ defmodule AshTypst.Context do
 
   Returns `{:ok, %CompileResult{}}` with the page count and warnings,
   or `{:error, %CompileError{}}` with diagnostics.
+
+  ## Options
+
+    * `:target` — which documents to build: `:paged` (default) for
+      `render_svg/2` and `export_pdf/2`, `:html` for `export_html/2`, or
+      `:both` to build both in one compile cycle. The page count is `0`
+      for `:html`, and warnings from both targets are merged for `:both`.
   """
-  @spec compile(t()) :: {:ok, AshTypst.CompileResult.t()} | {:error, AshTypst.CompileError.t()}
-  def compile(ctx) do
-    NIF.context_compile(ctx)
+  @spec compile(t(), keyword()) ::
+          {:ok, AshTypst.CompileResult.t()} | {:error, AshTypst.CompileError.t()}
+  def compile(ctx, opts \\ []) do
+    NIF.context_compile(ctx, Keyword.get(opts, :target, :paged))
   end
 
   @doc """
@@ -70,34 +101,553 @@ defmodule AshTypst.Context do
   ## Options
 
     * `:page` — zero-indexed page number (default `0`)
+    * `:impose` — lay several pages out per sheet first; a keyword list or
+      `AshTypst.Imposition`. `:page` then selects a sheet.
+    * `:overlays` — watermarks or stamps drawn over the pages; a list of
+      keyword lists or `AshTypst.Overlay` structs with either `:text` or an
+      SVG, PNG, or JPEG `:image`, and the `:position`, `:rotation`,
+      `:opacity`, and `:pages` to stamp
+
+  ## Examples
+
+      # Two A5 pages side by side on each A4 landscape sheet
+      AshTypst.Context.render_svg(ctx, impose: [columns: 2, width: 842, height: 595])
+
+      AshTypst.Context.render_svg(ctx, overlays: [[text: "DRAFT", rotation: -45, opacity: 0.2]])
   """
   @spec render_svg(t(), keyword()) :: {:ok, String.t()} | {:error, AshTypst.CompileError.t()}
   def render_svg(ctx, opts \\ []) do
-    page = Keyword.get(opts, :page, 0)
-    NIF.context_render_svg(ctx, page)
+    {page, opts} = Keyword.pop(opts, :page, 0)
+    NIF.context_render_svg(ctx, page, svg_options(opts))
+  end
+
+  defp svg_options(opts) do
+    AshTypst.SVGOptions
+    |> struct!(opts)
+    |> Map.update!(:impose, &imposition/1)
+    |> Map.update!(:overlays, &overlays/1)
+  end
+
+  defp overlays(overlays), do: Enum.map(overlays, &overlay/1)
+
+  defp overlay(opts) when is_list(opts), do: overlay(struct!(AshTypst.Overlay, opts))
+
+  defp overlay(%AshTypst.Overlay{} = overlay) do
+    %{
+      overlay
+      | dx: overlay.dx / 1,
+        dy: overlay.dy / 1,
+        rotation: overlay.rotation / 1,
+        opacity: overlay.opacity / 1,
+        size: overlay.size / 1,
+        width: overlay.width && overlay.width / 1
+    }
+  end
+
+  defp imposition(nil), do: nil
+  defp imposition(opts) when is_list(opts), do: imposition(struct!(AshTypst.Imposition, opts))
+
+  defp imposition(%AshTypst.Imposition{} = imposition) do
+    %{
+      imposition
+      | gutter: imposition.gutter / 1,
+        margin: imposition.margin / 1,
+        width: imposition.width && imposition.width / 1,
+        height: imposition.height && imposition.height / 1
+    }
   end
 
   @doc """
-  Export the compiled document as a PDF binary.
+  Export the compiled document as PDF.
+
+  Returns `{:ok, %PDFResult{}}` with the PDF binary, export warnings, and the
+  embedding permissions of the fonts used on the exported pages.
 
   ## Options
 
     * `:pages` — page range string like `"1-3,5,7-9"` (1-indexed)
     * `:pdf_standards` — list of standards, e.g. `[:pdf_a_2b]`
     * `:document_id` — stable identifier for caching
+    * `:font_embedding` — policy for fonts whose license forbids embedding:
+      `:warn` (default) adds a warning, `:error` refuses the export, and
+      `:ignore` skips the check
+    * `:encryption` — encrypt the PDF with AES-256; a keyword list or
+      `AshTypst.PDFEncryption` with `:owner_password`, `:user_password`, and
+      the `:print`, `:copy`, `:modify`, and `:annotate` permissions. Both
+      passwords are required unless `allow_open: true` lets anyone open the
+      file. Not allowed with PDF/A standards.
+    * `:signature` — sign the PDF with a PAdES-compatible signature; a keyword
+      list or `AshTypst.PDFSignature` with either `:pkcs12` and `:password`
+      or PEM `:key` and `:certificates`, an optional `:label` for a visible
+      signature box, and `:reason`, `:location`, and `:contact` signer details
+    * `:metadata` — override the document metadata; a keyword list or
+      `AshTypst.PDFMetadata` with `:title`, `:authors`, `:subject`,
+      `:keywords`, `:language`, and custom `:xmp` properties
+    * `:tagged` — write a tagged PDF with the document structure for assistive
+      technology (default `true`)
+    * `:print` — print production settings; a keyword list or
+      `AshTypst.PDFPrint` with the `:bleed` in points and whether to draw
+      `:crop_marks` and `:registration_marks`. Sets the TrimBox and BleedBox
+      of every page.
+    * `:color` — convert colours to CMYK for print; a keyword list or
+      `AshTypst.PDFColor` with the ICC `:profile` binary and the rendering
+      `:intent`. The profile becomes the PDF output intent.
+    * `:impose` — lay several pages out per sheet, e.g. 2-up, 4-up, or as a
+      booklet; a keyword list or `AshTypst.Imposition`. `:pages` then selects
+      sheets.
+    * `:overlays` — watermarks or stamps drawn over the pages, like the
+      `render_svg/2` option of the same name
+    * `:optimize` — make the file smaller; a keyword list or
+      `AshTypst.PDFOptimize` with the `:max_dpi` of images, the
+      `:jpeg_quality` for photos, and the stream `:compression` level. The
+      result's `optimization` reports what changed.
+    * `:linearize` — write a linearized ("fast web view") PDF with hint
+      tables, so browsers can show the first page while the rest downloads
+      (default `false`). Works with PDF/A standards, but not with
+      `:encryption` or `:signature`.
+
+  ## Examples
+
+      AshTypst.Context.export_pdf(ctx,
+        encryption: [user_password: "1234", owner_password: "s3cret", print: false, copy: false]
+      )
+
+      AshTypst.Context.export_pdf(ctx,
+        metadata: [title: "Invoice 2024-001", xmp: %{"InvoiceNumber" => "2024-001"}]
+      )
+
+      {:ok, %AshTypst.PDFResult{optimization: %{original_image_size: before, image_size: after}}} =
+        AshTypst.Context.export_pdf(ctx, optimize: [max_dpi: 150, jpeg_quality: 80])
+
+      AshTypst.Context.export_pdf(ctx, linearize: true, pdf_standards: [:pdf_a_2b])
+
+      AshTypst.Context.export_pdf(ctx,
+        signature: [
+          pkcs12: File.read!("signer.p12"),
+          password: "secret",
+          label: "signature",
+          reason: "Contract approval"
+        ]
+      )
   """
   @spec export_pdf(t(), keyword() | AshTypst.PDFOptions.t()) ::
-          {:ok, binary()} | {:error, AshTypst.CompileError.t()}
+          {:ok, AshTypst.PDFResult.t()} | {:error, AshTypst.CompileError.t()}
   def export_pdf(ctx, opts \\ [])
 
   def export_pdf(ctx, %AshTypst.PDFOptions{} = opts) do
-    NIF.context_export_pdf(ctx, opts)
+    NIF.context_export_pdf(ctx, pdf_options(opts))
   end
 
   def export_pdf(ctx, opts) when is_list(opts) do
     export_pdf(ctx, struct!(AshTypst.PDFOptions, opts))
   end
 
+  # Nested options may be given as keyword lists or structs, and numbers as
+  # integers; the NIF takes structs and floats.
+  defp pdf_options(%AshTypst.PDFOptions{} = opts) do
+    opts
+    |> Map.update!(:encryption, fn
+      encryption when is_list(encryption) -> struct!(AshTypst.PDFEncryption, encryption)
+      encryption -> encryption
+    end)
+    |> Map.update!(:signature, fn
+      nil -> nil
+      signature when is_list(signature) ->
+        signature_options(struct!(AshTypst.PDFSignature, signature))
+
+      signature -> signature_options(signature)
+    end)
+    |> Map.update!(:metadata, fn
+      nil -> nil
+      metadata when is_list(metadata) -> metadata_options(struct!(AshTypst.PDFMetadata, metadata))
+      metadata -> metadata_options(metadata)
+    end)
+    |> Map.update!(:print, fn
+      nil -> nil
+      print when is_list(print) -> print_options(struct!(AshTypst.PDFPrint, print))
+      print -> print_options(print)
+    end)
+    |> Map.update!(:color, fn
+      color when is_list(color) -> struct!(AshTypst.PDFColor, color)
+      color -> color
+    end)
+    |> Map.update!(:impose, &imposition/1)
+    |> Map.update!(:overlays, &overlays/1)
+    |> Map.update!(:optimize, fn
+      nil -> nil
+      optimize when is_list(optimize) -> optimize_options(struct!(AshTypst.PDFOptimize, optimize))
+      optimize -> optimize_options(optimize)
+    end)
+  end
+
+  defp optimize_options(%AshTypst.PDFOptimize{max_dpi: max_dpi} = optimize) do
+    %{optimize | max_dpi: max_dpi && max_dpi / 1}
+  end
+
+  defp print_options(%AshTypst.PDFPrint{bleed: bleed, mark_length: mark_length} = print) do
+    %{print | bleed: bleed / 1, mark_length: mark_length / 1}
+  end
+
+  defp metadata_options(%AshTypst.PDFMetadata{xmp: xmp} = metadata) do
+    %{metadata | xmp: Map.new(xmp, fn {key, value} -> {to_string(key), to_string(value)} end)}
+  end
+
+  defp signature_options(%AshTypst.PDFSignature{width: width, height: height} = signature) do
+    %{signature | width: width / 1, height: height / 1}
+  end
+
+  @doc """
+  Check the compiled document for common accessibility problems.
+
+  Returns `{:ok, findings}` where each finding is a warning
+  `AshTypst.Diagnostic` with a hint, pointing at the source span where one is
+  known. It reports a missing document title or language and images without
+  alternative text, which keep tagged PDFs from meeting PDF/UA. An empty list
+  means no problems were found.
+
+  ## Example
+
+      {:ok, findings} = AshTypst.Context.accessibility_report(ctx)
+      for %{message: message, span: span} <- findings do
+        IO.puts("#{message} at #{inspect(span)}")
+      end
+  """
+  @spec accessibility_report(t()) ::
+          {:ok, [AshTypst.Diagnostic.t()]} | {:error, AshTypst.CompileError.t()}
+  def accessibility_report(ctx) do
+    NIF.context_accessibility_report(ctx)
+  end
+
+  @doc """
+  Remove text from the compiled document.
+
+  Each entry of `targets` is a string, matched literally, or a `Regex`. The
+  `i`, `s`, `m`, `x`, `U`, and `u` modifiers are honoured; a regex with any
+  other modifier raises `ArgumentError`. The matching glyphs are dropped from
+  the pages, not just hidden, and a box is drawn in their place. Matches are
+  also replaced with `"[redacted]"` in heading bodies, which the PDF outline
+  shows, in link URLs and their alternative text, and in the document title,
+  authors, description, and keywords. Every later PDF, SVG, and raster export
+  uses the redacted document, until the next `compile/2`.
+
+  Text is matched within each run of text as laid out, so a match cannot span
+  a line break or a change of style. HTML and EPUB are compiled from the
+  markup, which still holds the text, so `export_html/2` and `export_epub/2`
+  return an error until the next `compile/2`.
+
+  Returns `{:ok, redactions}` with an `AshTypst.Redaction` for every match:
+  those on the pages in page order, then those in the metadata.
+
+  ## Options
+
+    * `:color` — hex colour of the redaction boxes (default `"#000000"`)
+
+  ## Example
+
+      {:ok, redactions} =
+        AshTypst.Context.redact(ctx, [~r/DE\\d{20}/, customer.national_id])
+
+      {:ok, %AshTypst.PDFResult{data: pdf}} = AshTypst.Context.export_pdf(ctx)
+  """
+  @spec redact(t(), [String.t() | Regex.t()], keyword()) ::
+          {:ok, [AshTypst.Redaction.t()]} | {:error, AshTypst.CompileError.t()}
+  def redact(ctx, targets, opts \\ []) do
+    {patterns, literals} = Enum.split_with(targets, &is_struct(&1, Regex))
+
+    opts =
+      struct!(AshTypst.RedactOptions,
+        patterns: Enum.map(patterns, &redaction_pattern/1),
+        literals: literals,
+        color: Keyword.get(opts, :color, "#000000")
+      )
+
+    NIF.context_redact(ctx, opts)
+  end
+
+  # The NIF's regex engine takes modifiers as inline flags. Its patterns are
+  # Unicode-aware anyway, so `u` needs no flag. With `x`, a trailing comment
+  # must end before the pattern is wrapped in a group.
+  defp redaction_pattern(regex) do
+    opts = Regex.opts(regex)
+
+    flags =
+      Enum.map_join(opts, fn
+        :caseless -> "i"
+        :dotall -> "s"
+        :multiline -> "m"
+        :extended -> "x"
+        :ungreedy -> "U"
+        opt when opt in [:unicode, :ucp] -> ""
+        opt -> raise ArgumentError, "unsupported redaction regex option: #{inspect(opt)}"
+      end)
+
+    cond do
+      flags == "" -> Regex.source(regex)
+      :extended in opts -> "(?#{flags})#{Regex.source(regex)}\n"
+      true -> "(?#{flags})#{Regex.source(regex)}"
+    end
+  end
+
+  @doc """
+  Split the compiled document into several PDFs.
+
+  `by` is either a list of page range strings, one per PDF, using the same
+  syntax as the `:pages` option (e.g. `["1-2", "3-5"]`), or `{:label, name}`
+  to start a new PDF on each page where an element labelled `<name>` appears.
+  With a label, any pages before its first occurrence form a PDF of their own.
+
+  Every part is exported with the same options as `export_pdf/2`, except
+  `:pages`, which cannot be used here. Each part gets its own document ID,
+  derived from `:document_id` (or the document's title and authors) and the
+  part's position. Returns `{:ok, [%PDFResult{}]}` in page order.
+
+  ## Example
+
+      # Each letter starts with `#metadata(none) <letter-start>`
+      {:ok, letters} = AshTypst.Context.split_pdf(ctx, {:label, "letter-start"})
+  """
+  @spec split_pdf(
+          t(),
+          [String.t()] | {:label, String.t()},
+          keyword() | AshTypst.PDFOptions.t()
+        ) :: {:ok, [AshTypst.PDFResult.t()]} | {:error, AshTypst.CompileError.t()}
+  def split_pdf(ctx, by, opts \\ [])
+
+  def split_pdf(ctx, by, opts) when is_list(opts) do
+    split_pdf(ctx, by, struct!(AshTypst.PDFOptions, opts))
+  end
+
+  def split_pdf(ctx, {:label, label}, %AshTypst.PDFOptions{} = opts) do
+    NIF.context_split_pdf(ctx, [], to_string(label), pdf_options(opts))
+  end
+
+  def split_pdf(ctx, ranges, %AshTypst.PDFOptions{} = opts) when is_list(ranges) do
+    NIF.context_split_pdf(ctx, ranges, nil, pdf_options(opts))
+  end
+
+  @doc """
+  Merge the compiled documents of several contexts into one PDF.
+
+  Pages appear in the order of `contexts`. The PDF gets a combined outline,
+  page labels numbered continuously across all documents, and the metadata
+  (title, author, and so on) of the first document. Accepts the same options
+  as `export_pdf/2`; `:pages` applies to the merged page sequence.
+
+  ## Example
+
+      {:ok, %AshTypst.PDFResult{data: packet}} =
+        AshTypst.Context.merge_pdf([cover_ctx, invoice_ctx, terms_ctx])
+  """
+  @spec merge_pdf([t()], keyword() | AshTypst.PDFOptions.t()) ::
+          {:ok, AshTypst.PDFResult.t()} | {:error, AshTypst.CompileError.t()}
+  def merge_pdf(contexts, opts \\ [])
+
+  def merge_pdf(contexts, %AshTypst.PDFOptions{} = opts) when is_list(contexts) do
+    NIF.merge_pdf(contexts, pdf_options(opts))
+  end
+
+  def merge_pdf(contexts, opts) when is_list(contexts) and is_list(opts) do
+    merge_pdf(contexts, struct!(AshTypst.PDFOptions, opts))
+  end
+
+  @doc """
+  Export the compiled document as PDF straight to a file.
+
+  Unlike `export_pdf/2`, the PDF never enters the BEAM heap. Accepts the same
+  options as `export_pdf/2`. Returns `{:ok, %WriteResult{}}` with the byte
+  count, SHA-256 checksum, and export warnings.
+  """
+  @spec write_pdf(t(), Path.t(), keyword() | AshTypst.PDFOptions.t()) ::
+          {:ok, AshTypst.WriteResult.t()} | {:error, AshTypst.CompileError.t()}
+  def write_pdf(ctx, path, opts \\ [])
+
+  def write_pdf(ctx, path, %AshTypst.PDFOptions{} = opts) do
+    NIF.context_write_pdf(ctx, to_string(path), pdf_options(opts))
+  end
+
+  def write_pdf(ctx, path, opts) when is_list(opts) do
+    write_pdf(ctx, path, struct!(AshTypst.PDFOptions, opts))
+  end
+
+  @doc """
+  Render a page of the compiled document as SVG straight to a file.
+
+  Accepts the same options as `render_svg/2`.
+  """
+  @spec write_svg(t(), Path.t(), keyword()) ::
+          {:ok, AshTypst.WriteResult.t()} | {:error, AshTypst.CompileError.t()}
+  def write_svg(ctx, path, opts \\ []) do
+    {page, opts} = Keyword.pop(opts, :page, 0)
+    NIF.context_write_svg(ctx, to_string(path), page, svg_options(opts))
+  end
+
+  @doc """
+  Render a page of the compiled document as a PNG, JPEG, or WebP image.
+
+  ## Options
+
+    * `:page` — zero-indexed page number (default `0`)
+    * `:format` — `:png` (default), `:jpeg`, or `:webp`
+    * `:pixel_per_pt` — resolution in pixels per point when no size is given
+      (default `2.0`, i.e. 144 DPI)
+    * `:width` / `:height` — target size in pixels
+    * `:fit` — `:contain` (default), `:cover`, or `:stretch` when both
+      `:width` and `:height` are given
+    * `:quality` — JPEG quality from 1 to 100 (default `85`); not accepted
+      for the lossless PNG and WebP formats
+    * `:background` — `:transparent`, a hex colour like `"#ffffff"`, or `nil`
+      (default) to keep the page fill
+    * `:impose` — lay several pages out per sheet first; a keyword list or
+      `AshTypst.Imposition`. `:page` then selects a sheet.
+    * `:overlays` — watermarks or stamps drawn over the pages, like the
+      `render_svg/2` option of the same name
+
+  See `AshTypst.RasterOptions` for details.
+  """
+  @spec render_raster(t(), keyword()) :: {:ok, binary()} | {:error, AshTypst.CompileError.t()}
+  def render_raster(ctx, opts \\ []) do
+    {page, opts} = Keyword.pop(opts, :page, 0)
+    NIF.context_render_raster(ctx, page, raster_options(opts))
+  end
+
+  @doc """
+  Render a page of the compiled document as an image straight to a file.
+
+  Accepts the same options as `render_raster/2`.
+  """
+  @spec write_raster(t(), Path.t(), keyword()) ::
+          {:ok, AshTypst.WriteResult.t()} | {:error, AshTypst.CompileError.t()}
+  def write_raster(ctx, path, opts \\ []) do
+    {page, opts} = Keyword.pop(opts, :page, 0)
+    NIF.context_write_raster(ctx, to_string(path), page, raster_options(opts))
+  end
+
+  @doc """
+  Render a rectangle of a page as SVG or as an image.
+
+  `region` is `{x, y, width, height}` in points, measured from the page's
+  top-left corner — the same units as the page size. Only that part of the
+  page is rendered, so a small region can be rasterized at a high resolution.
+
+  ## Options
+
+    * `:page` — zero-indexed page number (default `0`)
+    * `:format` — `:svg` (returns a string), or `:png` (default), `:jpeg`,
+      or `:webp`
+    * `:impose` and `:overlays` for SVG, like the `render_svg/2` options
+    * any other `render_raster/2` option for image formats, applied to the
+      region; e.g. `pixel_per_pt: 600 / 72` renders at 600 DPI
+  """
+  @spec render_region(t(), {number(), number(), number(), number()}, keyword()) ::
+          {:ok, binary()} | {:error, AshTypst.CompileError.t()}
+  def render_region(ctx, {x, y, width, height}, opts \\ []) do
+    {page, opts} = Keyword.pop(opts, :page, 0)
+    region = {x / 1, y / 1, width / 1, height / 1}
+
+    case Keyword.pop(opts, :format, :png) do
+      {:svg, opts} -> NIF.context_render_region_svg(ctx, page, region, svg_options(opts))
+      _ -> NIF.context_render_region_raster(ctx, page, region, raster_options(opts))
+    end
+  end
+
+  defp raster_options(opts) do
+    AshTypst.RasterOptions
+    |> struct!(opts)
+    |> Map.update!(:pixel_per_pt, &(&1 / 1))
+    |> Map.update!(:impose, &imposition/1)
+    |> Map.update!(:overlays, &overlays/1)
+    |> Map.update!(:background, fn
+      :transparent -> "transparent"
+      background -> background
+    end)
+  end
+
+  @doc """
+  Export several outputs from one snapshot of the compiled document.
+
+  `outputs` is a list of `{format, options}` tuples (or bare formats, or
+  `AshTypst.BundleOutput` structs), where format is `:pdf`, `:svg`, `:png`,
+  `:jpeg`, or `:webp`. Returns `{:ok, %BundleResult{}}` with every output
+  keyed by name.
+
+  ## Output options
+
+    * `:name` — file name; defaults to `"document.pdf"` for PDF and
+      `"page-{page}.svg"`, `"page-{page}.png"`, and so on for pages. `{page}`
+      is replaced by the 1-indexed page number and is required when a page
+      output covers several pages.
+    * `:pages` — page range string for page outputs like `"1-3,5"`
+      (default all pages)
+    * any `render_svg/2` option for `:svg`
+    * any `render_raster/2` option for `:png`, `:jpeg`, and `:webp`
+    * any `export_pdf/2` option for `:pdf`
+
+  ## Options
+
+    * `:zip` — return the outputs as a single ZIP `archive` instead of
+      `files` (default `false`)
+
+  ## Example
+
+      AshTypst.Context.export_bundle(ctx, [
+        pdf: [pdf_standards: [:pdf_a_2b]],
+        jpeg: [name: "thumbnail.jpg", pages: "1", width: 320, quality: 80],
+        svg: []
+      ])
+  """
+  @spec export_bundle(
+          t(),
+          [atom() | {atom(), keyword()} | AshTypst.BundleOutput.t()],
+          keyword()
+        ) :: {:ok, AshTypst.BundleResult.t()} | {:error, AshTypst.CompileError.t()}
+  def export_bundle(ctx, outputs, opts \\ []) do
+    outputs = Enum.map(outputs, &bundle_output/1)
+    NIF.context_export_bundle(ctx, outputs, Keyword.get(opts, :zip, false))
+  end
+
+  defp bundle_output(
+         %AshTypst.BundleOutput{pdf_options: %AshTypst.PDFOptions{} = pdf} = output
+       ) do
+    %{output | pdf_options: pdf_options(pdf)}
+  end
+
+  defp bundle_output(%AshTypst.BundleOutput{} = output), do: output
+  defp bundle_output(format) when is_atom(format), do: bundle_output({format, []})
+
+  defp bundle_output({:pdf, opts}) do
+    {name, opts} = Keyword.pop(opts, :name)
+
+    %AshTypst.BundleOutput{
+      format: :pdf,
+      name: name,
+      pdf_options: pdf_options(struct!(AshTypst.PDFOptions, opts))
+    }
+  end
+
+  defp bundle_output({:svg, opts}) do
+    {name, opts} = Keyword.pop(opts, :name)
+    {pages, opts} = Keyword.pop(opts, :pages)
+
+    %AshTypst.BundleOutput{
+      format: :svg,
+      name: name,
+      pages: pages,
+      svg_options: svg_options(opts)
+    }
+  end
+
+  defp bundle_output({format, opts}) when format in [:png, :jpeg, :webp] do
+    {name, opts} = Keyword.pop(opts, :name)
+    {pages, opts} = Keyword.pop(opts, :pages)
+
+    %AshTypst.BundleOutput{
+      format: format,
+      name: name,
+      pages: pages,
+      raster_options: raster_options(Keyword.put(opts, :format, format))
+    }
+  end
+
   @doc "List font families available in this context."
   @spec font_families(t()) :: [String.t()]
   def font_families(ctx) do
@@ -113,8 +663,8 @@ defmodule AshTypst.Context do
   @doc """
   Append a chunk to a virtual file (creates it if new).
 
-  Does **not** invalidate the compiled document — call `compile/1`
-  after streaming is complete.
+  Invalidates the compiled document — call `compile/1` after streaming is
+  complete.
   """
   @spec append_virtual_file(t(), String.t(), String.t()) :: :ok
   def append_virtual_file(ctx, path, chunk) when is_binary(path) and is_binary(chunk) do
@@ -127,6 +677,21 @@ defmodule AshTypst.Context do
     NIF.context_clear_virtual_file(ctx, path)
   end
 
+  @doc """
+  Register a WebAssembly plugin under a virtual path.
+
+  The bytes are validated as a Typst plugin before they are stored, so
+  templates can load vendored plugins with `plugin("qr.wasm")` without disk
+  or network access. Invalidates the compiled document.
+
+      wasm = File.read!(Application.app_dir(:my_app, "priv/typst/qr.wasm"))
+      :ok = AshTypst.Context.set_plugin(ctx, "qr.wasm", wasm)
+  """
+  @spec set_plugin(t(), String.t(), binary()) :: :ok | {:error, AshTypst.CompileError.t()}
+  def set_plugin(ctx, path, wasm) when is_binary(path) and is_binary(wasm) do
+    NIF.context_set_plugin(ctx, path, wasm)
+  end
+
   @doc """
   Stream an Elixir enumerable into a virtual file as a Typst array.
 
@@ -161,25 +726,130 @@ defmodule AshTypst.Context do
     NIF.context_append_virtual_file(ctx, path, ")\n")
   end
 
-  @doc "Set a single `sys.inputs` key/value pair."
+  @doc "Set a single `sys.inputs` key/value pair. Invalidates the compiled document."
   @spec set_input(t(), String.t(), String.t()) :: :ok
   def set_input(ctx, key, value) when is_binary(key) and is_binary(value) do
     NIF.context_set_input(ctx, key, value)
   end
 
-  @doc "Replace all `sys.inputs` with the given map of string keys/values."
+  @doc """
+  Replace all `sys.inputs` with the given map of string keys/values.
+  Invalidates the compiled document.
+  """
   @spec set_inputs(t(), %{String.t() => String.t()}) :: :ok
   def set_inputs(ctx, inputs) when is_map(inputs) do
     NIF.context_set_inputs(ctx, inputs)
   end
 
+  @doc """
+  Define a global binding available to every template in this context.
+
+  The value is encoded with `AshTypst.Code.encode/2`, so templates can
+  reference it directly, e.g. `#company.name`, without an import. `name` must
+  be a valid Typst identifier. Invalidates the compiled document.
+
+  ## Options
+
+    * `:context` — encoding context passed to `AshTypst.Code.encode/2`
+  """
+  @spec set_global(t(), String.t(), term(), keyword()) ::
+          :ok | {:error, AshTypst.CompileError.t()}
+  def set_global(ctx, name, value, opts \\ []) when is_binary(name) do
+    code = AshTypst.Code.encode(value, opts[:context] || %{})
+    NIF.context_set_global(ctx, name, code)
+  end
+
+  @doc """
+  Evaluate Typst source as a module and add it to the global scope.
+
+  By default every top-level binding of the module (e.g. a `#let money(x) = ...`
+  helper) becomes a global. With `:as`, the module is bound under that name
+  instead, so templates call `fmt.money(...)`. Diagnostics in the module
+  source carry its line and column. Invalidates the compiled document.
+
+  ## Options
+
+    * `:as` — bind the whole module under this name, a valid Typst identifier
+  """
+  @spec set_global_module(t(), String.t(), keyword()) ::
+          :ok | {:error, AshTypst.CompileError.t()}
+  def set_global_module(ctx, source, opts \\ []) when is_binary(source) do
+    NIF.context_set_global_module(ctx, opts[:as], source)
+  end
+
+  @doc """
+  Remove all globals defined with `set_global/4` or `set_global_module/3`.
+  Invalidates the compiled document.
+  """
+  @spec clear_globals(t()) :: :ok
+  def clear_globals(ctx) do
+    NIF.context_clear_globals(ctx)
+  end
+
   @doc """
   Export the document as HTML.
 
-  Performs its own compilation (separate from `compile/1`).
+  Uses the HTML document from the last `compile(ctx, target: :html)` or
+  `compile(ctx, target: :both)`. If there is none, the markup is compiled for
+  HTML first and the result is kept for later exports.
+
+  Returns `{:ok, %HTMLResult{}}` with the HTML string, the warnings from the
+  HTML compilation, and any extracted image assets.
+
+  ## Options
+
+    * `:body_only` — return only the contents of `<body>` (default `false`)
+    * `:images` — `:inline` (default) keeps images as data URIs, `:assets`
+      moves them into the result's `assets` map
+    * `:asset_prefix` — prefix for extracted image references, e.g.
+      `"/assets/"` or `"cid:"` (default `""`)
+    * `:head` — extra content appended to `<head>`, such as stylesheets and
+      meta tags; cannot be combined with `:body_only`
+  """
+  @spec export_html(t(), keyword() | AshTypst.HTMLOptions.t()) ::
+          {:ok, AshTypst.HTMLResult.t()} | {:error, AshTypst.CompileError.t()}
+  def export_html(ctx, opts \\ [])
+
+  def export_html(ctx, %AshTypst.HTMLOptions{} = opts) do
+    NIF.context_export_html(ctx, opts)
+  end
+
+  def export_html(ctx, opts) when is_list(opts) do
+    export_html(ctx, struct!(AshTypst.HTMLOptions, opts))
+  end
+
+  @doc """
+  Export the document as a zipped EPUB 3 e-book.
+
+  Built from the same HTML document as `export_html/2`, compiling it on demand
+  if needed. The book is split into chapters at the top-level headings, with
+  a navigation document generated from the heading outline. Images are
+  embedded from the HTML output, and fonts from the context for the families
+  the document sets with `text(font: ..)` and the ones listed in `:fonts`.
+
+  Returns `{:ok, %EPUBResult{}}` with the `.epub` binary and the warnings from
+  the HTML compilation.
+
+  ## Options
+
+    * `:title` — book title (defaults to the document title, or `"Untitled"`)
+    * `:authors` — list of authors (defaults to the document authors)
+    * `:language` — book language (defaults to the document language)
+    * `:identifier` — unique identifier, e.g. `"urn:isbn:..."` (defaults to a
+      UUID derived from the content)
+    * `:fonts` — extra font families to embed and prefer for body text;
+      fonts whose license restricts embedding are rejected
+    * `:stylesheet` — extra CSS appended to the book's stylesheet
   """
-  @spec export_html(t()) :: {:ok, String.t()} | {:error, AshTypst.CompileError.t()}
-  def export_html(ctx) do
-    NIF.context_export_html(ctx)
+  @spec export_epub(t(), keyword() | AshTypst.EPUBOptions.t()) ::
+          {:ok, AshTypst.EPUBResult.t()} | {:error, AshTypst.CompileError.t()}
+  def export_epub(ctx, opts \\ [])
+
+  def export_epub(ctx, %AshTypst.EPUBOptions{} = opts) do
+    NIF.context_export_epub(ctx, opts)
+  end
+
+  def export_epub(ctx, opts) when is_list(opts) do
+    export_epub(ctx, struct!(AshTypst.EPUBOptions, opts))
   end
 end
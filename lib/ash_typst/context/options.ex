@@ -1,10 +1,16 @@
 defmodule AshTypst.Context.Options do
   @moduledoc "Options for creating a new context."
-  defstruct root: ".", font_paths: [], ignore_system_fonts: false
+  defstruct root: ".",
+            font_paths: [],
+            ignore_system_fonts: false,
+            default_styles: %AshTypst.DefaultStyles{},
+            features: [:html]
 
   @type t :: %__MODULE__{
           root: String.t(),
           font_paths: [String.t()],
-          ignore_system_fonts: boolean()
+          ignore_system_fonts: boolean(),
+          default_styles: AshTypst.DefaultStyles.t(),
+          features: [:html | :a11y_extras]
         }
 end
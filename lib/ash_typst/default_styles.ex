@@ -0,0 +1,28 @@
+defmodule AshTypst.DefaultStyles do
+  @moduledoc """
+  House style applied to every document compiled in a context.
+
+  These styles are injected into the Typst standard library, so templates
+  inherit them without a preamble. Templates can still override them with
+  their own `set` rules.
+
+    * `:font` — font family fallback list, e.g. `["Inter", "Noto Sans"]`
+    * `:lang` — ISO 639 language code, e.g. `"de"`
+    * `:region` — ISO 3166 region code, e.g. `"CH"`
+    * `:paper` — Typst paper name, e.g. `"a4"` or `"us-letter"`
+    * `:margin` — page margin on all sides, in points
+    * `:justify` — whether paragraphs are justified
+
+  Values are validated when the context is created.
+  """
+  defstruct font: [], lang: nil, region: nil, paper: nil, margin: nil, justify: nil
+
+  @type t :: %__MODULE__{
+          font: [String.t()],
+          lang: String.t() | nil,
+          region: String.t() | nil,
+          paper: String.t() | nil,
+          margin: number() | nil,
+          justify: boolean() | nil
+        }
+end
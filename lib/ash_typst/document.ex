@@ -3,15 +3,17 @@ defmodule AshTypst.Document do
   Wrapper struct for rendered Typst documents.
 
   This is the return type of all render actions declared via the `AshTypst.Resource`
-  extension. The `data` field contains the raw binary output (PDF, SVG string, or
-  HTML string) and `format` indicates which export was used.
+  extension. The `data` field contains the raw binary output (PDF, SVG string,
+  HTML string, or EPUB) and `format` indicates which export was used. For HTML exports
+  with `images: :assets`, `assets` maps extracted image names to their bytes.
   """
-  defstruct [:format, :data, :page_count, :warnings]
+  defstruct [:format, :data, :page_count, :warnings, assets: %{}]
 
   @type t :: %__MODULE__{
-          format: :pdf | :svg | :html,
+          format: :pdf | :svg | :html | :epub,
           data: binary(),
           page_count: non_neg_integer(),
-          warnings: [AshTypst.Diagnostic.t()]
+          warnings: [AshTypst.Diagnostic.t()],
+          assets: %{String.t() => binary()}
         }
 end
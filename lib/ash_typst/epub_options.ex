@@ -0,0 +1,25 @@
+defmodule AshTypst.EPUBOptions do
+  @moduledoc """
+  Options for EPUB export.
+
+  `title`, `authors` and `language` default to the document's own metadata
+  (`#set document(...)` and `#set text(lang: ...)`). `fonts` lists font
+  families from the context to embed in the book and prefer for body text, in
+  addition to the families the document itself uses.
+  """
+  defstruct title: nil,
+            authors: [],
+            language: nil,
+            identifier: nil,
+            fonts: [],
+            stylesheet: nil
+
+  @type t :: %__MODULE__{
+          title: String.t() | nil,
+          authors: [String.t()],
+          language: String.t() | nil,
+          identifier: String.t() | nil,
+          fonts: [String.t()],
+          stylesheet: String.t() | nil
+        }
+end
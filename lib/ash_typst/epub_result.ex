@@ -0,0 +1,9 @@
+defmodule AshTypst.EPUBResult do
+  @moduledoc "Result of a successful EPUB export."
+  defstruct data: <<>>, warnings: []
+
+  @type t :: %__MODULE__{
+          data: binary(),
+          warnings: [AshTypst.Diagnostic.t()]
+        }
+end
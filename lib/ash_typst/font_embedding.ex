@@ -0,0 +1,15 @@
+defmodule AshTypst.FontEmbedding do
+  @moduledoc """
+  Embedding permissions of a font used in an exported PDF.
+
+  `permission` reflects the font's OS/2 `fsType` flags. Fonts that are
+  `:restricted`, or that forbid subsetting, cannot legally be embedded.
+  """
+  defstruct [:family, :permission, :subsetting_allowed]
+
+  @type t :: %__MODULE__{
+          family: String.t(),
+          permission: :installable | :restricted | :preview_and_print | :editable | :unknown,
+          subsetting_allowed: boolean()
+        }
+end
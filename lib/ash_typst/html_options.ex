@@ -0,0 +1,11 @@
+defmodule AshTypst.HTMLOptions do
+  @moduledoc "Options for HTML export."
+  defstruct body_only: false, images: :inline, asset_prefix: "", head: nil
+
+  @type t :: %__MODULE__{
+          body_only: boolean(),
+          images: :inline | :assets,
+          asset_prefix: String.t(),
+          head: String.t() | nil
+        }
+end
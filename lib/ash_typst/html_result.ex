@@ -0,0 +1,15 @@
+defmodule AshTypst.HTMLResult do
+  @moduledoc """
+  Result of a successful HTML export.
+
+  `assets` holds the images extracted with `images: :assets`, keyed by file
+  name. The HTML references each one as `asset_prefix <> name`.
+  """
+  defstruct data: "", warnings: [], assets: %{}
+
+  @type t :: %__MODULE__{
+          data: String.t(),
+          warnings: [AshTypst.Diagnostic.t()],
+          assets: %{String.t() => binary()}
+        }
+end
@@ -0,0 +1,38 @@
+defmodule AshTypst.Imposition do
+  @moduledoc """
+  Imposition settings: several pages of the document per output sheet.
+
+  `order` decides how pages fill the sheets:
+
+    * `:rows` — a grid of `columns` by `rows` pages, filled row by row
+    * `:columns` — the same grid, filled column by column
+    * `:booklet` — two pages side by side in saddle-stitch order. The pages
+      are padded with blanks to a multiple of four, so folding the stacked,
+      double-sided sheets yields the pages in sequence. `columns` and `rows`
+      are ignored.
+
+  The sheet is `width` by `height` points, or the named `paper` (e.g.
+  `"a3"`), or by default just large enough for the grid of unscaled pages.
+  Pages are scaled to fit their cells, keeping their aspect ratio, with
+  `gutter` points between cells and `margin` points around the grid.
+  """
+  defstruct order: :rows,
+            columns: 2,
+            rows: 1,
+            paper: nil,
+            width: nil,
+            height: nil,
+            gutter: 0.0,
+            margin: 0.0
+
+  @type t :: %__MODULE__{
+          order: :rows | :columns | :booklet,
+          columns: pos_integer(),
+          rows: pos_integer(),
+          paper: String.t() | nil,
+          width: number() | nil,
+          height: number() | nil,
+          gutter: number(),
+          margin: number()
+        }
+end
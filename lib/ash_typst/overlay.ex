@@ -0,0 +1,55 @@
+defmodule AshTypst.Overlay do
+  @moduledoc """
+  A watermark or stamp drawn over the pages at export time.
+
+  An overlay is either `text` or an `image` (an SVG, PNG, or JPEG binary),
+  never both. Text is set in `size` points in the hex `color`, using `font`
+  or the context's default font; an image keeps its own size unless `width`
+  (in points) is given.
+
+  The overlay is rotated clockwise by `rotation` degrees, then placed at
+  `position` — `:center`, `:top_left`, `:top`, `:top_right`, `:left`,
+  `:right`, `:bottom_left`, `:bottom`, or `:bottom_right` — inside the page
+  and moved by `dx` and `dy` points. `opacity` runs from `0.0` (invisible) to
+  `1.0` (opaque). `pages` selects the pages to stamp, using the same syntax
+  as the PDF `:pages` option (e.g. `"1"` or `"2-"`); `nil` stamps every page.
+  """
+  defstruct text: nil,
+            image: nil,
+            pages: nil,
+            position: :center,
+            dx: 0.0,
+            dy: 0.0,
+            rotation: 0.0,
+            opacity: 0.5,
+            size: 48.0,
+            width: nil,
+            color: "#808080",
+            font: nil
+
+  @type position ::
+          :center
+          | :top_left
+          | :top
+          | :top_right
+          | :left
+          | :right
+          | :bottom_left
+          | :bottom
+          | :bottom_right
+
+  @type t :: %__MODULE__{
+          text: String.t() | nil,
+          image: binary() | nil,
+          pages: String.t() | nil,
+          position: position(),
+          dx: number(),
+          dy: number(),
+          rotation: number(),
+          opacity: number(),
+          size: number(),
+          width: number() | nil,
+          color: String.t(),
+          font: String.t() | nil
+        }
+end
@@ -0,0 +1,22 @@
+defmodule AshTypst.PDFColor do
+  @moduledoc """
+  CMYK colour conversion for print PDFs.
+
+  After export, the solid colours of text, shapes, and SVG images are
+  converted from sRGB to an ICC-based CMYK colour space through the ICC
+  `profile` with the given rendering `intent`, and the profile is embedded as
+  the PDF output intent (for PDF/A too when a PDF/A standard is selected).
+  Greys become pure black ink and colours that are already CMYK are kept.
+
+  Raster images, gradients, and tilings are left as they are and reported as
+  export warnings. `output_condition` names the printing condition and
+  defaults to the profile description.
+  """
+  defstruct profile: nil, intent: :relative_colorimetric, output_condition: nil
+
+  @type t :: %__MODULE__{
+          profile: binary(),
+          intent: :perceptual | :relative_colorimetric | :saturation | :absolute_colorimetric,
+          output_condition: String.t() | nil
+        }
+end
@@ -0,0 +1,30 @@
+defmodule AshTypst.PDFEncryption do
+  @moduledoc """
+  AES-256 encryption settings for PDF export.
+
+  Opening the PDF requires `user_password`, and `owner_password` grants full
+  access; both are required and must differ. To encrypt a file anyone can
+  open, with only the permission flags protected, leave `user_password` unset
+  and set `allow_open: true`. The permission flags restrict what readers
+  opening the file with the user password may do.
+
+  Encryption cannot be combined with PDF/A standards.
+  """
+  defstruct user_password: nil,
+            owner_password: nil,
+            allow_open: false,
+            print: true,
+            copy: true,
+            modify: true,
+            annotate: true
+
+  @type t :: %__MODULE__{
+          user_password: String.t() | nil,
+          owner_password: String.t(),
+          allow_open: boolean(),
+          print: boolean(),
+          copy: boolean(),
+          modify: boolean(),
+          annotate: boolean()
+        }
+end
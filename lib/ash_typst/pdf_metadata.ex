@@ -0,0 +1,32 @@
+defmodule AshTypst.PDFMetadata do
+  @moduledoc """
+  Document metadata overrides for PDF export.
+
+  `title`, `authors`, `subject`, and `language` replace the values the
+  template sets with `#set document(...)` or `#set text(lang: ...)`, while
+  `keywords` are added to the template's own. Unset fields keep the
+  template's values.
+
+  `xmp` holds custom properties for document management systems. They are
+  written to the XMP metadata in `xmp_namespace` under the `ashtypst` prefix,
+  along with the extension schema PDF/A requires for custom properties.
+  Property names must be valid XML names.
+  """
+  defstruct title: nil,
+            authors: [],
+            subject: nil,
+            keywords: [],
+            language: nil,
+            xmp: %{},
+            xmp_namespace: "https://hexdocs.pm/ash_typst/xmp/1.0/"
+
+  @type t :: %__MODULE__{
+          title: String.t() | nil,
+          authors: [String.t()],
+          subject: String.t() | nil,
+          keywords: [String.t()],
+          language: String.t() | nil,
+          xmp: %{optional(String.t() | atom()) => String.t()},
+          xmp_namespace: String.t()
+        }
+end
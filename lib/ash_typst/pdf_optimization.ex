@@ -0,0 +1,20 @@
+defmodule AshTypst.PDFOptimization do
+  @moduledoc """
+  What optimizing a PDF export achieved.
+
+  `images` is the number of distinct images that were downsampled or
+  re-encoded, and `original_image_size` and `image_size` their total encoded
+  byte size before and after. `original_size` is the byte size of the PDF
+  before stream compression and `size` the byte size of the PDF returned,
+  after every other step such as signing, encryption, or linearization.
+  """
+  defstruct original_size: 0, size: 0, images: 0, original_image_size: 0, image_size: 0
+
+  @type t :: %__MODULE__{
+          original_size: non_neg_integer(),
+          size: non_neg_integer(),
+          images: non_neg_integer(),
+          original_image_size: non_neg_integer(),
+          image_size: non_neg_integer()
+        }
+end
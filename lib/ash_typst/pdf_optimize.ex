@@ -0,0 +1,23 @@
+defmodule AshTypst.PDFOptimize do
+  @moduledoc """
+  Size optimization settings for PDF export.
+
+  `max_dpi` downsamples raster images whose resolution, at the largest size
+  they are shown at, is higher than that; `150` suits screens and `300`
+  print. `jpeg_quality` (1–100) re-encodes JPEG photos, keeping the result
+  only when it is smaller; downsampled JPEGs use it too, or `85` when it is
+  not set. PNG and other lossless images stay lossless. Images with a CMYK
+  or greyscale ICC profile are left as they are.
+
+  `compression` (0–9) recompresses the PDF's streams with Flate at that
+  level; `0` stores them uncompressed. The XMP metadata stays uncompressed
+  so the file remains valid PDF/A.
+  """
+  defstruct max_dpi: nil, jpeg_quality: nil, compression: nil
+
+  @type t :: %__MODULE__{
+          max_dpi: number() | nil,
+          jpeg_quality: 1..100 | nil,
+          compression: 0..9 | nil
+        }
+end
@@ -1,10 +1,34 @@
 defmodule AshTypst.PDFOptions do
   @moduledoc "Options for PDF export."
-  defstruct pages: nil, pdf_standards: [], document_id: nil
+  defstruct pages: nil,
+            pdf_standards: [],
+            document_id: nil,
+            font_embedding: :warn,
+            encryption: nil,
+            signature: nil,
+            metadata: nil,
+            tagged: true,
+            print: nil,
+            color: nil,
+            impose: nil,
+            overlays: [],
+            optimize: nil,
+            linearize: false
 
   @type t :: %__MODULE__{
           pages: String.t() | nil,
           pdf_standards: [:pdf_1_7 | :pdf_a_2b | :pdf_a_3b],
-          document_id: String.t() | nil
+          document_id: String.t() | nil,
+          font_embedding: :ignore | :warn | :error,
+          encryption: AshTypst.PDFEncryption.t() | nil,
+          signature: AshTypst.PDFSignature.t() | nil,
+          metadata: AshTypst.PDFMetadata.t() | nil,
+          tagged: boolean(),
+          print: AshTypst.PDFPrint.t() | nil,
+          color: AshTypst.PDFColor.t() | nil,
+          impose: AshTypst.Imposition.t() | nil,
+          overlays: [AshTypst.Overlay.t()],
+          optimize: AshTypst.PDFOptimize.t() | nil,
+          linearize: boolean()
         }
 end
@@ -0,0 +1,22 @@
+defmodule AshTypst.PDFPrint do
+  @moduledoc """
+  Print production settings for PDF export.
+
+  Every page is extended by `bleed` points on each side, so content and page
+  fills that run past the page edge are kept for trimming. The TrimBox marks
+  the original page and the BleedBox the extended one. With `crop_marks` or
+  `registration_marks`, a slug of `mark_length` plus 6 points is added
+  outside the bleed and the marks are drawn there, starting 3 points beyond
+  the bleed.
+
+  Lengths are in points; 3 mm of bleed is about `8.5`.
+  """
+  defstruct bleed: 0.0, crop_marks: false, registration_marks: false, mark_length: 15.0
+
+  @type t :: %__MODULE__{
+          bleed: number(),
+          crop_marks: boolean(),
+          registration_marks: boolean(),
+          mark_length: number()
+        }
+end
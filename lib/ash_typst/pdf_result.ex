@@ -0,0 +1,11 @@
+defmodule AshTypst.PDFResult do
+  @moduledoc "Result of a successful PDF export."
+  defstruct data: <<>>, warnings: [], fonts: [], optimization: nil
+
+  @type t :: %__MODULE__{
+          data: binary(),
+          warnings: [AshTypst.Diagnostic.t()],
+          fonts: [AshTypst.FontEmbedding.t()],
+          optimization: AshTypst.PDFOptimization.t() | nil
+        }
+end
@@ -0,0 +1,43 @@
+defmodule AshTypst.PDFSignature do
+  @moduledoc """
+  Digital signature settings for PDF export.
+
+  The PDF is signed with a PAdES-compatible detached CMS signature
+  (`ETSI.CAdES.detached`) appended as an incremental update. Provide the
+  signing credentials either as a PKCS#12 bundle in `pkcs12` (with its
+  `password`) or as a PEM private key in `key` and a PEM certificate chain in
+  `certificates`, signer certificate first. Only RSA keys are supported.
+
+  Without a `label` the signature is invisible. With one, a visible signature
+  box of `width` by `height` points is drawn at the top-left corner of the
+  labelled element. `name` defaults to the common name of the signer
+  certificate.
+
+  Signed PDFs cannot also be encrypted.
+  """
+  defstruct pkcs12: nil,
+            password: "",
+            key: nil,
+            certificates: nil,
+            label: nil,
+            width: 200.0,
+            height: 50.0,
+            name: nil,
+            reason: nil,
+            location: nil,
+            contact: nil
+
+  @type t :: %__MODULE__{
+          pkcs12: binary() | nil,
+          password: String.t(),
+          key: String.t() | nil,
+          certificates: String.t() | nil,
+          label: String.t() | nil,
+          width: number(),
+          height: number(),
+          name: String.t() | nil,
+          reason: String.t() | nil,
+          location: String.t() | nil,
+          contact: String.t() | nil
+        }
+end
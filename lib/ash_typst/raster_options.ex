@@ -0,0 +1,43 @@
+defmodule AshTypst.RasterOptions do
+  @moduledoc """
+  Options for raster (PNG, JPEG, WebP) export.
+
+  Without `width` or `height` the page is rendered at `pixel_per_pt`. With
+  one of them the page is scaled to that size, keeping its aspect ratio. With
+  both, `fit` decides how the page fills the box:
+
+    * `:contain` — scale to fit inside the box, keeping the aspect ratio
+    * `:cover` — scale to cover the box and crop the overflow, centered
+    * `:stretch` — scale to exactly the box, distorting the aspect ratio
+
+  `background` is `nil` to keep the page's own fill, `:transparent` (not
+  supported by JPEG), or a hex colour such as `"#ffffff"`. `quality` (1–100,
+  default 85) applies to JPEG only; PNG and WebP output is lossless, so
+  setting it for them is an error.
+
+  `impose` lays several pages out per sheet first (see `AshTypst.Imposition`);
+  the page number then selects a sheet. `overlays` draws watermarks or
+  stamps over the pages before imposition (see `AshTypst.Overlay`).
+  """
+  defstruct format: :png,
+            pixel_per_pt: 2.0,
+            width: nil,
+            height: nil,
+            fit: :contain,
+            quality: nil,
+            background: nil,
+            impose: nil,
+            overlays: []
+
+  @type t :: %__MODULE__{
+          format: :png | :jpeg | :webp,
+          pixel_per_pt: float(),
+          width: pos_integer() | nil,
+          height: pos_integer() | nil,
+          fit: :contain | :cover | :stretch,
+          quality: 1..100 | nil,
+          background: :transparent | String.t() | nil,
+          impose: AshTypst.Imposition.t() | nil,
+          overlays: [AshTypst.Overlay.t()]
+        }
+end
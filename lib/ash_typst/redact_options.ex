@@ -0,0 +1,17 @@
+defmodule AshTypst.RedactOptions do
+  @moduledoc """
+  Options for `AshTypst.Context.redact/3`.
+
+  `patterns` are regular expression sources in the syntax of Rust's `regex`
+  crate, which covers the common subset of Elixir's `Regex`. `literals` are
+  matched exactly. Redacted text is covered by a box filled with the hex
+  `color`.
+  """
+  defstruct patterns: [], literals: [], color: "#000000"
+
+  @type t :: %__MODULE__{
+          patterns: [String.t()],
+          literals: [String.t()],
+          color: String.t()
+        }
+end
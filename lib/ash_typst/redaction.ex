@@ -0,0 +1,28 @@
+defmodule AshTypst.Redaction do
+  @moduledoc """
+  Text removed by `AshTypst.Context.redact/3`.
+
+  `kind` says where the text was:
+
+    * `:text` — laid out text on a page, covered by a box
+    * `:url` — the target of a link on a page
+    * `:alt` — the alternative text of a link on a page
+    * `:title`, `:author`, `:description`, `:keywords` — document metadata
+
+  `page` is 1-indexed, and `nil` for metadata. `x` and `y` give the top-left
+  corner of the redaction box or link in points from the page's top-left
+  corner, and `width` and `height` its size; they are `nil` for alternative
+  text and metadata, which have no place on the page.
+  """
+  defstruct kind: :text, page: nil, text: "", x: nil, y: nil, width: nil, height: nil
+
+  @type t :: %__MODULE__{
+          kind: :text | :url | :alt | :title | :author | :description | :keywords,
+          page: pos_integer() | nil,
+          text: String.t(),
+          x: float() | nil,
+          y: float() | nil,
+          width: float() | nil,
+          height: float() | nil
+        }
+end
@@ -63,8 +63,9 @@ defmodule AshTypst.Resource do
      blocks) or a `source` file path relative to the `root` directory.
 
   2. **Render actions** reference a template and specify an output `format` (`:pdf`,
-     `:svg`, or `:html`). They can optionally declare arguments, a `read` to fetch
-     resource data, and format-specific options like `pdf_options`.
+     `:svg`, `:html`, or `:epub`). They can optionally declare arguments, a `read`
+     to fetch resource data, and format-specific options like `pdf_options`,
+     `html_options`, and `epub_options`.
 
   3. At compile time, the `BuildActions` transformer converts each render entity into
      a standard `Ash.Resource.Actions.Action`.
@@ -122,6 +123,20 @@ defmodule AshTypst.Resource do
     schema: AshTypst.Resource.Render.PdfOptions.schema()
   }
 
+  @html_options %Spark.Dsl.Entity{
+    name: :html_options,
+    describe: "HTML-specific export options.",
+    target: AshTypst.Resource.Render.HtmlOptions,
+    schema: AshTypst.Resource.Render.HtmlOptions.schema()
+  }
+
+  @epub_options %Spark.Dsl.Entity{
+    name: :epub_options,
+    describe: "EPUB-specific export options.",
+    target: AshTypst.Resource.Render.EpubOptions,
+    schema: AshTypst.Resource.Render.EpubOptions.schema()
+  }
+
   @prepare %Spark.Dsl.Entity{
     name: :prepare,
     describe: "Declares a preparation that runs before the template is rendered.",
@@ -154,11 +169,13 @@ defmodule AshTypst.Resource do
     ],
     schema: AshTypst.Resource.Render.schema(),
     transform: {AshTypst.Resource.Render, :transform, []},
-    singleton_entity_keys: [:read, :pdf_options],
+    singleton_entity_keys: [:read, :pdf_options, :html_options, :epub_options],
     entities: [
       arguments: [@action_argument],
       read: [@read],
       pdf_options: [@pdf_options],
+      html_options: [@html_options],
+      epub_options: [@epub_options],
       preparations: [@prepare, @action_validate]
     ]
   }
@@ -181,6 +198,24 @@ defmodule AshTypst.Resource do
         type: :boolean,
         default: false,
         doc: "Skip system font loading."
+      ],
+      default_styles: [
+        type: :keyword_list,
+        keys: [
+          font: [type: {:list, :string}, doc: "Font family fallback list."],
+          lang: [type: :string, doc: "ISO 639 language code."],
+          region: [type: :string, doc: "ISO 3166 region code."],
+          paper: [type: :string, doc: "Typst paper name, e.g. `\"a4\"`."],
+          margin: [type: :number, doc: "Page margin on all sides, in points."],
+          justify: [type: :boolean, doc: "Justify paragraphs."]
+        ],
+        default: [],
+        doc: "House style inherited by every template. See `AshTypst.DefaultStyles`."
+      ],
+      features: [
+        type: {:list, {:one_of, [:html, :a11y_extras]}},
+        default: [:html],
+        doc: "Typst features to enable. `:html` is required for `:html` render actions."
       ]
     ],
     entities: [@template, @render]
@@ -3,9 +3,10 @@ defmodule AshTypst.Resource.Render do
   Struct and schema for the `render` entity in the `typst` DSL section.
 
   A render entity declares an action that compiles a template and exports it in
-  the specified format (`:pdf`, `:svg`, or `:html`). It can include arguments,
+  the specified format (`:pdf`, `:svg`, `:html`, or `:epub`). It can include arguments,
   a `read` sub-entity to fetch resource data, `pdf_options` for PDF-specific
-  settings, and preparations/validations.
+  settings, `html_options` and `epub_options` for
+  HTML- and EPUB-specific settings, and preparations/validations.
 
   For the full DSL reference, see `d:AshTypst.Resource.typst.render`.
   """
@@ -20,6 +21,8 @@ defmodule AshTypst.Resource.Render do
     arguments: [],
     read: [],
     pdf_options: [],
+    html_options: [],
+    epub_options: [],
     preparations: [],
     __identifier__: nil,
     __spark_metadata__: nil
@@ -28,7 +31,7 @@ defmodule AshTypst.Resource.Render do
   @type t :: %__MODULE__{
           name: atom(),
           template: atom(),
-          format: :pdf | :svg | :html,
+          format: :pdf | :svg | :html | :epub,
           description: String.t() | nil,
           page: non_neg_integer() | nil,
           data_file: String.t(),
@@ -36,6 +39,8 @@ defmodule AshTypst.Resource.Render do
           arguments: [Ash.Resource.Actions.Argument.t()],
           read: [AshTypst.Resource.Render.Read.t()],
           pdf_options: [AshTypst.Resource.Render.PdfOptions.t()],
+          html_options: [AshTypst.Resource.Render.HtmlOptions.t()],
+          epub_options: [AshTypst.Resource.Render.EpubOptions.t()],
           preparations: [Ash.Resource.Preparation.t()],
           __spark_metadata__: Spark.Dsl.Entity.spark_meta()
         }
@@ -52,7 +57,7 @@ defmodule AshTypst.Resource.Render do
       doc: "Reference to a template declared in the `typst` section."
     ],
     format: [
-      type: {:one_of, [:pdf, :svg, :html]},
+      type: {:one_of, [:pdf, :svg, :html, :epub]},
       required: true,
       doc: "Output export format."
     ],
@@ -0,0 +1,62 @@
+defmodule AshTypst.Resource.Render.EpubOptions do
+  @moduledoc """
+  Struct and schema for the `epub_options` sub-entity of a render action.
+
+  Only valid when the render action's format is `:epub`. Overrides the book's
+  metadata, embeds fonts, and adds a stylesheet.
+
+  For the full DSL reference, see `d:AshTypst.Resource.typst.render.epub_options`.
+  """
+  defstruct [
+    :title,
+    :language,
+    :identifier,
+    :stylesheet,
+    authors: [],
+    fonts: [],
+    __spark_metadata__: nil
+  ]
+
+  @type t :: %__MODULE__{
+          title: String.t() | nil,
+          authors: [String.t()],
+          language: String.t() | nil,
+          identifier: String.t() | nil,
+          fonts: [String.t()],
+          stylesheet: String.t() | nil,
+          __spark_metadata__: Spark.Dsl.Entity.spark_meta()
+        }
+
+  @schema [
+    title: [
+      type: :string,
+      doc: "Book title. Defaults to the document title."
+    ],
+    authors: [
+      type: {:list, :string},
+      default: [],
+      doc: "Book authors. Defaults to the document authors."
+    ],
+    language: [
+      type: :string,
+      doc: "Book language, e.g. `\"en\"`. Defaults to the document language."
+    ],
+    identifier: [
+      type: :string,
+      doc: "Unique identifier such as an ISBN URN. Defaults to a UUID derived from the content."
+    ],
+    fonts: [
+      type: {:list, :string},
+      default: [],
+      doc: "Font families to embed in the book and use for its body text."
+    ],
+    stylesheet: [
+      type: :string,
+      doc: "Extra CSS appended to the book's stylesheet."
+    ]
+  ]
+
+  @doc false
+  @spec schema() :: keyword()
+  def schema, do: @schema
+end
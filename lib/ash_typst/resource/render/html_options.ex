@@ -0,0 +1,52 @@
+defmodule AshTypst.Resource.Render.HtmlOptions do
+  @moduledoc """
+  Struct and schema for the `html_options` sub-entity of a render action.
+
+  Only valid when the render action's format is `:html`. Allows emitting a
+  body-only fragment, extracting images into a separate asset map, and
+  injecting extra `<head>` content.
+
+  For the full DSL reference, see `d:AshTypst.Resource.typst.render.html_options`.
+  """
+  defstruct [
+    :head,
+    body_only: false,
+    images: :inline,
+    asset_prefix: "",
+    __spark_metadata__: nil
+  ]
+
+  @type t :: %__MODULE__{
+          body_only: boolean(),
+          images: :inline | :assets,
+          asset_prefix: String.t(),
+          head: String.t() | nil,
+          __spark_metadata__: Spark.Dsl.Entity.spark_meta()
+        }
+
+  @schema [
+    body_only: [
+      type: :boolean,
+      default: false,
+      doc: "Emit only the contents of `<body>`, e.g. for emails or embedding in a page."
+    ],
+    images: [
+      type: {:one_of, [:inline, :assets]},
+      default: :inline,
+      doc: "Keep images inline as data URIs, or return them in the document's `assets` map."
+    ],
+    asset_prefix: [
+      type: :string,
+      default: "",
+      doc: "Prefix for extracted image references, e.g. `\"/assets/\"` or `\"cid:\"`."
+    ],
+    head: [
+      type: :string,
+      doc: "Extra content appended to `<head>`, such as stylesheets and meta tags."
+    ]
+  ]
+
+  @doc false
+  @spec schema() :: keyword()
+  def schema, do: @schema
+end
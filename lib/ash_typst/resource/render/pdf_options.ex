@@ -3,16 +3,24 @@ defmodule AshTypst.Resource.Render.PdfOptions do
   Struct and schema for the `pdf_options` sub-entity of a render action.
 
   Only valid when the render action's format is `:pdf`. Allows configuring page
-  ranges, PDF compliance standards, and document identifiers.
+  ranges, PDF compliance standards, document identifiers, and the font
+  embedding policy.
 
   For the full DSL reference, see `d:AshTypst.Resource.typst.render.pdf_options`.
   """
-  defstruct [:pages, :document_id, pdf_standards: [], __spark_metadata__: nil]
+  defstruct [
+    :pages,
+    :document_id,
+    pdf_standards: [],
+    font_embedding: :warn,
+    __spark_metadata__: nil
+  ]
 
   @type t :: %__MODULE__{
           pages: String.t() | nil,
           pdf_standards: [:pdf_1_7 | :pdf_a_2b | :pdf_a_3b],
           document_id: String.t() | nil,
+          font_embedding: :ignore | :warn | :error,
           __spark_metadata__: Spark.Dsl.Entity.spark_meta()
         }
 
@@ -29,6 +37,12 @@ defmodule AshTypst.Resource.Render.PdfOptions do
     document_id: [
       type: :string,
       doc: "PDF document identifier."
+    ],
+    font_embedding: [
+      type: {:one_of, [:ignore, :warn, :error]},
+      default: :warn,
+      doc:
+        "Policy for fonts whose license forbids embedding: `:warn` adds a warning, `:error` fails the export, `:ignore` skips the check."
     ]
   ]
 
@@ -18,7 +18,7 @@ defmodule AshTypst.Resource.Run do
          :ok <- set_template(ctx, template, resource),
          :ok <- set_inputs(ctx, template),
          :ok <- inject_data(ctx, data, input.arguments, opts),
-         {:ok, compile_result} <- compile(ctx) do
+         {:ok, compile_result} <- compile(ctx, opts[:format]) do
       export(ctx, opts[:format], opts, compile_result)
     end
   end
@@ -111,12 +111,19 @@ defmodule AshTypst.Resource.Run do
     {:ok, root} = Info.typst_root(resource)
     {:ok, font_paths} = Info.typst_font_paths(resource)
     {:ok, ignore_system_fonts} = Info.typst_ignore_system_fonts(resource)
-
-    AshTypst.Context.new(
-      root: root,
-      font_paths: font_paths,
-      ignore_system_fonts: ignore_system_fonts
-    )
+    {:ok, default_styles} = Info.typst_default_styles(resource)
+    {:ok, features} = Info.typst_features(resource)
+
+    case AshTypst.Context.new(
+           root: root,
+           font_paths: font_paths,
+           ignore_system_fonts: ignore_system_fonts,
+           default_styles: default_styles,
+           features: features
+         ) do
+      {:ok, ctx} -> {:ok, ctx}
+      {:error, compile_error} -> {:error, Errors.CompileError.from(compile_error)}
+    end
   end
 
   defp set_template(ctx, %{source: source}, resource) when not is_nil(source) do
@@ -166,8 +173,10 @@ defmodule AshTypst.Resource.Run do
     AshTypst.Context.append_virtual_file(ctx, data_file, args_code)
   end
 
-  defp compile(ctx) do
-    case AshTypst.Context.compile(ctx) do
+  defp compile(ctx, format) do
+    target = if format in [:html, :epub], do: :html, else: :paged
+
+    case AshTypst.Context.compile(ctx, target: target) do
       {:ok, result} ->
         {:ok, result}
 
@@ -189,14 +198,18 @@ defmodule AshTypst.Resource.Run do
           |> Enum.reject(fn {_k, v} -> is_nil(v) end)
       end
 
-    with {:ok, data} <- AshTypst.Context.export_pdf(ctx, pdf_opts) do
-      {:ok,
-       %AshTypst.Document{
-         format: :pdf,
-         data: data,
-         page_count: compile_result.page_count,
-         warnings: compile_result.warnings
-       }}
+    case AshTypst.Context.export_pdf(ctx, pdf_opts) do
+      {:ok, result} ->
+        {:ok,
+         %AshTypst.Document{
+           format: :pdf,
+           data: result.data,
+           page_count: compile_result.page_count,
+           warnings: compile_result.warnings ++ result.warnings
+         }}
+
+      {:error, compile_error} ->
+        {:error, Errors.CompileError.from(compile_error)}
     end
   end
 
@@ -212,15 +225,60 @@ defmodule AshTypst.Resource.Run do
     end
   end
 
-  defp export(ctx, :html, _opts, compile_result) do
-    with {:ok, data} <- AshTypst.Context.export_html(ctx) do
-      {:ok,
-       %AshTypst.Document{
-         format: :html,
-         data: data,
-         page_count: compile_result.page_count,
-         warnings: compile_result.warnings
-       }}
+  defp export(ctx, :html, opts, compile_result) do
+    html_opts =
+      case opts[:html_options] do
+        nil ->
+          []
+
+        map when is_map(map) ->
+          map
+          |> Map.delete(:__spark_metadata__)
+          |> Map.delete(:__identifier__)
+          |> Enum.reject(fn {_k, v} -> is_nil(v) end)
+      end
+
+    case AshTypst.Context.export_html(ctx, html_opts) do
+      {:ok, result} ->
+        {:ok,
+         %AshTypst.Document{
+           format: :html,
+           data: result.data,
+           page_count: compile_result.page_count,
+           warnings: compile_result.warnings,
+           assets: result.assets
+         }}
+
+      {:error, compile_error} ->
+        {:error, Errors.CompileError.from(compile_error)}
+    end
+  end
+
+  defp export(ctx, :epub, opts, compile_result) do
+    epub_opts =
+      case opts[:epub_options] do
+        nil ->
+          []
+
+        map when is_map(map) ->
+          map
+          |> Map.delete(:__spark_metadata__)
+          |> Map.delete(:__identifier__)
+          |> Enum.reject(fn {_k, v} -> is_nil(v) end)
+      end
+
+    case AshTypst.Context.export_epub(ctx, epub_opts) do
+      {:ok, result} ->
+        {:ok,
+         %AshTypst.Document{
+           format: :epub,
+           data: result.data,
+           page_count: compile_result.page_count,
+           warnings: compile_result.warnings
+         }}
+
+      {:error, compile_error} ->
+        {:error, Errors.CompileError.from(compile_error)}
     end
   end
 end
@@ -20,9 +20,11 @@ defmodule AshTypst.Resource.Transformers.BuildActions do
   end
 
   defp build_action(entity) do
-    # With singleton_entity_keys, read and pdf_options are a single entity or nil
+    # With singleton_entity_keys, read and the *_options entities are a single entity or nil
     read_entity = entity.read
     pdf_options_entity = entity.pdf_options
+    html_options_entity = entity.html_options
+    epub_options_entity = entity.epub_options
 
     run_opts =
       [
@@ -45,6 +47,20 @@ defmodule AshTypst.Resource.Transformers.BuildActions do
           opts
         end
       end)
+      |> then(fn opts ->
+        if html_options_entity do
+          Keyword.put(opts, :html_options, Map.from_struct(html_options_entity))
+        else
+          opts
+        end
+      end)
+      |> then(fn opts ->
+        if epub_options_entity do
+          Keyword.put(opts, :epub_options, Map.from_struct(epub_options_entity))
+        else
+          opts
+        end
+      end)
 
     %Ash.Resource.Actions.Action{
       name: entity.name,
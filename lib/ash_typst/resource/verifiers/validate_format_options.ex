@@ -2,13 +2,14 @@ defmodule AshTypst.Resource.Verifiers.ValidateFormatOptions do
   @moduledoc false
   use Spark.Dsl.Verifier
 
-  alias AshTypst.Resource.Run
+  alias AshTypst.Resource.{Info, Run}
   alias Spark.Dsl.{Extension, Verifier}
   alias Spark.Error.DslError
 
   @impl true
   def verify(dsl_state) do
     module = Verifier.get_persisted(dsl_state, :module)
+    {:ok, features} = Info.typst_features(dsl_state)
 
     dsl_state
     |> Extension.get_entities([:actions])
@@ -16,7 +17,10 @@ defmodule AshTypst.Resource.Verifiers.ValidateFormatOptions do
       %Ash.Resource.Actions.Action{run: {Run, opts}} = action ->
         validate_page_option(opts, action, module)
         validate_pdf_options(opts, action, module)
+        validate_html_options(opts, action, module)
+        validate_epub_options(opts, action, module)
         validate_read_options(opts, action, module)
+        validate_html_feature(opts, action, module, features)
 
       _ ->
         :ok
@@ -45,6 +49,49 @@ defmodule AshTypst.Resource.Verifiers.ValidateFormatOptions do
     end
   end
 
+  defp validate_html_options(opts, action, module) do
+    html_options = opts[:html_options]
+
+    if html_options && opts[:format] != :html do
+      raise DslError,
+        module: module,
+        message:
+          "Action #{inspect(action.name)}: `html_options` is only valid when `format` is `:html`, " <>
+            "but format is #{inspect(opts[:format])}.",
+        path: [:actions, action.name]
+    end
+
+    if html_options && html_options[:body_only] && html_options[:head] do
+      raise DslError,
+        module: module,
+        message:
+          "Action #{inspect(action.name)}: `html_options` cannot set both `body_only` and `head`.",
+        path: [:actions, action.name, :html_options]
+    end
+  end
+
+  defp validate_epub_options(opts, action, module) do
+    if opts[:epub_options] && opts[:format] != :epub do
+      raise DslError,
+        module: module,
+        message:
+          "Action #{inspect(action.name)}: `epub_options` is only valid when `format` is `:epub`, " <>
+            "but format is #{inspect(opts[:format])}.",
+        path: [:actions, action.name]
+    end
+  end
+
+  defp validate_html_feature(opts, action, module, features) do
+    if opts[:format] in [:html, :epub] && :html not in features do
+      raise DslError,
+        module: module,
+        message:
+          "Action #{inspect(action.name)}: `format #{inspect(opts[:format])}` " <>
+            "requires the `:html` feature, but the `typst` section enables #{inspect(features)}.",
+        path: [:actions, action.name]
+    end
+  end
+
   defp validate_read_options(opts, action, module) do
     case opts[:read] do
       %{cardinality: :one} = read ->
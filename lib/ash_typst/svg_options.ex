@@ -0,0 +1,9 @@
+defmodule AshTypst.SVGOptions do
+  @moduledoc "Options for SVG export."
+  defstruct impose: nil, overlays: []
+
+  @type t :: %__MODULE__{
+          impose: AshTypst.Imposition.t() | nil,
+          overlays: [AshTypst.Overlay.t()]
+        }
+end
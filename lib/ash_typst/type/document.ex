@@ -15,7 +15,8 @@ defmodule AshTypst.Type.Document do
        format: to_existing_atom(map[:format] || map["format"]),
        data: map[:data] || map["data"],
        page_count: map[:page_count] || map["page_count"],
-       warnings: map[:warnings] || map["warnings"] || []
+       warnings: map[:warnings] || map["warnings"] || [],
+       assets: map[:assets] || map["assets"] || %{}
      }}
   end
 
@@ -30,7 +31,8 @@ defmodule AshTypst.Type.Document do
        format: to_existing_atom(map[:format] || map["format"]),
        data: map[:data] || map["data"],
        page_count: map[:page_count] || map["page_count"],
-       warnings: map[:warnings] || map["warnings"] || []
+       warnings: map[:warnings] || map["warnings"] || [],
+       assets: map[:assets] || map["assets"] || %{}
      }}
   end
 
@@ -45,7 +47,8 @@ defmodule AshTypst.Type.Document do
        format: to_string(doc.format),
        data: doc.data,
        page_count: doc.page_count,
-       warnings: doc.warnings
+       warnings: doc.warnings,
+       assets: doc.assets
      }}
   end
 
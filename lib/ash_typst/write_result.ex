@@ -0,0 +1,16 @@
+defmodule AshTypst.WriteResult do
+  @moduledoc """
+  Result of an export written directly to a file.
+
+  `size` is the number of bytes written and `sha256` the lowercase hex digest
+  of the file contents.
+  """
+  defstruct path: "", size: 0, sha256: "", warnings: []
+
+  @type t :: %__MODULE__{
+          path: String.t(),
+          size: non_neg_integer(),
+          sha256: String.t(),
+          warnings: [AshTypst.Diagnostic.t()]
+        }
+end
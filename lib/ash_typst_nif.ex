@@ -21,15 +21,33 @@ defmodule AshTypst.NIF do
 
   def context_new(_opts), do: :erlang.nif_error(:not_loaded)
   def context_set_markup(_ctx, _markup), do: :erlang.nif_error(:not_loaded)
-  def context_compile(_ctx), do: :erlang.nif_error(:not_loaded)
-  def context_render_svg(_ctx, _page), do: :erlang.nif_error(:not_loaded)
+  def context_compile(_ctx, _target), do: :erlang.nif_error(:not_loaded)
+  def context_render_svg(_ctx, _page, _opts), do: :erlang.nif_error(:not_loaded)
   def context_export_pdf(_ctx, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_accessibility_report(_ctx), do: :erlang.nif_error(:not_loaded)
+  def context_redact(_ctx, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_split_pdf(_ctx, _ranges, _label, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_write_pdf(_ctx, _path, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_write_svg(_ctx, _path, _page, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_render_raster(_ctx, _page, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_write_raster(_ctx, _path, _page, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_render_region_svg(_ctx, _page, _region, _opts), do: :erlang.nif_error(:not_loaded)
+
+  def context_render_region_raster(_ctx, _page, _region, _opts),
+    do: :erlang.nif_error(:not_loaded)
+  def context_export_bundle(_ctx, _outputs, _zip), do: :erlang.nif_error(:not_loaded)
   def context_font_families(_ctx), do: :erlang.nif_error(:not_loaded)
   def context_set_virtual_file(_ctx, _path, _content), do: :erlang.nif_error(:not_loaded)
   def context_append_virtual_file(_ctx, _path, _chunk), do: :erlang.nif_error(:not_loaded)
   def context_clear_virtual_file(_ctx, _path), do: :erlang.nif_error(:not_loaded)
+  def context_set_plugin(_ctx, _path, _wasm), do: :erlang.nif_error(:not_loaded)
   def context_set_input(_ctx, _key, _value), do: :erlang.nif_error(:not_loaded)
   def context_set_inputs(_ctx, _inputs), do: :erlang.nif_error(:not_loaded)
-  def context_export_html(_ctx), do: :erlang.nif_error(:not_loaded)
+  def context_set_global(_ctx, _name, _code), do: :erlang.nif_error(:not_loaded)
+  def context_set_global_module(_ctx, _name, _source), do: :erlang.nif_error(:not_loaded)
+  def context_clear_globals(_ctx), do: :erlang.nif_error(:not_loaded)
+  def context_export_html(_ctx, _opts), do: :erlang.nif_error(:not_loaded)
+  def context_export_epub(_ctx, _opts), do: :erlang.nif_error(:not_loaded)
+  def merge_pdf(_contexts, _opts), do: :erlang.nif_error(:not_loaded)
   def font_families(_opts), do: :erlang.nif_error(:not_loaded)
 end
@@ -41,7 +41,30 @@ defmodule AshTypst.MixProject do
         "Data Encoding": [AshTypst.Code],
         Structs: [
           AshTypst.Context.Options,
+          AshTypst.DefaultStyles,
           AshTypst.PDFOptions,
+          AshTypst.PDFResult,
+          AshTypst.PDFEncryption,
+          AshTypst.PDFSignature,
+          AshTypst.PDFMetadata,
+          AshTypst.PDFPrint,
+          AshTypst.PDFColor,
+          AshTypst.PDFOptimize,
+          AshTypst.PDFOptimization,
+          AshTypst.SVGOptions,
+          AshTypst.Imposition,
+          AshTypst.Overlay,
+          AshTypst.RedactOptions,
+          AshTypst.Redaction,
+          AshTypst.HTMLOptions,
+          AshTypst.HTMLResult,
+          AshTypst.EPUBOptions,
+          AshTypst.EPUBResult,
+          AshTypst.RasterOptions,
+          AshTypst.WriteResult,
+          AshTypst.BundleOutput,
+          AshTypst.BundleResult,
+          AshTypst.FontEmbedding,
           AshTypst.CompileResult,
           AshTypst.CompileError,
           AshTypst.Diagnostic,
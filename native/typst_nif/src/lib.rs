@@ -1,40 +1,149 @@
+use base64::Engine;
 use chrono::{DateTime, Datelike, FixedOffset, Local, Utc};
-use ecow::EcoVec;
+use cms::builder::{SignedDataBuilder, SignerInfoBuilder};
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::signed_data::{EncapsulatedContentInfo, SignerIdentifier};
+use comemo::Track;
+use const_oid::db::rfc4519::COMMON_NAME;
+use const_oid::db::rfc5911::{ID_AA_SIGNING_CERTIFICATE_V_2, ID_DATA};
+use const_oid::db::rfc5912::ID_SHA_256;
+use ecow::{EcoString, EcoVec};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ExtendedColorType, ImageEncoder, RgbaImage};
+use lcms2::Transform as ColorTransform;
+use lcms2::{ColorSpaceSignature, InfoType, Intent, Locale, PixelFormat, Profile};
+use lopdf::encryption::crypt_filters::{Aes256CryptFilter, CryptFilter};
+use lopdf::{
+    dictionary, EncryptionState, EncryptionVersion, Object, ObjectId, Permissions, StringFormat,
+};
+use p12_keystore::KeyStore;
 use parking_lot::Mutex;
+use regex::Regex;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::{Signature as RsaSignature, SigningKey as RsaSigningKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use rustler::{Atom, Binary, Decoder, Encoder, Env, NewBinary, NifStruct, ResourceArc, Term};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
+use std::io::{BufWriter, Read, Write};
 use std::num::NonZeroUsize;
+use std::ops::{ControlFlow, Range};
 use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::sync::LazyLock;
 use std::sync::OnceLock;
 use std::{fs, mem};
-use typst::diag::{FileError, FileResult, Severity, SourceDiagnostic};
-use typst::foundations::{Bytes, Datetime, Dict, Smart, Str, Value};
+use typst::diag::{FileError, FileResult, Severity, SourceDiagnostic, SourceResult};
+use typst::engine::{Route, Sink, Traced};
+use typst::foundations::{
+    Binding, Bytes, Content, Datetime, Dict, Label, Module, Repr, Scope, Selector, Smart, Str,
+    StyleChain, StyledElem, Styles, Value,
+};
+use typst::introspection::{Introspector, IntrospectorBuilder, Location, Tag};
 use typst::layout::PageRanges;
-use typst::layout::PagedDocument;
-use typst::syntax::{FileId, Source, VirtualPath};
-use typst::text::{Font, FontBook};
-use typst::utils::LazyHash;
-use typst::{Feature, Features, Library, LibraryExt, World};
+use typst::layout::{
+    Abs, Frame, FrameItem, GroupItem, Length, Margin, Page, PageElem, PagedDocument, Paper, Point,
+    Ratio, Size, Transform,
+};
+use typst::model::{Destination, HeadingElem, LinkMarker, ParElem, Url};
+use typst::syntax::{FileId, Source, Span, SyntaxMode, VirtualPath};
+use typst::text::{
+    Font, FontBook, FontFamily, FontList, FontStyle, Glyph, Lang, Region, TextElem, TextItem,
+};
+use typst::utils::{LazyHash, PicoStr};
+use typst::visualize::{
+    Color, ColorSpace, Curve, ExchangeFormat, FixedStroke, Geometry, Image, ImageKind, Paint,
+    RasterFormat, RasterImage,
+};
+use typst::{Feature, Features, Library, LibraryExt, World, ROUTINES};
 use typst_html::HtmlDocument;
 use typst_kit::download::{DownloadState, Downloader, Progress};
 use typst_kit::fonts::{FontSlot, Fonts};
 use typst_kit::package::PackageStorage;
 use typst_pdf::{PdfOptions, PdfStandard, PdfStandards};
 use typst_timing::{timed, TimingScope};
+use x509_cert::attr::Attribute;
+use x509_cert::der::asn1::{
+    Any, Ia5StringRef, PrintableStringRef, SetOfVec, TeletexStringRef, Utf8StringRef,
+};
+use x509_cert::der::{Decode, Encode};
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use x509_cert::Certificate;
+use zip::result::ZipResult;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
 
 static MARKUP_ID: LazyLock<FileId> =
     LazyLock::new(|| FileId::new_fake(VirtualPath::new("MARKUP.typ")));
 
+static OVERLAY_ID: LazyLock<FileId> =
+    LazyLock::new(|| FileId::new_fake(VirtualPath::new("OVERLAY.typ")));
+
+/// The path an overlay's markup loads its image from.
+const OVERLAY_IMAGE_PATH: &str = "/__overlay_image__";
+
 rustler::atoms! {
     ok,
     pdf_1_7,
     pdf_a_2b,
     pdf_a_3b,
     error,
-    warning
+    warning,
+    ignore,
+    warn,
+    paged,
+    html,
+    both,
+    inline,
+    assets,
+    pdf,
+    svg,
+    png,
+    jpeg,
+    webp,
+    contain,
+    cover,
+    stretch,
+    installable,
+    restricted,
+    preview_and_print,
+    editable,
+    unknown,
+    perceptual,
+    relative_colorimetric,
+    saturation,
+    absolute_colorimetric,
+    rows,
+    columns,
+    booklet,
+    center,
+    top_left,
+    top,
+    top_right,
+    left,
+    right,
+    bottom_left,
+    bottom,
+    bottom_right,
+    text,
+    url,
+    alt,
+    title,
+    author,
+    description,
+    keywords
 }
 
 #[derive(NifStruct)]
@@ -43,6 +152,19 @@ pub struct ContextOptionsNif {
     pub root: String,
     pub font_paths: Vec<String>,
     pub ignore_system_fonts: bool,
+    pub default_styles: DefaultStylesNif,
+    pub features: Vec<String>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.DefaultStyles"]
+pub struct DefaultStylesNif {
+    pub font: Vec<String>,
+    pub lang: Option<String>,
+    pub region: Option<String>,
+    pub paper: Option<String>,
+    pub margin: Option<f64>,
+    pub justify: Option<bool>,
 }
 
 #[derive(NifStruct)]
@@ -51,6 +173,127 @@ pub struct PdfOptionsNif {
     pub pages: Option<String>,
     pub pdf_standards: Vec<PdfStandardNif>,
     pub document_id: Option<String>,
+    pub font_embedding: FontEmbeddingPolicyNif,
+    pub encryption: Option<PdfEncryptionNif>,
+    pub signature: Option<PdfSignatureNif>,
+    pub metadata: Option<PdfMetadataNif>,
+    pub tagged: bool,
+    pub print: Option<PdfPrintNif>,
+    pub color: Option<PdfColorNif>,
+    pub impose: Option<ImpositionNif>,
+    pub overlays: Vec<OverlayNif>,
+    pub optimize: Option<PdfOptimizeNif>,
+    pub linearize: bool,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFEncryption"]
+pub struct PdfEncryptionNif {
+    pub user_password: Option<String>,
+    pub owner_password: String,
+    pub allow_open: bool,
+    pub print: bool,
+    pub copy: bool,
+    pub modify: bool,
+    pub annotate: bool,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFSignature"]
+pub struct PdfSignatureNif {
+    pub pkcs12: Option<BytesNif>,
+    pub password: String,
+    pub key: Option<String>,
+    pub certificates: Option<String>,
+    pub label: Option<String>,
+    pub width: f64,
+    pub height: f64,
+    pub name: Option<String>,
+    pub reason: Option<String>,
+    pub location: Option<String>,
+    pub contact: Option<String>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFMetadata"]
+pub struct PdfMetadataNif {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub subject: Option<String>,
+    pub keywords: Vec<String>,
+    pub language: Option<String>,
+    pub xmp: HashMap<String, String>,
+    pub xmp_namespace: String,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFPrint"]
+pub struct PdfPrintNif {
+    pub bleed: f64,
+    pub crop_marks: bool,
+    pub registration_marks: bool,
+    pub mark_length: f64,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFOptimize"]
+pub struct PdfOptimizeNif {
+    pub max_dpi: Option<f64>,
+    pub jpeg_quality: Option<u32>,
+    pub compression: Option<u32>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFColor"]
+pub struct PdfColorNif {
+    pub profile: BytesNif,
+    pub intent: RenderingIntentNif,
+    pub output_condition: Option<String>,
+}
+
+/// An arbitrary Elixir binary, which `String` would reject unless it is UTF-8.
+pub struct BytesNif(Vec<u8>);
+
+impl Decoder<'_> for BytesNif {
+    fn decode(term: Term<'_>) -> rustler::NifResult<Self> {
+        let binary: Binary = term.decode()?;
+        Ok(BytesNif(binary.as_slice().to_vec()))
+    }
+}
+
+impl Encoder for BytesNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut binary = NewBinary::new(env, self.0.len());
+        binary.as_mut_slice().copy_from_slice(&self.0);
+        Binary::from(binary).to_term(env)
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFResult"]
+pub struct PdfResultNif<'a> {
+    pub data: Binary<'a>,
+    pub warnings: Vec<DiagnosticNif>,
+    pub fonts: Vec<FontEmbeddingNif>,
+    pub optimization: Option<PdfOptimizationNif>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.PDFOptimization"]
+pub struct PdfOptimizationNif {
+    pub original_size: usize,
+    pub size: usize,
+    pub images: usize,
+    pub original_image_size: usize,
+    pub image_size: usize,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.FontEmbedding"]
+pub struct FontEmbeddingNif {
+    pub family: String,
+    pub permission: FontPermissionNif,
+    pub subsetting_allowed: bool,
 }
 
 #[derive(NifStruct)]
@@ -67,6 +310,140 @@ pub struct CompileResultNif {
     pub warnings: Vec<DiagnosticNif>,
 }
 
+#[derive(NifStruct)]
+#[module = "AshTypst.HTMLOptions"]
+pub struct HtmlOptionsNif {
+    pub body_only: bool,
+    pub images: HtmlImagesNif,
+    pub asset_prefix: String,
+    pub head: Option<String>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.HTMLResult"]
+pub struct HtmlResultNif<'a> {
+    pub data: String,
+    pub warnings: Vec<DiagnosticNif>,
+    pub assets: HashMap<String, Binary<'a>>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.EPUBOptions"]
+pub struct EpubOptionsNif {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub identifier: Option<String>,
+    pub fonts: Vec<String>,
+    pub stylesheet: Option<String>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.EPUBResult"]
+pub struct EpubResultNif<'a> {
+    pub data: Binary<'a>,
+    pub warnings: Vec<DiagnosticNif>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.WriteResult"]
+pub struct WriteResultNif {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub warnings: Vec<DiagnosticNif>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.BundleOutput"]
+pub struct BundleOutputNif {
+    pub format: BundleFormatNif,
+    pub name: Option<String>,
+    pub pages: Option<String>,
+    pub pdf_options: Option<PdfOptionsNif>,
+    pub svg_options: Option<SvgOptionsNif>,
+    pub raster_options: Option<RasterOptionsNif>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.RasterOptions"]
+pub struct RasterOptionsNif {
+    pub format: RasterFormatNif,
+    pub pixel_per_pt: f64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: RasterFitNif,
+    pub quality: Option<u32>,
+    pub background: Option<String>,
+    pub impose: Option<ImpositionNif>,
+    pub overlays: Vec<OverlayNif>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.SVGOptions"]
+pub struct SvgOptionsNif {
+    pub impose: Option<ImpositionNif>,
+    pub overlays: Vec<OverlayNif>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.Imposition"]
+pub struct ImpositionNif {
+    pub order: ImpositionOrderNif,
+    pub columns: usize,
+    pub rows: usize,
+    pub paper: Option<String>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub gutter: f64,
+    pub margin: f64,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.Overlay"]
+pub struct OverlayNif {
+    pub text: Option<String>,
+    pub image: Option<BytesNif>,
+    pub pages: Option<String>,
+    pub position: OverlayPositionNif,
+    pub dx: f64,
+    pub dy: f64,
+    pub rotation: f64,
+    pub opacity: f64,
+    pub size: f64,
+    pub width: Option<f64>,
+    pub color: String,
+    pub font: Option<String>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.RedactOptions"]
+pub struct RedactOptionsNif {
+    pub patterns: Vec<String>,
+    pub literals: Vec<String>,
+    pub color: String,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.Redaction"]
+pub struct RedactionNif {
+    pub kind: RedactionKindNif,
+    pub page: Option<usize>,
+    pub text: String,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+}
+
+#[derive(NifStruct)]
+#[module = "AshTypst.BundleResult"]
+pub struct BundleResultNif<'a> {
+    pub files: HashMap<String, Binary<'a>>,
+    pub archive: Option<Binary<'a>>,
+    pub warnings: Vec<DiagnosticNif>,
+}
+
 #[derive(NifStruct)]
 #[module = "AshTypst.CompileError"]
 pub struct CompileErrorNif {
@@ -136,6 +513,188 @@ impl From<Severity> for SeverityNif {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileTargetNif {
+    Paged,
+    Html,
+    Both,
+}
+
+impl CompileTargetNif {
+    fn includes_paged(self) -> bool {
+        matches!(self, CompileTargetNif::Paged | CompileTargetNif::Both)
+    }
+
+    fn includes_html(self) -> bool {
+        matches!(self, CompileTargetNif::Html | CompileTargetNif::Both)
+    }
+}
+
+impl Decoder<'_> for CompileTargetNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == paged() {
+            Ok(CompileTargetNif::Paged)
+        } else if atom == html() {
+            Ok(CompileTargetNif::Html)
+        } else if atom == both() {
+            Ok(CompileTargetNif::Both)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+impl Encoder for CompileTargetNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            CompileTargetNif::Paged => paged().encode(env),
+            CompileTargetNif::Html => html().encode(env),
+            CompileTargetNif::Both => both().encode(env),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtmlImagesNif {
+    Inline,
+    Assets,
+}
+
+impl Decoder<'_> for HtmlImagesNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == inline() {
+            Ok(HtmlImagesNif::Inline)
+        } else if atom == assets() {
+            Ok(HtmlImagesNif::Assets)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+impl Encoder for HtmlImagesNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            HtmlImagesNif::Inline => inline().encode(env),
+            HtmlImagesNif::Assets => assets().encode(env),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleFormatNif {
+    Pdf,
+    Svg,
+    Raster(RasterFormatNif),
+}
+
+impl Decoder<'_> for BundleFormatNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == pdf() {
+            Ok(BundleFormatNif::Pdf)
+        } else if atom == svg() {
+            Ok(BundleFormatNif::Svg)
+        } else {
+            Ok(BundleFormatNif::Raster(term.decode()?))
+        }
+    }
+}
+
+impl Encoder for BundleFormatNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            BundleFormatNif::Pdf => pdf().encode(env),
+            BundleFormatNif::Svg => svg().encode(env),
+            BundleFormatNif::Raster(format) => format.encode(env),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterFormatNif {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl RasterFormatNif {
+    fn name(self) -> &'static str {
+        match self {
+            RasterFormatNif::Png => "png",
+            RasterFormatNif::Jpeg => "jpeg",
+            RasterFormatNif::Webp => "webp",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            RasterFormatNif::Png => "png",
+            RasterFormatNif::Jpeg => "jpg",
+            RasterFormatNif::Webp => "webp",
+        }
+    }
+}
+
+impl Decoder<'_> for RasterFormatNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == png() {
+            Ok(RasterFormatNif::Png)
+        } else if atom == jpeg() {
+            Ok(RasterFormatNif::Jpeg)
+        } else if atom == webp() {
+            Ok(RasterFormatNif::Webp)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+impl Encoder for RasterFormatNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            RasterFormatNif::Png => png().encode(env),
+            RasterFormatNif::Jpeg => jpeg().encode(env),
+            RasterFormatNif::Webp => webp().encode(env),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterFitNif {
+    Contain,
+    Cover,
+    Stretch,
+}
+
+impl Decoder<'_> for RasterFitNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == contain() {
+            Ok(RasterFitNif::Contain)
+        } else if atom == cover() {
+            Ok(RasterFitNif::Cover)
+        } else if atom == stretch() {
+            Ok(RasterFitNif::Stretch)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+impl Encoder for RasterFitNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            RasterFitNif::Contain => contain().encode(env),
+            RasterFitNif::Cover => cover().encode(env),
+            RasterFitNif::Stretch => stretch().encode(env),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PdfStandardNif {
     Pdf17,
@@ -158,6 +717,16 @@ impl Decoder<'_> for PdfStandardNif {
     }
 }
 
+impl PdfStandardNif {
+    fn name(self) -> &'static str {
+        match self {
+            PdfStandardNif::Pdf17 => "PDF 1.7",
+            PdfStandardNif::PdfA2b => "PDF/A-2b",
+            PdfStandardNif::PdfA3b => "PDF/A-3b",
+        }
+    }
+}
+
 impl Encoder for PdfStandardNif {
     fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
         match self {
@@ -178,481 +747,5379 @@ impl From<PdfStandardNif> for PdfStandard {
     }
 }
 
-impl PdfOptionsNif {
-    fn to_pdf_options(&self) -> Result<PdfOptions<'_>, String> {
-        let mut opts = PdfOptions::default();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontEmbeddingPolicyNif {
+    Ignore,
+    Warn,
+    Error,
+}
 
-        if let Some(ref document_id) = self.document_id {
-            opts.ident = Smart::Custom(document_id.as_str());
+impl Decoder<'_> for FontEmbeddingPolicyNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == ignore() {
+            Ok(FontEmbeddingPolicyNif::Ignore)
+        } else if atom == warn() {
+            Ok(FontEmbeddingPolicyNif::Warn)
+        } else if atom == error() {
+            Ok(FontEmbeddingPolicyNif::Error)
+        } else {
+            Err(rustler::Error::BadArg)
         }
+    }
+}
 
-        if !self.pdf_standards.is_empty() {
-            let standards: Vec<PdfStandard> =
-                self.pdf_standards.iter().map(|&s| s.into()).collect();
-            opts.standards = PdfStandards::new(&standards)
-                .map_err(|e| format!("Invalid PDF standards: {}", e))?;
+impl Encoder for FontEmbeddingPolicyNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            FontEmbeddingPolicyNif::Ignore => ignore().encode(env),
+            FontEmbeddingPolicyNif::Warn => warn().encode(env),
+            FontEmbeddingPolicyNif::Error => error().encode(env),
         }
+    }
+}
 
-        Ok(opts)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpositionOrderNif {
+    Rows,
+    Columns,
+    Booklet,
+}
+
+impl Decoder<'_> for ImpositionOrderNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == rows() {
+            Ok(ImpositionOrderNif::Rows)
+        } else if atom == columns() {
+            Ok(ImpositionOrderNif::Columns)
+        } else if atom == booklet() {
+            Ok(ImpositionOrderNif::Booklet)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
     }
 }
 
-pub struct SystemWorld {
-    root: PathBuf,
-    main: FileId,
-    markup: String,
-    library: LazyHash<Library>,
-    book: LazyHash<FontBook>,
-    fonts: Vec<FontSlot>,
-    slots: Mutex<HashMap<FileId, FileSlot>>,
-    package_storage: PackageStorage,
-    now: Now,
-    virtual_files: HashMap<String, Vec<u8>>,
-    inputs: HashMap<String, String>,
+impl Encoder for ImpositionOrderNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            ImpositionOrderNif::Rows => rows().encode(env),
+            ImpositionOrderNif::Columns => columns().encode(env),
+            ImpositionOrderNif::Booklet => booklet().encode(env),
+        }
+    }
 }
 
-impl SystemWorld {
-    pub fn new(root: PathBuf, font_paths: Vec<PathBuf>, ignore_system_fonts: bool) -> Self {
-        let filtered_paths: Vec<PathBuf> = font_paths
-            .into_iter()
-            .filter(|p| p.exists() && p.is_dir())
-            .collect();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayPositionNif {
+    Center,
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
 
-        let include_system_fonts = !ignore_system_fonts;
+impl OverlayPositionNif {
+    /// How far along the free horizontal and vertical space the overlay
+    /// sits: 0 at the left or top edge, 1 at the right or bottom edge.
+    fn anchor(self) -> (f64, f64) {
+        match self {
+            OverlayPositionNif::Center => (0.5, 0.5),
+            OverlayPositionNif::TopLeft => (0.0, 0.0),
+            OverlayPositionNif::Top => (0.5, 0.0),
+            OverlayPositionNif::TopRight => (1.0, 0.0),
+            OverlayPositionNif::Left => (0.0, 0.5),
+            OverlayPositionNif::Right => (1.0, 0.5),
+            OverlayPositionNif::BottomLeft => (0.0, 1.0),
+            OverlayPositionNif::Bottom => (0.5, 1.0),
+            OverlayPositionNif::BottomRight => (1.0, 1.0),
+        }
+    }
+}
 
-        let fonts = if filtered_paths.is_empty() {
-            Fonts::searcher()
-                .include_system_fonts(include_system_fonts)
-                .search()
+impl Decoder<'_> for OverlayPositionNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == center() {
+            Ok(OverlayPositionNif::Center)
+        } else if atom == top_left() {
+            Ok(OverlayPositionNif::TopLeft)
+        } else if atom == top() {
+            Ok(OverlayPositionNif::Top)
+        } else if atom == top_right() {
+            Ok(OverlayPositionNif::TopRight)
+        } else if atom == left() {
+            Ok(OverlayPositionNif::Left)
+        } else if atom == right() {
+            Ok(OverlayPositionNif::Right)
+        } else if atom == bottom_left() {
+            Ok(OverlayPositionNif::BottomLeft)
+        } else if atom == bottom() {
+            Ok(OverlayPositionNif::Bottom)
+        } else if atom == bottom_right() {
+            Ok(OverlayPositionNif::BottomRight)
         } else {
-            Fonts::searcher()
-                .include_system_fonts(include_system_fonts)
-                .search_with(filtered_paths)
-        };
+            Err(rustler::Error::BadArg)
+        }
+    }
+}
 
-        let user_agent = concat!("typst/", env!("CARGO_PKG_VERSION"));
-        Self {
-            root,
-            main: *MARKUP_ID,
-            markup: String::new(),
-            library: LazyHash::new(
-                Library::builder()
-                    .with_features(Features::from_iter([Feature::Html]))
-                    .build(),
-            ),
-            book: LazyHash::new(fonts.book),
-            fonts: fonts.fonts,
-            slots: Mutex::new(HashMap::new()),
-            package_storage: PackageStorage::new(None, None, Downloader::new(user_agent)),
-            now: Now::System(OnceLock::new()),
-            virtual_files: HashMap::new(),
-            inputs: HashMap::new(),
+impl Encoder for OverlayPositionNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            OverlayPositionNif::Center => center().encode(env),
+            OverlayPositionNif::TopLeft => top_left().encode(env),
+            OverlayPositionNif::Top => top().encode(env),
+            OverlayPositionNif::TopRight => top_right().encode(env),
+            OverlayPositionNif::Left => left().encode(env),
+            OverlayPositionNif::Right => right().encode(env),
+            OverlayPositionNif::BottomLeft => bottom_left().encode(env),
+            OverlayPositionNif::Bottom => bottom().encode(env),
+            OverlayPositionNif::BottomRight => bottom_right().encode(env),
         }
     }
+}
 
-    pub fn reset(&mut self) {
-        for slot in self.slots.get_mut().values_mut() {
-            slot.reset();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingIntentNif {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+impl Decoder<'_> for RenderingIntentNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == perceptual() {
+            Ok(RenderingIntentNif::Perceptual)
+        } else if atom == relative_colorimetric() {
+            Ok(RenderingIntentNif::RelativeColorimetric)
+        } else if atom == saturation() {
+            Ok(RenderingIntentNif::Saturation)
+        } else if atom == absolute_colorimetric() {
+            Ok(RenderingIntentNif::AbsoluteColorimetric)
+        } else {
+            Err(rustler::Error::BadArg)
         }
-        if let Now::System(time_lock) = &mut self.now {
-            time_lock.take();
+    }
+}
+
+impl Encoder for RenderingIntentNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            RenderingIntentNif::Perceptual => perceptual().encode(env),
+            RenderingIntentNif::RelativeColorimetric => relative_colorimetric().encode(env),
+            RenderingIntentNif::Saturation => saturation().encode(env),
+            RenderingIntentNif::AbsoluteColorimetric => absolute_colorimetric().encode(env),
         }
     }
+}
 
-    fn rebuild_library(&mut self) {
-        let mut dict = Dict::new();
-        for (key, value) in &self.inputs {
-            dict.insert(
-                Str::from(key.as_str()),
-                Value::Str(Str::from(value.as_str())),
-            );
+impl From<RenderingIntentNif> for Intent {
+    fn from(intent: RenderingIntentNif) -> Self {
+        match intent {
+            RenderingIntentNif::Perceptual => Intent::Perceptual,
+            RenderingIntentNif::RelativeColorimetric => Intent::RelativeColorimetric,
+            RenderingIntentNif::Saturation => Intent::Saturation,
+            RenderingIntentNif::AbsoluteColorimetric => Intent::AbsoluteColorimetric,
         }
-        self.library = LazyHash::new(
-            Library::builder()
-                .with_inputs(dict)
-                .with_features(Features::from_iter([Feature::Html]))
-                .build(),
-        );
     }
 }
 
-impl World for SystemWorld {
-    fn library(&self) -> &LazyHash<Library> {
-        &self.library
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactionKindNif {
+    Text,
+    Url,
+    Alt,
+    Title,
+    Author,
+    Description,
+    Keywords,
+}
+
+impl Decoder<'_> for RedactionKindNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == text() {
+            Ok(RedactionKindNif::Text)
+        } else if atom == url() {
+            Ok(RedactionKindNif::Url)
+        } else if atom == alt() {
+            Ok(RedactionKindNif::Alt)
+        } else if atom == title() {
+            Ok(RedactionKindNif::Title)
+        } else if atom == author() {
+            Ok(RedactionKindNif::Author)
+        } else if atom == description() {
+            Ok(RedactionKindNif::Description)
+        } else if atom == keywords() {
+            Ok(RedactionKindNif::Keywords)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
     }
+}
 
-    fn book(&self) -> &LazyHash<FontBook> {
-        &self.book
+impl Encoder for RedactionKindNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            RedactionKindNif::Text => text().encode(env),
+            RedactionKindNif::Url => url().encode(env),
+            RedactionKindNif::Alt => alt().encode(env),
+            RedactionKindNif::Title => title().encode(env),
+            RedactionKindNif::Author => author().encode(env),
+            RedactionKindNif::Description => description().encode(env),
+            RedactionKindNif::Keywords => keywords().encode(env),
+        }
     }
+}
 
-    fn main(&self) -> FileId {
-        self.main
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontPermissionNif {
+    Installable,
+    Restricted,
+    PreviewAndPrint,
+    Editable,
+    Unknown,
+}
+
+impl Decoder<'_> for FontPermissionNif {
+    fn decode(term: Term) -> Result<Self, rustler::Error> {
+        let atom: Atom = term.decode()?;
+        if atom == installable() {
+            Ok(FontPermissionNif::Installable)
+        } else if atom == restricted() {
+            Ok(FontPermissionNif::Restricted)
+        } else if atom == preview_and_print() {
+            Ok(FontPermissionNif::PreviewAndPrint)
+        } else if atom == editable() {
+            Ok(FontPermissionNif::Editable)
+        } else if atom == unknown() {
+            Ok(FontPermissionNif::Unknown)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
     }
+}
 
-    fn source(&self, id: FileId) -> FileResult<Source> {
-        if id == *MARKUP_ID {
-            return Ok(Source::new(id, self.markup.clone()));
+impl Encoder for FontPermissionNif {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            FontPermissionNif::Installable => installable().encode(env),
+            FontPermissionNif::Restricted => restricted().encode(env),
+            FontPermissionNif::PreviewAndPrint => preview_and_print().encode(env),
+            FontPermissionNif::Editable => editable().encode(env),
+            FontPermissionNif::Unknown => unknown().encode(env),
         }
+    }
+}
 
-        if let Some(path) = id.vpath().as_rootless_path().to_str() {
-            if let Some(content) = self.virtual_files.get(path) {
-                let text = decode_utf8(content)?;
-                return Ok(Source::new(id, text.into()));
+impl From<Option<ttf_parser::Permissions>> for FontPermissionNif {
+    fn from(permissions: Option<ttf_parser::Permissions>) -> Self {
+        match permissions {
+            Some(ttf_parser::Permissions::Installable) => FontPermissionNif::Installable,
+            Some(ttf_parser::Permissions::Restricted) => FontPermissionNif::Restricted,
+            Some(ttf_parser::Permissions::PreviewAndPrint) => FontPermissionNif::PreviewAndPrint,
+            Some(ttf_parser::Permissions::Editable) => FontPermissionNif::Editable,
+            None => FontPermissionNif::Unknown,
+        }
+    }
+}
+
+/// Parse Typst feature names as passed from Elixir (e.g. `"a11y_extras"`).
+fn parse_features(names: &[String]) -> Result<Features, String> {
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            "html" => Ok(Feature::Html),
+            "a11y_extras" => Ok(Feature::A11yExtras),
+            other => Err(format!(
+                "Unknown Typst feature: {} (expected one of: html, a11y_extras)",
+                other
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Features::from_iter)
+}
+
+impl DefaultStylesNif {
+    /// Convert to library-level styles, validating every value against the
+    /// fonts available in the given book.
+    fn to_styles(&self, book: &FontBook) -> Result<Styles, String> {
+        let mut styles = Styles::new();
+
+        if !self.font.is_empty() {
+            for family in &self.font {
+                if book.select_family(&family.to_lowercase()).next().is_none() {
+                    return Err(format!("Unknown font family: {}", family));
+                }
             }
+            let families = self.font.iter().map(|f| FontFamily::new(f)).collect();
+            styles.set(TextElem::font, FontList(families));
         }
 
-        self.slot(id, |slot| slot.source(&self.root, &self.package_storage))
-    }
+        if let Some(ref lang) = self.lang {
+            let lang =
+                Lang::from_str(lang).map_err(|e| format!("Invalid language {:?}: {}", lang, e))?;
+            styles.set(TextElem::lang, lang);
+        }
 
-    fn file(&self, id: FileId) -> FileResult<Bytes> {
-        if let Some(path) = id.vpath().as_rootless_path().to_str() {
-            if let Some(content) = self.virtual_files.get(path) {
-                return Ok(Bytes::new(content.clone()));
+        if let Some(ref region) = self.region {
+            let region = Region::from_str(region)
+                .map_err(|e| format!("Invalid region {:?}: {}", region, e))?;
+            styles.set(TextElem::region, Some(region));
+        }
+
+        if let Some(ref paper) = self.paper {
+            let paper =
+                Paper::from_str(paper).map_err(|_| format!("Unknown paper size: {}", paper))?;
+            styles.set(PageElem::width, Smart::Custom(paper.width().into()));
+            styles.set(PageElem::height, Smart::Custom(paper.height().into()));
+        }
+
+        if let Some(margin) = self.margin {
+            if !margin.is_finite() || margin < 0.0 {
+                return Err(format!("Invalid margin: {}", margin));
             }
+            let margin = Length::from(Abs::pt(margin));
+            styles.set(
+                PageElem::margin,
+                Margin::splat(Some(Smart::Custom(margin.into()))),
+            );
         }
 
-        self.slot(id, |slot| slot.file(&self.root, &self.package_storage))
-    }
+        if let Some(justify) = self.justify {
+            styles.set(ParElem::justify, justify);
+        }
 
-    fn font(&self, index: usize) -> Option<Font> {
-        self.fonts.get(index)?.get()
+        Ok(styles)
     }
+}
 
-    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
-        let now = match &self.now {
-            Now::Fixed(time) => time,
-            Now::System(time) => time.get_or_init(Utc::now),
-        };
+impl PdfOptionsNif {
+    fn to_pdf_options(&self) -> Result<PdfOptions<'_>, String> {
+        let mut opts = PdfOptions::default();
 
-        let with_offset = match offset {
-            None => now.with_timezone(&Local).fixed_offset(),
-            Some(hours) => {
-                let seconds = i32::try_from(hours).ok()?.checked_mul(3600)?;
-                now.with_timezone(&FixedOffset::east_opt(seconds)?)
+        if let Some(ref document_id) = self.document_id {
+            opts.ident = Smart::Custom(document_id.as_str());
+        }
+
+        if !self.pdf_standards.is_empty() {
+            let standards: Vec<PdfStandard> =
+                self.pdf_standards.iter().map(|&s| s.into()).collect();
+            opts.standards = PdfStandards::new(&standards)
+                .map_err(|e| format!("Invalid PDF standards: {}", e))?;
+        }
+
+        opts.tagged = self.tagged;
+
+        if let Some(ref encryption) = self.encryption {
+            encryption.validate(&self.pdf_standards)?;
+        }
+
+        if let Some(ref metadata) = self.metadata {
+            metadata.validate()?;
+        }
+
+        if let Some(ref print) = self.print {
+            print.validate()?;
+        }
+
+        if let Some(ref color) = self.color {
+            color.profile()?;
+        }
+
+        if let Some(ref optimize) = self.optimize {
+            optimize.validate()?;
+        }
+
+        if let Some(ref signature) = self.signature {
+            if self.encryption.is_some() {
+                return Err("Signed PDFs cannot also be encrypted".to_string());
             }
-        };
+            signature.validate()?;
+        }
 
-        Datetime::from_ymd(
-            with_offset.year(),
-            with_offset.month().try_into().ok()?,
-            with_offset.day().try_into().ok()?,
-        )
+        if self.linearize {
+            if self.signature.is_some() {
+                return Err("Signed PDFs cannot be linearized".to_string());
+            }
+            if self.encryption.is_some() {
+                return Err("Encrypted PDFs cannot be linearized".to_string());
+            }
+        }
+
+        Ok(opts)
     }
 }
 
-impl SystemWorld {
-    fn slot<F, T>(&self, id: FileId, f: F) -> T
-    where
-        F: FnOnce(&mut FileSlot) -> T,
-    {
-        let mut map = self.slots.lock();
-        f(map.entry(id).or_insert_with(|| FileSlot::new(id)))
+impl PdfEncryptionNif {
+    /// Reject settings that cannot produce a usable or conforming file.
+    fn validate(&self, standards: &[PdfStandardNif]) -> Result<(), String> {
+        if let Some(standard) = standards
+            .iter()
+            .find(|s| matches!(s, PdfStandardNif::PdfA2b | PdfStandardNif::PdfA3b))
+        {
+            return Err(format!(
+                "Encryption is not allowed by {} (PDF/A forbids encrypted files)",
+                standard.name()
+            ));
+        }
+        if self.owner_password.is_empty() {
+            return Err("Encryption requires a non-empty owner_password".to_string());
+        }
+        if self.user_password().is_empty() && !self.allow_open {
+            return Err(
+                "Encryption requires a non-empty user_password; set allow_open: true to let \
+                 anyone open the file"
+                    .to_string(),
+            );
+        }
+        if self.owner_password == self.user_password() {
+            return Err(
+                "The owner_password must differ from the user_password, or recipients \
+                 could lift the permission restrictions"
+                    .to_string(),
+            );
+        }
+        Ok(())
     }
-}
 
-struct FileSlot {
-    id: FileId,
-    source: SlotCell<Source>,
-    file: SlotCell<Bytes>,
+    /// The password needed to open the file, empty when anyone may open it.
+    fn user_password(&self) -> &str {
+        self.user_password.as_deref().unwrap_or("")
+    }
+
+    fn permissions(&self) -> Permissions {
+        let mut permissions = Permissions::FILLABLE | Permissions::COPYABLE_FOR_ACCESSIBILITY;
+        if self.print {
+            permissions |= Permissions::PRINTABLE | Permissions::PRINTABLE_IN_HIGH_QUALITY;
+        }
+        if self.copy {
+            permissions |= Permissions::COPYABLE;
+        }
+        if self.modify {
+            permissions |= Permissions::MODIFIABLE | Permissions::ASSEMBLABLE;
+        }
+        if self.annotate {
+            permissions |= Permissions::ANNOTABLE;
+        }
+        permissions
+    }
 }
 
-impl FileSlot {
-    fn new(id: FileId) -> Self {
-        Self {
-            id,
-            file: SlotCell::new(),
-            source: SlotCell::new(),
+impl PdfMetadataNif {
+    fn validate(&self) -> Result<(), String> {
+        if self.language.as_deref() == Some("") {
+            return Err("The metadata language cannot be empty".to_string());
+        }
+        self.locale()?;
+        if self.xmp_namespace.is_empty() {
+            return Err("The XMP namespace cannot be empty".to_string());
         }
+        if let Some(name) = self.xmp.keys().find(|name| !is_xml_name(name)) {
+            return Err(format!(
+                "Invalid XMP property name {:?}: use letters, digits, '_', '-' and '.'",
+                name
+            ));
+        }
+        Ok(())
     }
 
-    fn reset(&mut self) {
-        self.source.reset();
-        self.file.reset();
+    /// The language as a locale, from a tag like `"de"` or `"de-AT"`.
+    fn locale(&self) -> Result<Option<typst::text::Locale>, String> {
+        let Some(ref language) = self.language else {
+            return Ok(None);
+        };
+        let (lang, region) = match language.split_once('-') {
+            Some((lang, region)) => (lang, Some(region)),
+            None => (language.as_str(), None),
+        };
+        let lang =
+            Lang::from_str(lang).map_err(|e| format!("Invalid language {:?}: {}", language, e))?;
+        let region = region
+            .map(Region::from_str)
+            .transpose()
+            .map_err(|e| format!("Invalid language {:?}: {}", language, e))?;
+        Ok(Some(typst::text::Locale { lang, region }))
     }
 
-    fn source(
-        &mut self,
-        project_root: &Path,
-        package_storage: &PackageStorage,
-    ) -> FileResult<Source> {
-        self.source.get_or_init(
-            || read(self.id, project_root, package_storage),
-            |data, prev| {
-                let name = if prev.is_some() {
-                    "reparsing file"
-                } else {
-                    "parsing file"
-                };
-                let _scope = TimingScope::new(name);
-                let text = decode_utf8(&data)?;
-                if let Some(mut prev) = prev {
-                    prev.replace(text);
-                    Ok(prev)
-                } else {
-                    Ok(Source::new(self.id, text.into()))
-                }
-            },
-        )
+    /// A copy of the document with the overridden fields in its info.
+    /// Keywords are added to the template's own. The language goes into the
+    /// locale, which typst-pdf writes to the catalog, the XMP metadata, and
+    /// the tag tree.
+    fn apply(&self, document: &PagedDocument) -> PagedDocument {
+        let mut document = document.clone();
+        let info = &mut document.info;
+        if let Some(ref title) = self.title {
+            info.title = Some(title.as_str().into());
+        }
+        if !self.authors.is_empty() {
+            info.author = self.authors.iter().map(|a| a.as_str().into()).collect();
+        }
+        if let Some(ref subject) = self.subject {
+            info.description = Some(subject.as_str().into());
+        }
+        info.keywords
+            .extend(self.keywords.iter().map(|k| k.as_str().into()));
+        if let Ok(Some(locale)) = self.locale() {
+            info.locale = Smart::Custom(locale);
+        }
+        document
     }
 
-    fn file(&mut self, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Bytes> {
-        self.file.get_or_init(
-            || read(self.id, project_root, package_storage),
-            |data, _| Ok(Bytes::new(data)),
+    /// The custom properties as an XMP description, together with the PDF/A
+    /// extension schema that declares them.
+    fn xmp_description(&self) -> String {
+        let namespace = xml_escape(&self.xmp_namespace);
+        let mut names: Vec<&String> = self.xmp.keys().collect();
+        names.sort();
+
+        let mut properties = String::new();
+        let mut schema = String::new();
+        for name in names {
+            properties.push_str(&format!(
+                "<ashtypst:{name}>{}</ashtypst:{name}>",
+                xml_escape(&self.xmp[name])
+            ));
+            schema.push_str(&format!(
+                "<rdf:li rdf:parseType=\"Resource\">\
+                 <pdfaProperty:name>{name}</pdfaProperty:name>\
+                 <pdfaProperty:valueType>Text</pdfaProperty:valueType>\
+                 <pdfaProperty:category>external</pdfaProperty:category>\
+                 <pdfaProperty:description>{name}</pdfaProperty:description>\
+                 </rdf:li>"
+            ));
+        }
+
+        format!(
+            "<rdf:Description rdf:about=\"\" xmlns:ashtypst=\"{namespace}\">{properties}</rdf:Description>\
+             <rdf:Description rdf:about=\"\" \
+             xmlns:pdfaExtension=\"http://www.aiim.org/pdfa/ns/extension/\" \
+             xmlns:pdfaSchema=\"http://www.aiim.org/pdfa/ns/schema#\" \
+             xmlns:pdfaProperty=\"http://www.aiim.org/pdfa/ns/property#\">\
+             <pdfaExtension:schemas><rdf:Bag><rdf:li rdf:parseType=\"Resource\">\
+             <pdfaSchema:schema>Custom document properties</pdfaSchema:schema>\
+             <pdfaSchema:namespaceURI>{namespace}</pdfaSchema:namespaceURI>\
+             <pdfaSchema:prefix>ashtypst</pdfaSchema:prefix>\
+             <pdfaSchema:property><rdf:Seq>{schema}</rdf:Seq></pdfaSchema:property>\
+             </rdf:li></rdf:Bag></pdfaExtension:schemas></rdf:Description>"
         )
     }
 }
 
-struct SlotCell<T> {
-    data: Option<FileResult<T>>,
-    fingerprint: u128,
-    accessed: bool,
-}
+/// Gap between the bleed edge and the printer's marks, in points.
+const MARK_GAP: f64 = 3.0;
 
-impl<T: Clone> SlotCell<T> {
-    fn new() -> Self {
-        Self {
-            data: None,
-            fingerprint: 0,
-            accessed: false,
+impl PdfPrintNif {
+    fn validate(&self) -> Result<(), String> {
+        if !self.bleed.is_finite() || self.bleed < 0.0 {
+            return Err("The bleed must be zero or positive".to_string());
+        }
+        if !self.mark_length.is_finite() || self.mark_length <= 0.0 {
+            return Err("The mark length must be positive".to_string());
         }
+        Ok(())
     }
 
-    fn reset(&mut self) {
-        self.accessed = false;
+    /// The space outside the bleed reserved for printer's marks.
+    fn slug(&self) -> f64 {
+        if self.crop_marks || self.registration_marks {
+            self.mark_length + 2.0 * MARK_GAP
+        } else {
+            0.0
+        }
     }
 
-    fn get_or_init(
-        &mut self,
-        load: impl FnOnce() -> FileResult<Vec<u8>>,
-        f: impl FnOnce(Vec<u8>, Option<T>) -> FileResult<T>,
+    /// The distance from the media edge to the trim edge.
+    fn margin(&self) -> f64 {
+        self.bleed + self.slug()
+    }
+
+    /// A copy of the document with every page extended by the bleed and
+    /// slug. The introspector is rebuilt so links and the outline point at
+    /// the moved content.
+    fn apply(&self, document: &PagedDocument) -> PagedDocument {
+        let pages: Vec<Page> = document.pages.iter().map(|page| self.page(page)).collect();
+        let introspector = introspect_pages(&pages);
+        PagedDocument {
+            pages,
+            info: document.info.clone(),
+            introspector,
+        }
+    }
+
+    /// Place the page on a larger sheet. Content the page lays out beyond its
+    /// edges shows in the bleed, the page fill is extended over the bleed,
+    /// and the marks are drawn in the slug.
+    fn page(&self, page: &Page) -> Page {
+        let trim = page.frame.size();
+        let bleed = Abs::pt(self.bleed);
+        let slug = Abs::pt(self.slug());
+        let margin = Abs::pt(self.margin());
+        let mut frame = Frame::hard(Size::new(trim.x + margin * 2.0, trim.y + margin * 2.0));
+
+        if let Smart::Custom(Some(ref paint)) = page.fill {
+            let area = Size::new(trim.x + bleed * 2.0, trim.y + bleed * 2.0);
+            frame.push(
+                Point::splat(slug),
+                FrameItem::Shape(Geometry::Rect(area).filled(paint.clone()), Span::detached()),
+            );
+        }
+        frame.push_frame(Point::splat(margin), page.frame.clone());
+
+        let stroke = FixedStroke::from_pair(Color::BLACK, Abs::pt(0.25));
+        let mut draw = |at: Point, geometry: Geometry| {
+            frame.push(
+                at,
+                FrameItem::Shape(geometry.stroked(stroke.clone()), Span::detached()),
+            );
+        };
+        let start = bleed + Abs::pt(MARK_GAP);
+        let length = Abs::pt(self.mark_length);
+        let (left, top) = (margin, margin);
+        let (right, bottom) = (margin + trim.x, margin + trim.y);
+
+        if self.crop_marks {
+            for (x, dx) in [(left, -1.0), (right, 1.0)] {
+                for (y, dy) in [(top, -1.0), (bottom, 1.0)] {
+                    draw(
+                        Point::new(x + start * dx, y),
+                        Geometry::Line(Point::with_x(length * dx)),
+                    );
+                    draw(
+                        Point::new(x, y + start * dy),
+                        Geometry::Line(Point::with_y(length * dy)),
+                    );
+                }
+            }
+        }
+
+        if self.registration_marks {
+            let radius = length / 4.0;
+            let offset = start + length / 2.0;
+            let centers = [
+                Point::new((left + right) / 2.0, top - offset),
+                Point::new((left + right) / 2.0, bottom + offset),
+                Point::new(left - offset, (top + bottom) / 2.0),
+                Point::new(right + offset, (top + bottom) / 2.0),
+            ];
+            for center in centers {
+                draw(
+                    center - Point::with_x(radius * 1.5),
+                    Geometry::Line(Point::with_x(radius * 3.0)),
+                );
+                draw(
+                    center - Point::with_y(radius * 1.5),
+                    Geometry::Line(Point::with_y(radius * 3.0)),
+                );
+                draw(
+                    center - Point::splat(radius),
+                    Geometry::Curve(Curve::ellipse(Size::splat(radius * 2.0))),
+                );
+            }
+        }
+
+        let mut printed = page.clone();
+        printed.frame = frame;
+        printed.fill = Smart::Custom(None);
+        printed
+    }
+}
+
+impl PdfColorNif {
+    /// Parse the ICC profile, which must describe a CMYK output device.
+    fn profile(&self) -> Result<Profile, String> {
+        let profile =
+            Profile::new_icc(&self.profile.0).map_err(|e| format!("Invalid ICC profile: {}", e))?;
+        if profile.color_space() != ColorSpaceSignature::CmykData {
+            return Err("The ICC profile must be a CMYK profile".to_string());
+        }
+        Ok(profile)
+    }
+
+    /// The profile's description, used to name the output condition.
+    fn description(&self) -> String {
+        self.profile()
+            .ok()
+            .and_then(|profile| profile.info(InfoType::Description, Locale::none()))
+            .unwrap_or_else(|| "CMYK".to_string())
+    }
+
+    /// The colour transform from sRGB to the profile.
+    fn transform(&self) -> Result<ColorTransform<[f64; 3], [f64; 4]>, String> {
+        ColorTransform::new(
+            &Profile::new_srgb(),
+            PixelFormat::RGB_DBL,
+            &self.profile()?,
+            PixelFormat::CMYK_DBL,
+            self.intent.into(),
+        )
+        .map_err(|e| format!("Failed to set up colour conversion: {}", e))
+    }
+
+    /// Prepare the document for the conversion of its exported PDF, along
+    /// with warnings for the images and paints that are left as they are.
+    ///
+    /// PDF/A export rejects CMYK colours without an embedded profile, so
+    /// there the document's CMYK colours are swapped for marker RGB colours
+    /// it does not otherwise use, which [`Self::convert_pdf`] turns back into
+    /// the original ink values. The markers are returned with the document.
+    fn prepare(
+        &self,
+        document: &PagedDocument,
+        pdf_a: bool,
+    ) -> (PagedDocument, HashMap<[u8; 3], [u8; 4]>, Vec<DiagnosticNif>) {
+        let mut preparer = CmykPreparer {
+            swap: pdf_a,
+            used: HashSet::new(),
+            markers: HashMap::new(),
+            warnings: vec![],
+        };
+        if pdf_a {
+            for page in &document.pages {
+                collect_srgb_colors(&page.frame, &mut preparer.used);
+                if let Smart::Custom(Some(Paint::Solid(fill))) = page.fill {
+                    preparer.used.extend(srgb_u8(&fill));
+                }
+            }
+        }
+
+        let mut prepared = document.clone();
+        for page in &mut prepared.pages {
+            page.frame = preparer.frame(&page.frame);
+            if let Smart::Custom(Some(ref fill)) = page.fill {
+                page.fill = Smart::Custom(Some(preparer.paint(fill, Span::detached())));
+            }
+        }
+
+        let warnings = preparer
+            .warnings
+            .into_iter()
+            .map(|(message, span)| DiagnosticNif {
+                severity: SeverityNif::Warning,
+                message: message.to_string(),
+                span: span_to_nif_simple(span),
+                trace: vec![],
+                hints: vec!["convert it to CMYK before adding it to the document".to_string()],
+            })
+            .collect();
+        let markers = preparer
+            .markers
+            .into_iter()
+            .map(|(cmyk, rgb)| (rgb, cmyk))
+            .collect();
+        (prepared, markers, warnings)
+    }
+
+    /// Convert the solid colours of the exported PDF's pages and form
+    /// XObjects to an ICC-based CMYK colour space with the profile, and embed
+    /// the profile as the output intent for print (PDF/X) and, with PDF/A,
+    /// for archiving as well. Greys become pure black ink, colours that are
+    /// already CMYK keep their values, and `markers` map back to CMYK
+    /// colours swapped out by [`Self::prepare`]. Shadings and tiling
+    /// patterns are left as they are.
+    fn convert_pdf(
+        &self,
+        pdf: &[u8],
+        markers: &HashMap<[u8; 3], [u8; 4]>,
+        pdf_a: bool,
+    ) -> Result<Vec<u8>, String> {
+        let read_error = |e: lopdf::Error| format!("Failed to read PDF: {}", e);
+        let mut document = lopdf::Document::load_mem(pdf).map_err(read_error)?;
+        let root_id = document
+            .trailer
+            .get(b"Root")
+            .and_then(Object::as_reference)
+            .map_err(read_error)?;
+
+        let profile_id = document.add_object(lopdf::Stream::new(
+            dictionary! { "N" => 4i64 },
+            self.profile.0.clone(),
+        ));
+        let space_id = document.add_object(vec![Object::from("ICCBased"), profile_id.into()]);
+
+        let mut converter = PdfCmykConverter {
+            transform: self.transform()?,
+            markers,
+            space_id,
+            visited: HashSet::new(),
+        };
+        let page_ids: Vec<ObjectId> = document.get_pages().into_values().collect();
+        for page_id in page_ids {
+            converter
+                .convert(&mut document, page_id, true)
+                .map_err(read_error)?;
+        }
+
+        let description = self.description();
+        let condition = self
+            .output_condition
+            .clone()
+            .unwrap_or_else(|| description.clone());
+        let intent = |subtype: &str| -> Object {
+            dictionary! {
+                "Type" => "OutputIntent",
+                "S" => subtype,
+                "OutputConditionIdentifier" => Object::string_literal(condition.clone()),
+                "Info" => Object::string_literal(description.clone()),
+                "DestOutputProfile" => profile_id,
+            }
+            .into()
+        };
+        let mut intents = vec![intent("GTS_PDFX")];
+        if pdf_a {
+            intents.push(intent("GTS_PDFA1"));
+        }
+
+        document
+            .get_dictionary_mut(root_id)
+            .map_err(read_error)?
+            .set("OutputIntents", intents);
+
+        let mut out = Vec::new();
+        document
+            .save_to(&mut out)
+            .map_err(|e| format!("Failed to write PDF: {}", e))?;
+        Ok(out)
+    }
+}
+
+/// The 8-bit sRGB value typst exports a solid colour with, or `None` for
+/// greys and CMYK colours, which keep their own colour spaces.
+fn srgb_u8(color: &Color) -> Option<[u8; 3]> {
+    match color.space() {
+        ColorSpace::D65Gray | ColorSpace::Cmyk => None,
+        _ => {
+            let [r, g, b, _] = color.to_space(ColorSpace::Srgb).to_vec4_u8();
+            Some([r, g, b])
+        }
+    }
+}
+
+/// Collect the sRGB values of the solid colours in a frame.
+fn collect_srgb_colors(frame: &Frame, used: &mut HashSet<[u8; 3]>) {
+    for (_, item) in frame.items() {
+        let paints: Vec<&Paint> = match item {
+            FrameItem::Group(group) => {
+                collect_srgb_colors(&group.frame, used);
+                continue;
+            }
+            FrameItem::Text(text) => std::iter::once(&text.fill)
+                .chain(text.stroke.as_ref().map(|stroke| &stroke.paint))
+                .collect(),
+            FrameItem::Shape(shape, _) => shape
+                .fill
+                .iter()
+                .chain(shape.stroke.as_ref().map(|stroke| &stroke.paint))
+                .collect(),
+            _ => continue,
+        };
+        for paint in paints {
+            if let Paint::Solid(color) = paint {
+                used.extend(srgb_u8(color));
+            }
+        }
+    }
+}
+
+/// Walks the frames of a document before export for
+/// [`PdfColorNif::prepare`].
+struct CmykPreparer {
+    swap: bool,
+    used: HashSet<[u8; 3]>,
+    markers: HashMap<[u8; 4], [u8; 3]>,
+    warnings: Vec<(&'static str, Span)>,
+}
+
+impl CmykPreparer {
+    fn warn(&mut self, message: &'static str, span: Span) {
+        if !self.warnings.contains(&(message, span)) {
+            self.warnings.push((message, span));
+        }
+    }
+
+    fn frame(&mut self, frame: &Frame) -> Frame {
+        let mut prepared = Frame::new(frame.size(), frame.kind());
+        if frame.has_baseline() {
+            prepared.set_baseline(frame.baseline());
+        }
+
+        for (point, item) in frame.items() {
+            let item = match item {
+                FrameItem::Group(group) => {
+                    let mut group = group.clone();
+                    group.frame = self.frame(&group.frame);
+                    FrameItem::Group(group)
+                }
+                FrameItem::Text(text) => {
+                    let span = text
+                        .glyphs
+                        .first()
+                        .map(|g| g.span.0)
+                        .unwrap_or_else(Span::detached);
+                    let mut text = text.clone();
+                    text.fill = self.paint(&text.fill, span);
+                    text.stroke = text.stroke.map(|stroke| self.stroke(stroke, span));
+                    FrameItem::Text(text)
+                }
+                FrameItem::Shape(shape, span) => {
+                    let mut shape = shape.clone();
+                    shape.fill = shape.fill.map(|fill| self.paint(&fill, *span));
+                    shape.stroke = shape.stroke.map(|stroke| self.stroke(stroke, *span));
+                    FrameItem::Shape(shape, *span)
+                }
+                FrameItem::Image(image, _, span) => {
+                    if let ImageKind::Raster(_) = image.kind() {
+                        self.warn("Image colours are not converted to CMYK", *span);
+                    }
+                    item.clone()
+                }
+                _ => item.clone(),
+            };
+            prepared.push(*point, item);
+        }
+        prepared
+    }
+
+    fn stroke(&mut self, mut stroke: FixedStroke, span: Span) -> FixedStroke {
+        stroke.paint = self.paint(&stroke.paint, span);
+        stroke
+    }
+
+    fn paint(&mut self, paint: &Paint, span: Span) -> Paint {
+        match paint {
+            Paint::Solid(color) => Paint::Solid(self.color(*color)),
+            _ => {
+                self.warn(
+                    "Gradient and tiling colours are not converted to CMYK",
+                    span,
+                );
+                paint.clone()
+            }
+        }
+    }
+
+    /// Swap a CMYK colour for its marker.
+    fn color(&mut self, color: Color) -> Color {
+        if !self.swap || color.space() != ColorSpace::Cmyk {
+            return color;
+        }
+
+        let [c, m, y, k] = color.to_vec4_u8();
+        let marker = match self.markers.get(&[c, m, y, k]) {
+            Some(&marker) => marker,
+            None => {
+                let marker = (0x010203u32..)
+                    .map(|n| [(n >> 16) as u8, (n >> 8) as u8, n as u8])
+                    .find(|rgb| !self.used.contains(rgb))
+                    .unwrap();
+                self.used.insert(marker);
+                self.markers.insert([c, m, y, k], marker);
+                marker
+            }
+        };
+        let [r, g, b] = marker;
+        Color::from_u8(r, g, b, 255)
+    }
+}
+
+/// Rewrites the content streams of an exported PDF in place for
+/// [`PdfColorNif::convert_pdf`].
+struct PdfCmykConverter<'a> {
+    transform: ColorTransform<[f64; 3], [f64; 4]>,
+    markers: &'a HashMap<[u8; 3], [u8; 4]>,
+    space_id: ObjectId,
+    visited: HashSet<ObjectId>,
+}
+
+/// The colour space a content stream paints solid colours in.
+#[derive(Clone, Copy, PartialEq)]
+enum PdfColorSpace {
+    Rgb,
+    Gray,
+    Other,
+}
+
+impl PdfCmykConverter<'_> {
+    /// Convert the content of a page or form XObject and of the form
+    /// XObjects it uses. The CMYK colour space is added to its resources.
+    fn convert(
+        &mut self,
+        document: &mut lopdf::Document,
+        id: ObjectId,
+        is_page: bool,
+    ) -> lopdf::Result<()> {
+        if !self.visited.insert(id) {
+            return Ok(());
+        }
+
+        let owner = match document.get_object(id)? {
+            Object::Stream(stream) => &stream.dict,
+            object => object.as_dict()?,
+        };
+        let Ok(resources) = owner.get(b"Resources") else {
+            return Ok(());
+        };
+        let mut resources = document.dereference(resources)?.1.as_dict()?.clone();
+        let mut color_spaces = match resources.get(b"ColorSpace") {
+            Ok(spaces) => document.dereference(spaces)?.1.as_dict()?.clone(),
+            Err(_) => lopdf::Dictionary::new(),
+        };
+
+        let mut spaces = HashMap::new();
+        for (name, space) in color_spaces.iter() {
+            let kind = match document.dereference(space)?.1.as_array().map(Vec::as_slice) {
+                Ok([family, profile]) if family.as_name().is_ok_and(|f| f == b"ICCBased") => {
+                    match document.dereference(profile)?.1.as_stream()?.dict.get(b"N") {
+                        Ok(Object::Integer(3)) => PdfColorSpace::Rgb,
+                        Ok(Object::Integer(1)) => PdfColorSpace::Gray,
+                        _ => PdfColorSpace::Other,
+                    }
+                }
+                _ => PdfColorSpace::Other,
+            };
+            spaces.insert(name.clone(), kind);
+        }
+        let cmyk = (0..)
+            .map(|i| format!("cmyk{}", i).into_bytes())
+            .find(|name| !color_spaces.has(name))
+            .unwrap();
+        color_spaces.set(cmyk.clone(), self.space_id);
+        resources.set("ColorSpace", color_spaces);
+
+        let forms: Vec<ObjectId> = match resources.get(b"XObject") {
+            Ok(xobjects) => document
+                .dereference(xobjects)?
+                .1
+                .as_dict()?
+                .iter()
+                .filter_map(|(_, xobject)| xobject.as_reference().ok())
+                .filter(|&xobject| {
+                    document
+                        .get_object(xobject)
+                        .and_then(Object::as_stream)
+                        .and_then(|stream| stream.dict.get(b"Subtype"))
+                        .and_then(Object::as_name)
+                        .is_ok_and(|subtype| subtype == b"Form")
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+
+        if is_page {
+            let contents = document.get_page_contents(id);
+            let content = document.get_page_content(id)?;
+            let converted = self.content(&content, &spaces, &cmyk)?;
+            let mut stream = lopdf::Stream::new(lopdf::Dictionary::new(), converted);
+            stream.compress()?;
+            for old in contents {
+                document.objects.remove(&old);
+            }
+            let content_id = document.add_object(stream);
+            let page = document.get_dictionary_mut(id)?;
+            page.set("Resources", resources);
+            page.set("Contents", content_id);
+        } else {
+            let stream = document.get_object_mut(id)?.as_stream_mut()?;
+            let content = stream.get_plain_content()?;
+            let converted = self.content(&content, &spaces, &cmyk)?;
+            stream.dict.set("Resources", resources);
+            stream.set_plain_content(converted);
+            stream.compress()?;
+        }
+
+        for form in forms {
+            self.convert(document, form, false)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the colour operators of a content stream, following the
+    /// colour spaces through `q`/`Q` nesting.
+    fn content(
+        &self,
+        content: &[u8],
+        spaces: &HashMap<Vec<u8>, PdfColorSpace>,
+        cmyk: &[u8],
+    ) -> lopdf::Result<Vec<u8>> {
+        let content = lopdf::content::Content::decode(content)?;
+        let mut operations = Vec::with_capacity(content.operations.len());
+        // The fill and stroke colour spaces, saved by `q` and restored by `Q`.
+        let mut state = (PdfColorSpace::Other, PdfColorSpace::Other);
+        let mut saved = Vec::new();
+
+        for mut op in content.operations {
+            let stroke = op.operator.chars().all(|c| c.is_ascii_uppercase());
+            let current = if stroke { state.1 } else { state.0 };
+            let mut space = current;
+            match op.operator.as_str() {
+                "q" => saved.push(state),
+                "Q" => state = saved.pop().unwrap_or(state),
+                "cs" | "CS" => {
+                    space = op
+                        .operands
+                        .first()
+                        .and_then(|name| name.as_name().ok())
+                        .and_then(|name| spaces.get(name).copied())
+                        .unwrap_or(PdfColorSpace::Other);
+                    if space != PdfColorSpace::Other {
+                        op.operands = vec![Object::Name(cmyk.to_vec())];
+                    }
+                }
+                "sc" | "scn" | "SC" | "SCN" => {
+                    if let Some(values) = self.values(current, &op.operands) {
+                        op.operands = values;
+                    }
+                }
+                "rg" | "RG" | "g" | "G" | "k" | "K" => {
+                    space = match op.operator.as_str() {
+                        "rg" | "RG" => PdfColorSpace::Rgb,
+                        "g" | "G" => PdfColorSpace::Gray,
+                        _ => PdfColorSpace::Other,
+                    };
+                    let values = self.values(space, &op.operands).unwrap_or(op.operands);
+                    let (set_space, set_color) = if stroke { ("CS", "SCN") } else { ("cs", "scn") };
+                    operations.push(lopdf::content::Operation::new(
+                        set_space,
+                        vec![Object::Name(cmyk.to_vec())],
+                    ));
+                    op = lopdf::content::Operation::new(set_color, values);
+                }
+                _ => {}
+            }
+            if space != current {
+                if stroke {
+                    state.1 = space;
+                } else {
+                    state.0 = space;
+                }
+            }
+            operations.push(op);
+        }
+
+        lopdf::content::Content { operations }.encode()
+    }
+
+    /// The CMYK operands for a solid colour in `space`, or `None` when the
+    /// colour is not converted.
+    fn values(&self, space: PdfColorSpace, operands: &[Object]) -> Option<Vec<Object>> {
+        let values: Vec<f32> = operands
+            .iter()
+            .map(Object::as_float)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let cmyk = match (space, &values[..]) {
+            (PdfColorSpace::Gray, &[gray]) => [0.0, 0.0, 0.0, 1.0 - gray],
+            (PdfColorSpace::Rgb, &[r, g, b]) => {
+                let rgb = [r, g, b].map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8);
+                match self.markers.get(&rgb) {
+                    Some(cmyk) => cmyk.map(|v| v as f32 / 255.0),
+                    None => {
+                        let mut cmyk = [[0.0; 4]];
+                        self.transform
+                            .transform_pixels(&[[r as f64, g as f64, b as f64]], &mut cmyk);
+                        cmyk[0].map(|v| (v / 100.0).clamp(0.0, 1.0) as f32)
+                    }
+                }
+            }
+            _ => return None,
+        };
+        Some(cmyk.map(Object::Real).to_vec())
+    }
+}
+
+impl PdfOptimizeNif {
+    fn validate(&self) -> Result<(), String> {
+        if self
+            .max_dpi
+            .is_some_and(|dpi| !dpi.is_finite() || dpi <= 0.0)
+        {
+            return Err("The maximum image DPI must be positive".to_string());
+        }
+        if let Some(quality) = self.jpeg_quality {
+            if !(1..=100).contains(&quality) {
+                return Err(format!(
+                    "Invalid JPEG quality: {} (must be between 1 and 100)",
+                    quality
+                ));
+            }
+        }
+        if let Some(level) = self.compression {
+            if level > 9 {
+                return Err(format!(
+                    "Invalid compression level: {} (must be between 0 and 9)",
+                    level
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// A copy of the document with its raster images downsampled to the
+    /// maximum DPI at the largest size each is shown at, and JPEG images
+    /// re-encoded at the JPEG quality. Also returns how many images changed
+    /// and their encoded sizes before and after; the PDF sizes are left for
+    /// the export to fill in.
+    fn apply(
+        &self,
+        document: &PagedDocument,
+    ) -> Result<(PagedDocument, PdfOptimizationNif), String> {
+        let mut images = HashMap::new();
+        for page in &document.pages {
+            collect_images(&page.frame, Transform::identity(), &mut images);
+        }
+
+        let mut report = PdfOptimizationNif {
+            original_size: 0,
+            size: 0,
+            images: 0,
+            original_image_size: 0,
+            image_size: 0,
+        };
+        let mut replacements = HashMap::new();
+        for (key, (image, shown)) in images {
+            if let Some(optimized) = self.image(&image, shown)? {
+                report.images += 1;
+                report.original_image_size += raster_size(&image);
+                report.image_size += raster_size(&optimized);
+                replacements.insert(key, optimized);
+            }
+        }
+
+        let mut optimized = document.clone();
+        if !replacements.is_empty() {
+            for page in &mut optimized.pages {
+                page.frame = replace_images(&page.frame, &replacements);
+            }
+        }
+        Ok((optimized, report))
+    }
+
+    /// The optimized version of a raster image shown at the given size, or
+    /// `None` to keep it. Images with a CMYK or grey ICC profile are kept,
+    /// since they are re-encoded as RGB. Lossless images stay lossless.
+    fn image(&self, image: &Image, shown: Size) -> Result<Option<Image>, String> {
+        let ImageKind::Raster(raster) = image.kind() else {
+            return Ok(None);
+        };
+        if let Some(icc) = raster.icc() {
+            let space = Profile::new_icc(icc)
+                .ok()
+                .map(|profile| profile.color_space());
+            if space != Some(ColorSpaceSignature::RgbData) {
+                return Ok(None);
+            }
+        }
+
+        let (width, height) = (raster.width(), raster.height());
+        let (mut target_width, mut target_height) = (width, height);
+        if let Some(max_dpi) = self.max_dpi {
+            let scale = (shown.x.to_inches() * max_dpi / width as f64)
+                .min(shown.y.to_inches() * max_dpi / height as f64);
+            if scale < 1.0 {
+                target_width = ((width as f64 * scale).ceil() as u32).max(1);
+                target_height = ((height as f64 * scale).ceil() as u32).max(1);
+            }
+        }
+
+        let resized = (target_width, target_height) != (width, height);
+        let jpeg = raster.format() == RasterFormat::Exchange(ExchangeFormat::Jpg);
+        let reencode = jpeg && self.jpeg_quality.is_some();
+        if !resized && !reencode {
+            return Ok(None);
+        }
+
+        let pixels: Cow<DynamicImage> = if resized {
+            Cow::Owned(raster.dynamic().resize_exact(
+                target_width,
+                target_height,
+                FilterType::Lanczos3,
+            ))
+        } else {
+            Cow::Borrowed(raster.dynamic().as_ref())
+        };
+
+        let mut data = Vec::new();
+        let encoded = if jpeg {
+            let rgb = pixels.to_rgb8();
+            JpegEncoder::new_with_quality(&mut data, self.jpeg_quality.unwrap_or(85) as u8)
+                .write_image(
+                    rgb.as_raw(),
+                    rgb.width(),
+                    rgb.height(),
+                    ExtendedColorType::Rgb8,
+                )
+        } else if pixels.color().has_alpha() {
+            let rgba = pixels.to_rgba8();
+            PngEncoder::new(&mut data).write_image(
+                rgba.as_raw(),
+                rgba.width(),
+                rgba.height(),
+                ExtendedColorType::Rgba8,
+            )
+        } else {
+            let rgb = pixels.to_rgb8();
+            PngEncoder::new(&mut data).write_image(
+                rgb.as_raw(),
+                rgb.width(),
+                rgb.height(),
+                ExtendedColorType::Rgb8,
+            )
+        };
+        encoded.map_err(|e| format!("Failed to encode image: {}", e))?;
+
+        if !resized && data.len() >= raster.data().len() {
+            return Ok(None);
+        }
+
+        let format = if jpeg {
+            ExchangeFormat::Jpg
+        } else {
+            ExchangeFormat::Png
+        };
+        let icc = match raster.icc() {
+            Some(icc) => Smart::Custom(icc.clone()),
+            None => Smart::Auto,
+        };
+        let optimized = RasterImage::new(Bytes::new(data), format, icc)
+            .map_err(|e| format!("Failed to decode optimized image: {}", e))?;
+        Ok(Some(Image::new(
+            optimized,
+            image.alt().map(Into::into),
+            image.scaling(),
+        )))
+    }
+}
+
+/// Collect the distinct images of a frame, each with the largest size it is
+/// shown at on the page, keyed by their hash.
+fn collect_images(frame: &Frame, ts: Transform, images: &mut HashMap<u128, (Image, Size)>) {
+    for (point, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => {
+                let ts = ts
+                    .pre_concat(Transform::translate(point.x, point.y))
+                    .pre_concat(group.transform);
+                collect_images(&group.frame, ts, images);
+            }
+            FrameItem::Image(image, size, _) => {
+                let shown = Size::new(
+                    size.x * ts.sx.get().hypot(ts.ky.get()),
+                    size.y * ts.kx.get().hypot(ts.sy.get()),
+                );
+                let (_, largest) = images
+                    .entry(typst::utils::hash128(image))
+                    .or_insert_with(|| (image.clone(), Size::zero()));
+                *largest = Size::new(largest.x.max(shown.x), largest.y.max(shown.y));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A copy of the frame with images replaced by the ones with the same hash.
+fn replace_images(frame: &Frame, replacements: &HashMap<u128, Image>) -> Frame {
+    let mut replaced = Frame::new(frame.size(), frame.kind());
+    if frame.has_baseline() {
+        replaced.set_baseline(frame.baseline());
+    }
+
+    for (point, item) in frame.items() {
+        let item = match item {
+            FrameItem::Group(group) => {
+                let mut group = group.clone();
+                group.frame = replace_images(&group.frame, replacements);
+                FrameItem::Group(group)
+            }
+            FrameItem::Image(image, size, span) => {
+                match replacements.get(&typst::utils::hash128(image)) {
+                    Some(optimized) => FrameItem::Image(optimized.clone(), *size, *span),
+                    None => item.clone(),
+                }
+            }
+            _ => item.clone(),
+        };
+        replaced.push(*point, item);
+    }
+    replaced
+}
+
+/// Recompress the PDF's streams with Flate at the given level, or store them
+/// uncompressed at level 0. Streams with other filters, such as JPEG images,
+/// are kept as they are, and so is the XMP metadata, which PDF/A readers
+/// expect to find uncompressed.
+fn compress_pdf(pdf: &[u8], level: u32) -> Result<Vec<u8>, String> {
+    let read_error = |e: lopdf::Error| format!("Failed to read PDF: {}", e);
+    let mut document = lopdf::Document::load_mem(pdf).map_err(read_error)?;
+
+    for object in document.objects.values_mut() {
+        let Object::Stream(stream) = object else {
+            continue;
+        };
+        let is_metadata = stream
+            .dict
+            .get(b"Type")
+            .and_then(Object::as_name)
+            .is_ok_and(|name| name == b"Metadata");
+        let has_parms = stream.dict.has(b"DecodeParms");
+        if is_metadata || (level == 0 && has_parms) {
+            continue;
+        }
+
+        let flate = match stream.dict.get(b"Filter") {
+            Err(_) => false,
+            Ok(Object::Name(name)) if name == b"FlateDecode" => true,
+            Ok(Object::Array(filters)) if matches!(&filters[..], [Object::Name(name)] if name == b"FlateDecode") => {
+                true
+            }
+            Ok(_) => continue,
+        };
+        let plain = if flate {
+            let mut plain = Vec::new();
+            if ZlibDecoder::new(&stream.content[..])
+                .read_to_end(&mut plain)
+                .is_err()
+            {
+                continue;
+            }
+            plain
+        } else {
+            stream.content.clone()
+        };
+
+        if level == 0 {
+            stream.dict.remove(b"Filter");
+            stream.set_content(plain);
+            continue;
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+        let compressed = encoder
+            .write_all(&plain)
+            .and_then(|_| encoder.finish())
+            .map_err(|e| format!("Failed to compress PDF stream: {}", e))?;
+        stream.dict.set("Filter", "FlateDecode");
+        stream.set_content(compressed);
+    }
+
+    let mut out = Vec::new();
+    document
+        .save_to(&mut out)
+        .map_err(|e| format!("Failed to write PDF: {}", e))?;
+    Ok(out)
+}
+
+/// Set the TrimBox and BleedBox of every page, inset from its MediaBox by
+/// the print margins.
+fn set_page_boxes(pdf: &[u8], print: &PdfPrintNif) -> Result<Vec<u8>, String> {
+    let read_error = |e: lopdf::Error| format!("Failed to read PDF: {}", e);
+    let mut document = lopdf::Document::load_mem(pdf).map_err(read_error)?;
+
+    let page_ids: Vec<_> = document.get_pages().into_values().collect();
+    for id in page_ids {
+        let page = document.get_dictionary_mut(id).map_err(read_error)?;
+        let media_box = page
+            .get(b"MediaBox")
+            .and_then(Object::as_array)
+            .map_err(read_error)?
+            .iter()
+            .map(Object::as_float)
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(read_error)?;
+        let [x0, y0, x1, y1] = media_box[..] else {
+            return Err("A page has an invalid MediaBox".to_string());
+        };
+
+        let inset = |by: f64| -> Object {
+            let by = by as f32;
+            vec![
+                (x0 + by).into(),
+                (y0 + by).into(),
+                (x1 - by).into(),
+                (y1 - by).into(),
+            ]
+            .into()
+        };
+        page.set("BleedBox", inset(print.slug()));
+        page.set("TrimBox", inset(print.margin()));
+    }
+
+    let mut out = Vec::new();
+    document
+        .save_to(&mut out)
+        .map_err(|e| format!("Failed to write PDF: {}", e))?;
+    Ok(out)
+}
+
+/// Whether `name` can be used as an XML element name.
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Add the custom XMP properties, creating an XMP packet if the PDF has none.
+fn rewrite_pdf_metadata(pdf: &[u8], metadata: &PdfMetadataNif) -> Result<Vec<u8>, String> {
+    let read_error = |e: lopdf::Error| format!("Failed to read PDF: {}", e);
+    let mut document = lopdf::Document::load_mem(pdf).map_err(read_error)?;
+    let root_id = document
+        .trailer
+        .get(b"Root")
+        .and_then(Object::as_reference)
+        .map_err(read_error)?;
+
+    let description = metadata.xmp_description();
+    let existing = document
+        .get_dictionary(root_id)
+        .and_then(|catalog| catalog.get(b"Metadata"))
+        .and_then(Object::as_reference)
+        .ok();
+
+    match existing {
+        Some(id) => {
+            let stream = document
+                .get_object_mut(id)
+                .and_then(Object::as_stream_mut)
+                .map_err(read_error)?;
+            let xml = stream
+                .decompressed_content()
+                .unwrap_or_else(|_| stream.content.clone());
+            let xml = String::from_utf8(xml)
+                .map_err(|_| "The XMP metadata is not valid UTF-8".to_string())?;
+            let end = xml
+                .rfind("</rdf:RDF>")
+                .ok_or("The XMP metadata has no RDF element")?;
+            let xml = format!("{}{}{}", &xml[..end], description, &xml[end..]);
+
+            // PDF/A requires the metadata stream to stay uncompressed.
+            stream.dict.remove(b"Filter");
+            stream.dict.remove(b"DecodeParms");
+            stream.set_content(xml.into_bytes());
+        }
+        None => {
+            let xml = format!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\
+                     <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+                     <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+                     {description}</rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>"
+            );
+            let stream = lopdf::Stream::new(
+                dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+                xml.into_bytes(),
+            );
+            let id = document.add_object(stream);
+            document
+                .get_dictionary_mut(root_id)
+                .map_err(read_error)?
+                .set("Metadata", id);
+        }
+    }
+
+    let mut out = Vec::new();
+    document
+        .save_to(&mut out)
+        .map_err(|e| format!("Failed to write PDF: {}", e))?;
+    Ok(out)
+}
+
+/// Where a signature widget goes: the 1-indexed page of the exported PDF and
+/// the annotation rectangle in PDF coordinates. An empty rectangle makes the
+/// signature invisible.
+struct SignaturePlacement {
+    page: u32,
+    rect: [f32; 4],
+}
+
+impl PdfSignatureNif {
+    fn validate(&self) -> Result<(), String> {
+        match (&self.pkcs12, &self.key, &self.certificates) {
+            (Some(_), None, None) | (None, Some(_), Some(_)) => {}
+            _ => {
+                return Err(
+                    "A signature needs either a pkcs12 bundle or both a PEM key and certificates"
+                        .to_string(),
+                )
+            }
+        }
+        if self.label.is_some() && (self.width <= 0.0 || self.height <= 0.0) {
+            return Err("The signature width and height must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Load the RSA signing key and the certificate chain, signer first. The
+    /// key must belong to the signer's certificate, or the signature could
+    /// never be verified.
+    fn credentials(&self) -> Result<(RsaPrivateKey, Vec<Certificate>), String> {
+        let (key, certificates) = self.load_credentials()?;
+        let signer = certificates
+            .first()
+            .ok_or("The certificate chain contains no certificates")?;
+        let spki = signer
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .map_err(|e| format!("Invalid signer certificate: {}", e))?;
+        let certified = RsaPublicKey::from_public_key_der(&spki).map_err(|e| {
+            format!(
+                "Unsupported signer certificate (only RSA is supported): {}",
+                e
+            )
+        })?;
+        if certified != key.to_public_key() {
+            return Err(
+                "The signing key does not match the public key of the first certificate"
+                    .to_string(),
+            );
+        }
+        Ok((key, certificates))
+    }
+
+    fn load_credentials(&self) -> Result<(RsaPrivateKey, Vec<Certificate>), String> {
+        if let Some(ref bundle) = self.pkcs12 {
+            let keystore = KeyStore::from_pkcs12(&bundle.0, &self.password)
+                .map_err(|e| format!("Failed to read PKCS#12 bundle: {}", e))?;
+            let (_alias, chain) = keystore
+                .private_key_chain()
+                .ok_or("The PKCS#12 bundle contains no private key")?;
+            let key = RsaPrivateKey::from_pkcs8_der(chain.key())
+                .map_err(|e| format!("Unsupported signing key (only RSA is supported): {}", e))?;
+            let certificates = chain
+                .chain()
+                .iter()
+                .map(|cert| Certificate::from_der(cert.as_der()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Invalid certificate in PKCS#12 bundle: {}", e))?;
+            return Ok((key, certificates));
+        }
+
+        let pem = self.key.as_deref().unwrap_or_default();
+        let key = RsaPrivateKey::from_pkcs8_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+            .map_err(|e| format!("Unsupported signing key (only RSA is supported): {}", e))?;
+        let certificates = Certificate::load_pem_chain(
+            self.certificates.as_deref().unwrap_or_default().as_bytes(),
+        )
+        .map_err(|e| format!("Invalid PEM certificates: {}", e))?;
+        Ok((key, certificates))
+    }
+
+    /// Place the signature at the top-left corner of the labelled element, or
+    /// invisibly on the first exported page when no label is given.
+    fn placement(
+        &self,
+        document: &PagedDocument,
+        page_ranges: Option<&PageRanges>,
+    ) -> Result<SignaturePlacement, String> {
+        let included = |page: NonZeroUsize| page_ranges.is_none_or(|r| r.includes_page(page));
+
+        let Some(ref name) = self.label else {
+            return Ok(SignaturePlacement {
+                page: 1,
+                rect: [0.0; 4],
+            });
+        };
+
+        let label = Label::new(PicoStr::intern(name)).ok_or("Label names cannot be empty")?;
+        let introspector = &document.introspector;
+        let location = introspector
+            .query(&Selector::Label(label))
+            .iter()
+            .find_map(|content| content.location())
+            .ok_or_else(|| format!("Label <{}> not found in the document", name))?;
+        let position = introspector.position(location);
+
+        if !included(position.page) {
+            return Err(format!(
+                "Label <{}> is on page {}, which is not exported",
+                name, position.page
+            ));
+        }
+        let page = (1..=position.page.get())
+            .filter_map(NonZeroUsize::new)
+            .filter(|&p| included(p))
+            .count();
+
+        let height = document.pages[position.page.get() - 1]
+            .frame
+            .height()
+            .to_pt();
+        let x = position.point.x.to_pt();
+        let top = height - position.point.y.to_pt();
+        Ok(SignaturePlacement {
+            page: page as u32,
+            rect: [
+                x as f32,
+                (top - self.height) as f32,
+                (x + self.width) as f32,
+                top as f32,
+            ],
+        })
+    }
+}
+
+/// Escape a string for a PDF content stream literal shown in a
+/// `WinAnsiEncoding` font. Characters outside ASCII are written as octal
+/// escapes of their WinAnsi code, and those the encoding lacks become `?`.
+fn pdf_literal(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(ch);
+            }
+            ch if ch.is_ascii() && !ch.is_ascii_control() => out.push(ch),
+            ch => match win_ansi_code(ch) {
+                Some(code) => out.push_str(&format!("\\{:03o}", code)),
+                None => out.push('?'),
+            },
+        }
+    }
+    out
+}
+
+/// The `WinAnsiEncoding` code of a character outside ASCII. The encoding
+/// matches Latin-1 from 0xA0 and puts typographic characters in 0x80-0x9F.
+fn win_ansi_code(ch: char) -> Option<u8> {
+    const HIGH: [(char, u8); 27] = [
+        ('€', 0x80),
+        ('‚', 0x82),
+        ('ƒ', 0x83),
+        ('„', 0x84),
+        ('…', 0x85),
+        ('†', 0x86),
+        ('‡', 0x87),
+        ('ˆ', 0x88),
+        ('‰', 0x89),
+        ('Š', 0x8A),
+        ('‹', 0x8B),
+        ('Œ', 0x8C),
+        ('Ž', 0x8E),
+        ('‘', 0x91),
+        ('’', 0x92),
+        ('“', 0x93),
+        ('”', 0x94),
+        ('•', 0x95),
+        ('–', 0x96),
+        ('—', 0x97),
+        ('˜', 0x98),
+        ('™', 0x99),
+        ('š', 0x9A),
+        ('›', 0x9B),
+        ('œ', 0x9C),
+        ('ž', 0x9E),
+        ('Ÿ', 0x9F),
+    ];
+    match ch as u32 {
+        code @ 0xA0..=0xFF => Some(code as u8),
+        _ => HIGH
+            .iter()
+            .find(|(high, _)| *high == ch)
+            .map(|(_, code)| *code),
+    }
+}
+
+/// The common name of the certificate subject, if it has one.
+fn common_name(certificate: &Certificate) -> Option<String> {
+    certificate
+        .tbs_certificate
+        .subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .find(|attribute| attribute.oid == COMMON_NAME)
+        .and_then(|attribute| directory_string(&attribute.value))
+}
+
+/// The text of an attribute value in one of the X.520 directory string
+/// types certificates use.
+fn directory_string(value: &Any) -> Option<String> {
+    if let Ok(text) = value.decode_as::<Utf8StringRef>() {
+        return Some(text.as_str().to_string());
+    }
+    if let Ok(text) = value.decode_as::<PrintableStringRef>() {
+        return Some(text.as_str().to_string());
+    }
+    if let Ok(text) = value.decode_as::<Ia5StringRef>() {
+        return Some(text.as_str().to_string());
+    }
+    if let Ok(text) = value.decode_as::<TeletexStringRef>() {
+        return Some(text.as_str().to_string());
+    }
+    None
+}
+
+/// The ESS signing-certificate-v2 attribute PAdES requires, binding the
+/// signer certificate to the signature by its SHA-256 hash.
+fn signing_certificate_attribute(certificate: &Certificate) -> Result<Attribute, String> {
+    let der = certificate
+        .to_der()
+        .map_err(|e| format!("Invalid signer certificate: {}", e))?;
+    let hash = Sha256::digest(&der);
+
+    // SigningCertificateV2 ::= SEQUENCE { certs SEQUENCE OF ESSCertIDv2 }
+    // ESSCertIDv2 ::= SEQUENCE { certHash OCTET STRING } (SHA-256 is the default)
+    let mut value = vec![0x30, 0x26, 0x30, 0x24, 0x30, 0x22, 0x04, 0x20];
+    value.extend_from_slice(&hash);
+
+    let value = Any::from_der(&value).map_err(|e| e.to_string())?;
+    Ok(Attribute {
+        oid: ID_AA_SIGNING_CERTIFICATE_V_2,
+        values: SetOfVec::try_from(vec![value]).map_err(|e| e.to_string())?,
+    })
+}
+
+/// Build a detached CMS SignedData over the given SHA-256 digest.
+fn cms_signature(
+    key: RsaPrivateKey,
+    certificates: &[Certificate],
+    digest: &[u8],
+) -> Result<Vec<u8>, String> {
+    let signer_certificate = certificates
+        .first()
+        .ok_or("The certificate chain is empty")?;
+    let signer = RsaSigningKey::<Sha256>::new(key);
+    let digest_algorithm = AlgorithmIdentifierOwned {
+        oid: ID_SHA_256,
+        parameters: None,
+    };
+    let content = EncapsulatedContentInfo {
+        econtent_type: ID_DATA,
+        econtent: None,
+    };
+    let sid = SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+        issuer: signer_certificate.tbs_certificate.issuer.clone(),
+        serial_number: signer_certificate.tbs_certificate.serial_number.clone(),
+    });
+
+    let error = |e: cms::builder::Error| format!("Failed to sign PDF: {}", e);
+    let mut signer_info = SignerInfoBuilder::new(
+        &signer,
+        sid,
+        digest_algorithm.clone(),
+        &content,
+        Some(digest),
+    )
+    .map_err(error)?;
+    signer_info
+        .add_signed_attribute(signing_certificate_attribute(signer_certificate)?)
+        .map_err(error)?;
+
+    let mut signed_data = SignedDataBuilder::new(&content);
+    signed_data
+        .add_digest_algorithm(digest_algorithm)
+        .map_err(error)?;
+    for certificate in certificates {
+        signed_data
+            .add_certificate(CertificateChoices::Certificate(certificate.clone()))
+            .map_err(error)?;
+    }
+    signed_data
+        .add_signer_info::<RsaSigningKey<Sha256>, RsaSignature>(signer_info)
+        .map_err(error)?
+        .build()
+        .map_err(error)?
+        .to_der()
+        .map_err(|e| format!("Failed to sign PDF: {}", e))
+}
+
+/// The appearance stream of a visible signature: a frame with the signer,
+/// date and reason. PDF/A forbids unembedded fonts, so `plain` draws only the
+/// frame.
+fn signature_appearance(lines: &[String], width: f32, height: f32, plain: bool) -> lopdf::Stream {
+    let mut content = format!(
+        "q 0.5 w 0 0 0 RG 0.25 0.25 {} {} re S Q\n",
+        width - 0.5,
+        height - 0.5
+    );
+    if !plain {
+        content.push_str(&format!("BT /F1 8 Tf 10 TL 4 {} Td\n", height - 11.0));
+        for line in lines {
+            content.push_str(&format!("({}) Tj T*\n", pdf_literal(line)));
+        }
+        content.push_str("ET\n");
+    }
+
+    let mut dict = dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Form",
+        "BBox" => vec![0f32.into(), 0f32.into(), width.into(), height.into()],
+    };
+    if !plain {
+        dict.set(
+            "Resources",
+            dictionary! {
+                "Font" => dictionary! {
+                    "F1" => dictionary! {
+                        "Type" => "Font",
+                        "Subtype" => "Type1",
+                        "BaseFont" => "Helvetica",
+                        "Encoding" => "WinAnsiEncoding",
+                    },
+                },
+            },
+        );
+    }
+    lopdf::Stream::new(dict, content.into_bytes())
+}
+
+/// Sign a PDF with a PAdES baseline signature (`ETSI.CAdES.detached`).
+///
+/// The signature is appended as an incremental update, so the signed bytes
+/// are the original file plus a signature field. Its `/Contents` hold a
+/// detached CMS signature over everything except themselves.
+fn sign_pdf(
+    pdf: &[u8],
+    signature: &PdfSignatureNif,
+    placement: SignaturePlacement,
+    plain_appearance: bool,
+) -> Result<Vec<u8>, String> {
+    let (key, certificates) = signature.credentials()?;
+    let signer_name = signature
+        .name
+        .clone()
+        .or_else(|| certificates.first().and_then(common_name))
+        .unwrap_or_else(|| "Unknown signer".to_string());
+
+    let mut incremental = lopdf::IncrementalDocument::load_from(pdf)
+        .map_err(|e| format!("Failed to read PDF: {}", e))?;
+    let previous = incremental.get_prev_documents();
+    let read_error = |e: lopdf::Error| format!("Failed to read PDF: {}", e);
+
+    let root_id = previous
+        .trailer
+        .get(b"Root")
+        .and_then(Object::as_reference)
+        .map_err(read_error)?;
+    let mut catalog = previous
+        .get_dictionary(root_id)
+        .map_err(read_error)?
+        .clone();
+    let page_id = *previous
+        .get_pages()
+        .get(&placement.page)
+        .ok_or("The signature page is missing from the PDF")?;
+    let mut page = previous
+        .get_dictionary(page_id)
+        .map_err(read_error)?
+        .clone();
+    let mut annotations = match page.get(b"Annots") {
+        Ok(Object::Reference(id)) => previous
+            .get_object(*id)
+            .and_then(Object::as_array)
+            .map_err(read_error)?
+            .clone(),
+        Ok(Object::Array(annotations)) => annotations.clone(),
+        _ => vec![],
+    };
+
+    let now = Utc::now();
+    let mut signature_dict = dictionary! {
+        "Type" => "Sig",
+        "Filter" => "Adobe.PPKLite",
+        "SubFilter" => "ETSI.CAdES.detached",
+        "ByteRange" => vec![
+            0i64.into(),
+            BYTE_RANGE_PLACEHOLDER.into(),
+            BYTE_RANGE_PLACEHOLDER.into(),
+            BYTE_RANGE_PLACEHOLDER.into(),
+        ],
+        "Contents" => Object::String(
+            vec![0; signature_capacity(&certificates)],
+            StringFormat::Hexadecimal,
+        ),
+        "M" => Object::string_literal(now.format("D:%Y%m%d%H%M%S+00'00'").to_string()),
+        "Name" => Object::string_literal(signer_name.clone()),
+    };
+    for (name, value) in [
+        ("Reason", &signature.reason),
+        ("Location", &signature.location),
+        ("ContactInfo", &signature.contact),
+    ] {
+        if let Some(value) = value {
+            signature_dict.set(name, Object::string_literal(value.clone()));
+        }
+    }
+
+    let document = &mut incremental.new_document;
+    let signature_id = document.add_object(signature_dict);
+
+    let [x0, y0, x1, y1] = placement.rect;
+    let mut widget = dictionary! {
+        "Type" => "Annot",
+        "Subtype" => "Widget",
+        "FT" => "Sig",
+        "T" => Object::string_literal("Signature1"),
+        "V" => signature_id,
+        "P" => page_id,
+        "F" => 132i64,
+        "Rect" => vec![x0.into(), y0.into(), x1.into(), y1.into()],
+    };
+    if x1 > x0 && y1 > y0 {
+        let mut lines = vec![
+            format!("Digitally signed by {}", signer_name),
+            format!("Date: {}", now.format("%Y-%m-%d %H:%M:%S UTC")),
+        ];
+        if let Some(ref reason) = signature.reason {
+            lines.push(format!("Reason: {}", reason));
+        }
+        let appearance = signature_appearance(&lines, x1 - x0, y1 - y0, plain_appearance);
+        let appearance_id = document.add_object(appearance);
+        widget.set("AP", dictionary! { "N" => appearance_id });
+    }
+    let widget_id = document.add_object(widget);
+
+    annotations.push(widget_id.into());
+    page.set("Annots", annotations);
+    document.objects.insert(page_id, Object::Dictionary(page));
+
+    catalog.set(
+        "AcroForm",
+        dictionary! {
+            "Fields" => vec![widget_id.into()],
+            "SigFlags" => 3i64,
+        },
+    );
+    document
+        .objects
+        .insert(root_id, Object::Dictionary(catalog));
+
+    let mut out = Vec::new();
+    incremental
+        .save_to(&mut out)
+        .map_err(|e| format!("Failed to write PDF: {}", e))?;
+
+    fill_signature(out, pdf.len(), |digest| {
+        cms_signature(key, &certificates, digest)
+    })
+}
+
+const BYTE_RANGE_PLACEHOLDER: i64 = 9_999_999_999;
+
+/// Bytes reserved for the CMS signature: the certificates plus room for the
+/// signer info and attributes.
+fn signature_capacity(certificates: &[Certificate]) -> usize {
+    let chain: usize = certificates
+        .iter()
+        .filter_map(|cert| cert.to_der().ok())
+        .map(|der| der.len())
+        .sum();
+    chain + 4096
+}
+
+/// Fill in the `/ByteRange` and `/Contents` placeholders of a signature
+/// appended after `offset`, signing the digest of everything but the
+/// contents.
+fn fill_signature(
+    mut pdf: Vec<u8>,
+    offset: usize,
+    sign: impl FnOnce(&[u8]) -> Result<Vec<u8>, String>,
+) -> Result<Vec<u8>, String> {
+    let find = |haystack: &[u8], needle: &[u8], from: usize| {
+        haystack[from..]
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|i| i + from)
+    };
+    let missing = || "Failed to locate the signature placeholder".to_string();
+
+    let contents_start = find(&pdf, b"<0000000000000000", offset).ok_or_else(missing)?;
+    let contents_end = pdf[contents_start..]
+        .iter()
+        .position(|&b| b == b'>')
+        .map(|i| contents_start + i + 1)
+        .ok_or_else(missing)?;
+
+    let range_key = find(&pdf, b"/ByteRange", offset).ok_or_else(missing)?;
+    let range_start = find(&pdf, b"[", range_key).ok_or_else(missing)?;
+    let range_end = find(&pdf, b"]", range_start).ok_or_else(missing)? + 1;
+    let byte_range = format!(
+        "[0 {} {} {}]",
+        contents_start,
+        contents_end,
+        pdf.len() - contents_end
+    );
+    let slot = &mut pdf[range_start..range_end];
+    slot.fill(b' ');
+    slot[..byte_range.len()].copy_from_slice(byte_range.as_bytes());
+
+    let mut hasher = Sha256::new();
+    hasher.update(&pdf[..contents_start]);
+    hasher.update(&pdf[contents_end..]);
+    let cms = sign(&hasher.finalize())?;
+
+    let hex: String = cms.iter().map(|b| format!("{:02X}", b)).collect();
+    if hex.len() > contents_end - contents_start - 2 {
+        return Err("The signature does not fit in the space reserved for it".to_string());
+    }
+    pdf[contents_start + 1..contents_start + 1 + hex.len()].copy_from_slice(hex.as_bytes());
+    Ok(pdf)
+}
+
+/// Encrypt a PDF with AES-256 (security handler revision 6) under the given
+/// passwords and permissions.
+fn encrypt_pdf(pdf: &[u8], encryption: &PdfEncryptionNif) -> Result<Vec<u8>, String> {
+    let mut document =
+        lopdf::Document::load_mem(pdf).map_err(|e| format!("Failed to read PDF: {}", e))?;
+
+    let mut file_key = [0u8; 32];
+    getrandom::getrandom(&mut file_key)
+        .map_err(|e| format!("Failed to generate an encryption key: {}", e))?;
+
+    let crypt_filter: Arc<dyn CryptFilter> = Arc::new(Aes256CryptFilter);
+    let version = EncryptionVersion::V5 {
+        encrypt_metadata: true,
+        crypt_filters: BTreeMap::from([(b"StdCF".to_vec(), crypt_filter)]),
+        file_encryption_key: &file_key,
+        stream_filter: b"StdCF".to_vec(),
+        string_filter: b"StdCF".to_vec(),
+        owner_password: &encryption.owner_password,
+        user_password: encryption.user_password(),
+        permissions: encryption.permissions(),
+    };
+    let state =
+        EncryptionState::try_from(version).map_err(|e| format!("Failed to encrypt PDF: {}", e))?;
+    document
+        .encrypt(&state)
+        .map_err(|e| format!("Failed to encrypt PDF: {}", e))?;
+
+    let mut out = Vec::new();
+    document
+        .save_to(&mut out)
+        .map_err(|e| format!("Failed to write PDF: {}", e))?;
+    Ok(out)
+}
+
+/// Rewrite a PDF as a linearized ("fast web view") file (ISO 32000-1,
+/// Annex F): the first page and everything it uses come first, behind a
+/// linearization dictionary, its own cross-reference section and the page
+/// offset and shared object hint tables, so a viewer can show page one
+/// before the rest of the file has arrived.
+fn linearize_pdf(pdf: &[u8]) -> Result<Vec<u8>, String> {
+    let read_error = |e: lopdf::Error| format!("Failed to read PDF: {}", e);
+    let document = lopdf::Document::load_mem(pdf).map_err(read_error)?;
+    let root = document
+        .trailer
+        .get(b"Root")
+        .and_then(Object::as_reference)
+        .map_err(read_error)?;
+    let pages: Vec<ObjectId> = document.get_pages().into_values().collect();
+    if pages.is_empty() {
+        return Err("Cannot linearize a PDF without pages".to_string());
+    }
+
+    let closures: Vec<Vec<ObjectId>> = pages
+        .iter()
+        .map(|&page| object_closure(&document, vec![page]))
+        .collect();
+    let mut users: HashMap<ObjectId, usize> = HashMap::new();
+    for id in closures.iter().flatten() {
+        *users.entry(*id).or_default() += 1;
+    }
+
+    // Objects the document uses outside its pages. What the outline and the
+    // entries needed to open the file use goes after the pages; anything
+    // else is not private to a page.
+    let catalog = document.get_dictionary(root).map_err(read_error)?;
+    let mut opening = HashSet::new();
+    let mut document_level = HashSet::new();
+    let entries = catalog
+        .iter()
+        .map(|(key, value)| {
+            let opens = matches!(
+                key.as_slice(),
+                b"Outlines"
+                    | b"ViewerPreferences"
+                    | b"PageMode"
+                    | b"Threads"
+                    | b"OpenAction"
+                    | b"AcroForm"
+            );
+            (opens, value)
+        })
+        .chain(
+            document
+                .trailer
+                .iter()
+                .filter(|(key, _)| key.as_slice() != b"Root")
+                .map(|(key, value)| (key.as_slice() == b"Encrypt", value)),
+        );
+    for (opens, value) in entries {
+        let ids = entry_closure(&document, value);
+        if opens {
+            opening.extend(ids);
+        } else {
+            document_level.extend(ids);
+        }
+    }
+
+    // The first page and everything it uses.
+    let first: Vec<ObjectId> = closures[0]
+        .iter()
+        .copied()
+        .filter(|id| *id != root && !opening.contains(id))
+        .collect();
+    let first_set: HashSet<ObjectId> = first.iter().copied().collect();
+    let later = |id: &ObjectId| *id != root && !first_set.contains(id) && !opening.contains(id);
+    // Every later page with the objects only it uses.
+    let own: Vec<Vec<ObjectId>> = closures[1..]
+        .iter()
+        .map(|closure| {
+            closure
+                .iter()
+                .copied()
+                .filter(|id| later(id) && users[id] == 1 && !document_level.contains(id))
+                .collect()
+        })
+        .collect();
+    // Objects shared by later pages.
+    let mut shared = Vec::new();
+    let mut shared_set = HashSet::new();
+    for &id in closures[1..].iter().flatten() {
+        if later(&id) && users[&id] > 1 && shared_set.insert(id) {
+            shared.push(id);
+        }
+    }
+    // Everything else, minus the object and cross-reference streams the
+    // rewrite replaces.
+    let assigned: HashSet<ObjectId> = first
+        .iter()
+        .chain(own.iter().flatten())
+        .chain(&shared)
+        .copied()
+        .chain([root])
+        .collect();
+    let rest: Vec<ObjectId> = document
+        .objects
+        .iter()
+        .filter(|(id, object)| {
+            !assigned.contains(id)
+                && !pdf_type(object).is_some_and(|t| t == b"ObjStm" || t == b"XRef")
+        })
+        .map(|(id, _)| *id)
+        .collect();
+
+    // Objects after the first page are numbered from 1 in file order; the
+    // first-page part takes the numbers above them.
+    let mut numbers: HashMap<ObjectId, u32> = HashMap::new();
+    for id in own.iter().flatten().chain(&shared).chain(&rest) {
+        let number = numbers.len() as u32 + 1;
+        numbers.insert(*id, number);
+    }
+    let main_size = numbers.len() as u32 + 1;
+    let lin_number = main_size;
+    numbers.insert(root, lin_number + 1);
+    let hint_number = lin_number + 2;
+    for (i, id) in first.iter().enumerate() {
+        numbers.insert(*id, hint_number + 1 + i as u32);
+    }
+    let size = hint_number + 1 + first.len() as u32;
+
+    let blob = |id: &ObjectId| {
+        let mut out = format!("{} 0 obj\n", numbers[id]).into_bytes();
+        write_pdf_object(&mut out, &renumber_object(&document.objects[id], &numbers));
+        out.extend_from_slice(b"\nendobj\n");
+        out
+    };
+    let catalog = blob(&root);
+    let first_blobs: Vec<Vec<u8>> = first.iter().map(blob).collect();
+    let own_blobs: Vec<Vec<Vec<u8>>> = own
+        .iter()
+        .map(|ids| ids.iter().map(blob).collect())
+        .collect();
+    let shared_blobs: Vec<Vec<u8>> = shared.iter().map(blob).collect();
+    let rest_blobs: Vec<Vec<u8>> = rest.iter().map(blob).collect();
+
+    let mut trailer = lopdf::Dictionary::new();
+    trailer.set("Size", size as i64);
+    trailer.set("Root", Object::Reference((numbers[&root], 0)));
+    if let Ok(info) = document.trailer.get(b"Info") {
+        trailer.set("Info", renumber_object(info, &numbers));
+    }
+    let id = document.trailer.get(b"ID").ok().cloned();
+    if let Some(ref id) = id {
+        trailer.set("ID", id.clone());
+    }
+    // Values that are only known once the file is laid out are padded to a
+    // fixed width, so the parts before them do not move.
+    let first_trailer = |prev: usize| {
+        let mut out = b"trailer\n".to_vec();
+        write_pdf_object(&mut out, &Object::Dictionary(trailer.clone()));
+        out.truncate(out.len() - 2);
+        out.extend_from_slice(format!(" /Prev {:<10} >>\nstartxref\n0\n%%EOF\n", prev).as_bytes());
+        out
+    };
+    let lin_dict = |length: usize, hint: (usize, usize), end: usize, main: usize| {
+        format!(
+            "{} 0 obj\n<< /Linearized 1 /L {:<10} /H [ {:<10} {:<10} ] /O {} /E {:<10} /N {} \
+             /T {:<10} >>\nendobj\n",
+            lin_number,
+            length,
+            hint.0,
+            hint.1,
+            numbers[&pages[0]],
+            end,
+            pages.len(),
+            main
+        )
+        .into_bytes()
+    };
+    let first_xref = format!("xref\n{} {}\n", lin_number, size - lin_number);
+
+    let mut header = format!("%PDF-{}\n", document.version).into_bytes();
+    header.extend_from_slice(b"%\xE2\xE3\xCF\xD3\n");
+    let lin_offset = header.len();
+    let first_xref_offset = lin_offset + lin_dict(0, (0, 0), 0, 0).len();
+    let catalog_offset = first_xref_offset
+        + first_xref.len()
+        + 20 * (size - lin_number) as usize
+        + first_trailer(0).len();
+    let hint_offset = catalog_offset + catalog.len();
+
+    // The hint tables record offsets as if the hint stream were absent.
+    let mut offset = hint_offset;
+    let first_offsets = place_blobs(&mut offset, &first_blobs);
+    let first_end = offset;
+    let own_offsets: Vec<Vec<usize>> = own_blobs
+        .iter()
+        .map(|blobs| place_blobs(&mut offset, blobs))
+        .collect();
+    let shared_offsets = place_blobs(&mut offset, &shared_blobs);
+    let rest_offsets = place_blobs(&mut offset, &rest_blobs);
+    let main_xref_offset = offset;
+
+    let hint = hint_stream(
+        &closures,
+        &first,
+        &shared,
+        &numbers,
+        &first_blobs,
+        &own_blobs,
+        &shared_blobs,
+        first_offsets[0],
+        shared_offsets.first().copied(),
+    );
+    let mut hint_blob = format!("{} 0 obj\n", hint_number).into_bytes();
+    write_pdf_object(&mut hint_blob, &Object::Stream(hint));
+    hint_blob.extend_from_slice(b"\nendobj\n");
+    let shift = hint_blob.len();
+
+    let mut main_xref = format!("xref\n0 {}\n0000000000 65535 f \n", main_size).into_bytes();
+    for offset in own_offsets
+        .iter()
+        .flatten()
+        .chain(&shared_offsets)
+        .chain(&rest_offsets)
+    {
+        main_xref.extend_from_slice(format!("{:010} 00000 n \n", offset + shift).as_bytes());
+    }
+    let mut main_trailer = lopdf::Dictionary::new();
+    main_trailer.set("Size", main_size as i64);
+    if let Some(id) = id {
+        main_trailer.set("ID", id);
+    }
+    main_xref.extend_from_slice(b"trailer\n");
+    write_pdf_object(&mut main_xref, &Object::Dictionary(main_trailer));
+    main_xref.extend_from_slice(format!("\nstartxref\n{}\n%%EOF\n", first_xref_offset).as_bytes());
+
+    let main_xref_offset = main_xref_offset + shift;
+    let length = main_xref_offset + main_xref.len();
+    let first_entry = main_xref_offset + format!("xref\n0 {}", main_size).len();
+
+    let mut out = Vec::with_capacity(length);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&lin_dict(
+        length,
+        (hint_offset, shift),
+        first_end + shift,
+        first_entry,
+    ));
+    out.extend_from_slice(first_xref.as_bytes());
+    let first_part = [lin_offset, catalog_offset, hint_offset]
+        .into_iter()
+        .chain(first_offsets.iter().map(|offset| offset + shift));
+    for offset in first_part {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(&first_trailer(main_xref_offset));
+    out.extend_from_slice(&catalog);
+    out.extend_from_slice(&hint_blob);
+    for blob in first_blobs
+        .iter()
+        .chain(own_blobs.iter().flatten())
+        .chain(&shared_blobs)
+        .chain(&rest_blobs)
+    {
+        out.extend_from_slice(blob);
+    }
+    out.extend_from_slice(&main_xref);
+    debug_assert_eq!(out.len(), length);
+    Ok(out)
+}
+
+/// Build the primary hint stream: the page offset hint table followed by
+/// the shared object hint table, one shared object per group.
+#[allow(clippy::too_many_arguments)]
+fn hint_stream(
+    closures: &[Vec<ObjectId>],
+    first: &[ObjectId],
+    shared: &[ObjectId],
+    numbers: &HashMap<ObjectId, u32>,
+    first_blobs: &[Vec<u8>],
+    own_blobs: &[Vec<Vec<u8>>],
+    shared_blobs: &[Vec<u8>],
+    first_page_offset: usize,
+    shared_offset: Option<usize>,
+) -> lopdf::Stream {
+    let size = |blobs: &[Vec<u8>]| blobs.iter().map(Vec::len).sum::<usize>() as u64;
+    let counts: Vec<u64> = std::iter::once(first_blobs.len() as u64)
+        .chain(own_blobs.iter().map(|blobs| blobs.len() as u64))
+        .collect();
+    let lengths: Vec<u64> = std::iter::once(size(first_blobs))
+        .chain(own_blobs.iter().map(|blobs| size(blobs)))
+        .collect();
+
+    // Later pages refer to the objects they share by their index in the
+    // shared object table: first-page objects, then the shared section.
+    let index: HashMap<ObjectId, u64> = first
+        .iter()
+        .chain(shared)
+        .enumerate()
+        .map(|(i, id)| (*id, i as u64))
+        .collect();
+    let references: Vec<Vec<u64>> = std::iter::once(Vec::new())
+        .chain(closures[1..].iter().map(|closure| {
+            closure
+                .iter()
+                .filter_map(|id| index.get(id).copied())
+                .collect()
+        }))
+        .collect();
+
+    let (min_count, count_bits) = hint_range(&counts);
+    let (min_length, length_bits) = hint_range(&lengths);
+    let reference_bits = bit_width(references.iter().map(Vec::len).max().unwrap_or(0) as u64);
+    let id_bits = bit_width(references.iter().flatten().copied().max().unwrap_or(0));
+
+    let mut bits = BitWriter::default();
+    bits.write(min_count, 32);
+    bits.write(first_page_offset as u64, 32);
+    bits.write(count_bits as u64, 16);
+    bits.write(min_length, 32);
+    bits.write(length_bits as u64, 16);
+    // Content stream offsets and lengths are given as the whole page, as
+    // viewers do not use them.
+    bits.write(0, 32);
+    bits.write(0, 16);
+    bits.write(min_length, 32);
+    bits.write(length_bits as u64, 16);
+    bits.write(reference_bits as u64, 16);
+    bits.write(id_bits as u64, 16);
+    bits.write(0, 16);
+    bits.write(4, 16);
+    for count in &counts {
+        bits.write(count - min_count, count_bits);
+    }
+    bits.flush();
+    for length in &lengths {
+        bits.write(length - min_length, length_bits);
+    }
+    bits.flush();
+    for page in &references {
+        bits.write(page.len() as u64, reference_bits);
+    }
+    bits.flush();
+    for &id in references.iter().flatten() {
+        bits.write(id, id_bits);
+    }
+    bits.flush();
+    for length in &lengths {
+        bits.write(length - min_length, length_bits);
+    }
+    bits.flush();
+
+    let shared_table = bits.bytes.len();
+    let groups: Vec<u64> = first_blobs
+        .iter()
+        .chain(shared_blobs)
+        .map(|blob| blob.len() as u64)
+        .collect();
+    let (min_group, group_bits) = hint_range(&groups);
+    bits.write(shared.first().map_or(0, |id| numbers[id]) as u64, 32);
+    bits.write(shared_offset.unwrap_or(0) as u64, 32);
+    bits.write(first.len() as u64, 32);
+    bits.write(groups.len() as u64, 32);
+    bits.write(0, 16);
+    bits.write(min_group, 32);
+    bits.write(group_bits as u64, 16);
+    for group in &groups {
+        bits.write(group - min_group, group_bits);
+    }
+    bits.flush();
+    for _ in &groups {
+        bits.write(0, 1);
+    }
+    bits.flush();
+
+    lopdf::Stream::new(dictionary! { "S" => shared_table as i64 }, bits.bytes)
+}
+
+/// The least value and the bits needed for the differences from it.
+fn hint_range(values: &[u64]) -> (u64, u32) {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    (min, bit_width(max - min))
+}
+
+fn bit_width(value: u64) -> u32 {
+    u64::BITS - value.leading_zeros()
+}
+
+/// Packs hint table fields, most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, width: u32) {
+        for i in (0..width).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            if let Some(byte) = self.bytes.last_mut() {
+                *byte |= (((value >> i) & 1) as u8) << (7 - self.used);
+            }
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    /// Pad to a byte boundary, where each item of a hint table starts.
+    fn flush(&mut self) {
+        self.used = 0;
+    }
+}
+
+/// Record where each blob starts when written from `offset` on.
+fn place_blobs(offset: &mut usize, blobs: &[Vec<u8>]) -> Vec<usize> {
+    blobs
+        .iter()
+        .map(|blob| {
+            let start = *offset;
+            *offset += blob.len();
+            start
+        })
+        .collect()
+}
+
+/// The objects reached from `start`, in the order they are reached, without
+/// entering any other page or climbing from a page into the page tree. This
+/// is the walk qpdf checks the hint tables against.
+fn object_closure(document: &lopdf::Document, start: Vec<ObjectId>) -> Vec<ObjectId> {
+    let mut seen: HashSet<ObjectId> = start.iter().copied().collect();
+    let mut order = start;
+    let mut next = 0;
+    while next < order.len() {
+        let mut references = Vec::new();
+        if let Some(object) = document.objects.get(&order[next]) {
+            collect_references(object, &mut references);
+        }
+        next += 1;
+        for id in references {
+            if reachable(document, id) && seen.insert(id) {
+                order.push(id);
+            }
+        }
+    }
+    order
+}
+
+/// The objects a catalog or trailer entry uses outside the pages.
+fn entry_closure(document: &lopdf::Document, value: &Object) -> Vec<ObjectId> {
+    let mut start = Vec::new();
+    collect_references(value, &mut start);
+    start.retain(|&id| reachable(document, id));
+    object_closure(document, start)
+}
+
+/// Whether a walk from elsewhere steps into the object: it must exist and
+/// not be a page.
+fn reachable(document: &lopdf::Document, id: ObjectId) -> bool {
+    document
+        .objects
+        .get(&id)
+        .is_some_and(|object| pdf_type(object).is_none_or(|t| t != b"Page"))
+}
+
+fn collect_references(object: &Object, references: &mut Vec<ObjectId>) {
+    match object {
+        Object::Reference(id) => references.push(*id),
+        Object::Array(items) => {
+            for item in items {
+                collect_references(item, references);
+            }
+        }
+        Object::Dictionary(dict) => {
+            let page = pdf_type(object).is_some_and(|t| t == b"Page");
+            for (key, value) in dict.iter() {
+                if !(page && key == b"Parent") {
+                    collect_references(value, references);
+                }
+            }
+        }
+        Object::Stream(stream) => {
+            collect_references(&Object::Dictionary(stream.dict.clone()), references)
+        }
+        _ => {}
+    }
+}
+
+fn pdf_type(object: &Object) -> Option<&[u8]> {
+    let dict = match object {
+        Object::Dictionary(dict) => dict,
+        Object::Stream(stream) => &stream.dict,
+        _ => return None,
+    };
+    dict.get(b"Type").and_then(Object::as_name).ok()
+}
+
+/// Point references at the new object numbers, dropping dangling ones.
+fn renumber_object(object: &Object, numbers: &HashMap<ObjectId, u32>) -> Object {
+    let dict = |dict: &lopdf::Dictionary| {
+        let mut out = lopdf::Dictionary::new();
+        for (key, value) in dict.iter() {
+            out.set(key.clone(), renumber_object(value, numbers));
+        }
+        out
+    };
+    match object {
+        Object::Reference(id) => numbers
+            .get(id)
+            .map_or(Object::Null, |&number| Object::Reference((number, 0))),
+        Object::Array(items) => Object::Array(
+            items
+                .iter()
+                .map(|item| renumber_object(item, numbers))
+                .collect(),
+        ),
+        Object::Dictionary(d) => Object::Dictionary(dict(d)),
+        Object::Stream(stream) => {
+            let mut stream = stream.clone();
+            stream.dict = dict(&stream.dict);
+            Object::Stream(stream)
+        }
+        _ => object.clone(),
+    }
+}
+
+/// Serialize a PDF object in the byte layout the linearized file needs.
+fn write_pdf_object(out: &mut Vec<u8>, object: &Object) {
+    match object {
+        Object::Null => out.extend_from_slice(b"null"),
+        Object::Boolean(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Object::Integer(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Object::Real(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Object::Name(name) => write_pdf_name(out, name),
+        Object::String(bytes, StringFormat::Literal) => {
+            out.push(b'(');
+            for &byte in bytes {
+                match byte {
+                    b'(' | b')' | b'\\' => out.extend_from_slice(&[b'\\', byte]),
+                    b'\r' => out.extend_from_slice(b"\\r"),
+                    _ => out.push(byte),
+                }
+            }
+            out.push(b')');
+        }
+        Object::String(bytes, StringFormat::Hexadecimal) => {
+            out.push(b'<');
+            for byte in bytes {
+                out.extend_from_slice(format!("{:02X}", byte).as_bytes());
+            }
+            out.push(b'>');
+        }
+        Object::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b' ');
+                }
+                write_pdf_object(out, item);
+            }
+            out.push(b']');
+        }
+        Object::Dictionary(dict) => {
+            out.extend_from_slice(b"<<");
+            for (key, value) in dict.iter() {
+                write_pdf_name(out, key);
+                out.push(b' ');
+                write_pdf_object(out, value);
+            }
+            out.extend_from_slice(b">>");
+        }
+        Object::Stream(stream) => {
+            let mut dict = stream.dict.clone();
+            dict.set("Length", stream.content.len() as i64);
+            write_pdf_object(out, &Object::Dictionary(dict));
+            out.extend_from_slice(b"\nstream\n");
+            out.extend_from_slice(&stream.content);
+            out.extend_from_slice(b"\nendstream");
+        }
+        Object::Reference((number, generation)) => {
+            out.extend_from_slice(format!("{} {} R", number, generation).as_bytes())
+        }
+    }
+}
+
+fn write_pdf_name(out: &mut Vec<u8>, name: &[u8]) {
+    out.push(b'/');
+    for &byte in name {
+        if byte.is_ascii_graphic() && !b"()<>[]{}/%#".contains(&byte) {
+            out.push(byte);
+        } else {
+            out.extend_from_slice(format!("#{:02X}", byte).as_bytes());
+        }
+    }
+}
+
+pub struct SystemWorld {
+    root: PathBuf,
+    main: FileId,
+    markup: String,
+    library: LazyHash<Library>,
+    book: LazyHash<FontBook>,
+    fonts: Vec<FontSlot>,
+    slots: Mutex<HashMap<FileId, FileSlot>>,
+    package_storage: PackageStorage,
+    now: Now,
+    virtual_files: HashMap<String, Vec<u8>>,
+    plugins: HashMap<String, Bytes>,
+    inputs: HashMap<String, String>,
+    styles: Styles,
+    default_lang: bool,
+    globals: HashMap<String, Value>,
+    global_sources: HashMap<String, Source>,
+    features: Features,
+}
+
+impl SystemWorld {
+    pub fn new(
+        root: PathBuf,
+        font_paths: Vec<PathBuf>,
+        ignore_system_fonts: bool,
+        features: Features,
+    ) -> Self {
+        let filtered_paths: Vec<PathBuf> = font_paths
+            .into_iter()
+            .filter(|p| p.exists() && p.is_dir())
+            .collect();
+
+        let include_system_fonts = !ignore_system_fonts;
+
+        let fonts = if filtered_paths.is_empty() {
+            Fonts::searcher()
+                .include_system_fonts(include_system_fonts)
+                .search()
+        } else {
+            Fonts::searcher()
+                .include_system_fonts(include_system_fonts)
+                .search_with(filtered_paths)
+        };
+
+        let user_agent = concat!("typst/", env!("CARGO_PKG_VERSION"));
+        Self {
+            root,
+            main: *MARKUP_ID,
+            markup: String::new(),
+            library: LazyHash::new(Library::builder().with_features(features.clone()).build()),
+            book: LazyHash::new(fonts.book),
+            fonts: fonts.fonts,
+            slots: Mutex::new(HashMap::new()),
+            package_storage: PackageStorage::new(None, None, Downloader::new(user_agent)),
+            now: Now::System(OnceLock::new()),
+            virtual_files: HashMap::new(),
+            plugins: HashMap::new(),
+            inputs: HashMap::new(),
+            styles: Styles::new(),
+            default_lang: false,
+            globals: HashMap::new(),
+            global_sources: HashMap::new(),
+            features,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for slot in self.slots.get_mut().values_mut() {
+            slot.reset();
+        }
+        if let Now::System(time_lock) = &mut self.now {
+            time_lock.take();
+        }
+    }
+
+    pub fn set_default_styles(&mut self, defaults: &DefaultStylesNif) -> Result<(), String> {
+        self.styles = defaults.to_styles(&self.book)?;
+        self.default_lang = defaults.lang.is_some();
+        self.rebuild_library();
+        Ok(())
+    }
+
+    fn rebuild_library(&mut self) {
+        let mut dict = Dict::new();
+        for (key, value) in &self.inputs {
+            dict.insert(
+                Str::from(key.as_str()),
+                Value::Str(Str::from(value.as_str())),
+            );
+        }
+        let mut library = Library::builder()
+            .with_inputs(dict)
+            .with_features(self.features.clone())
+            .build();
+        library.styles = self.styles.clone();
+        for (name, value) in &self.globals {
+            library
+                .global
+                .scope_mut()
+                .bind(name.into(), Binding::detached(value.clone()));
+        }
+        self.library = LazyHash::new(library);
+    }
+
+    /// Evaluate a Typst code expression against the current library, with
+    /// extra bindings from `scope`.
+    fn eval_code(&self, code: &str, scope: Scope) -> SourceResult<Value> {
+        let world: &dyn World = self;
+        let mut sink = Sink::new();
+        typst_eval::eval_string(
+            &ROUTINES,
+            world.track(),
+            sink.track_mut(),
+            code,
+            Span::detached(),
+            SyntaxMode::Code,
+            scope,
+        )
+    }
+
+    /// Evaluate Typst source as a module against the current library. The
+    /// source is kept under `name` so diagnostics in it, including those of
+    /// later compiles calling into the module, resolve to a line and column.
+    fn eval_module(&mut self, name: &str, text: String) -> SourceResult<Module> {
+        let id = FileId::new_fake(VirtualPath::new(format!("{}.typ", name)));
+        let source = Source::new(id, text);
+        self.global_sources.insert(name.to_string(), source.clone());
+        self.eval_source(&source)
+    }
+
+    /// The font families the document asks for: the house style's, then
+    /// those of every `text(font: ..)` rule in the evaluated markup.
+    fn document_font_families(&self) -> SourceResult<Vec<String>> {
+        let main = Source::new(self.main, self.markup.clone());
+        let content = self.eval_source(&main)?.content();
+
+        let mut families: Vec<String> = Vec::new();
+        let mut add = |list: &FontList| {
+            for family in &list.0 {
+                if !families.iter().any(|f| f == family.as_str()) {
+                    families.push(family.as_str().to_string());
+                }
+            }
+        };
+        add(StyleChain::new(&self.library.styles).get_ref(TextElem::font));
+        let _ = content.traverse(&mut |elem| -> ControlFlow<()> {
+            if let Some(styled) = elem.to_packed::<StyledElem>() {
+                if styled.styles.has(TextElem::font) {
+                    add(StyleChain::new(&styled.styles).get_ref(TextElem::font));
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        Ok(families)
+    }
+
+    fn eval_source(&self, source: &Source) -> SourceResult<Module> {
+        let world: &dyn World = self;
+        let traced = Traced::default();
+        let mut sink = Sink::new();
+        typst_eval::eval(
+            &ROUTINES,
+            world.track(),
+            traced.track(),
+            sink.track_mut(),
+            Route::default().track(),
+            source,
+        )
+    }
+}
+
+impl World for SystemWorld {
+    fn library(&self) -> &LazyHash<Library> {
+        &self.library
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        &self.book
+    }
+
+    fn main(&self) -> FileId {
+        self.main
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if id == *MARKUP_ID {
+            return Ok(Source::new(id, self.markup.clone()));
+        }
+
+        if let Some(source) = self.global_sources.values().find(|s| s.id() == id) {
+            return Ok(source.clone());
+        }
+
+        if let Some(path) = id.vpath().as_rootless_path().to_str() {
+            if let Some(content) = self.virtual_files.get(path) {
+                let text = decode_utf8(content)?;
+                return Ok(Source::new(id, text.into()));
+            }
+        }
+
+        self.slot(id, |slot| slot.source(&self.root, &self.package_storage))
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        if let Some(path) = id.vpath().as_rootless_path().to_str() {
+            if let Some(content) = self.virtual_files.get(path) {
+                return Ok(Bytes::new(content.clone()));
+            }
+            if let Some(wasm) = self.plugins.get(path) {
+                return Ok(wasm.clone());
+            }
+        }
+
+        self.slot(id, |slot| slot.file(&self.root, &self.package_storage))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.fonts.get(index)?.get()
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        let now = match &self.now {
+            Now::Fixed(time) => time,
+            Now::System(time) => time.get_or_init(Utc::now),
+        };
+
+        let with_offset = match offset {
+            None => now.with_timezone(&Local).fixed_offset(),
+            Some(hours) => {
+                let seconds = i32::try_from(hours).ok()?.checked_mul(3600)?;
+                now.with_timezone(&FixedOffset::east_opt(seconds)?)
+            }
+        };
+
+        Datetime::from_ymd(
+            with_offset.year(),
+            with_offset.month().try_into().ok()?,
+            with_offset.day().try_into().ok()?,
+        )
+    }
+}
+
+impl SystemWorld {
+    fn slot<F, T>(&self, id: FileId, f: F) -> T
+    where
+        F: FnOnce(&mut FileSlot) -> T,
+    {
+        let mut map = self.slots.lock();
+        f(map.entry(id).or_insert_with(|| FileSlot::new(id)))
+    }
+}
+
+struct FileSlot {
+    id: FileId,
+    source: SlotCell<Source>,
+    file: SlotCell<Bytes>,
+}
+
+impl FileSlot {
+    fn new(id: FileId) -> Self {
+        Self {
+            id,
+            file: SlotCell::new(),
+            source: SlotCell::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.source.reset();
+        self.file.reset();
+    }
+
+    fn source(
+        &mut self,
+        project_root: &Path,
+        package_storage: &PackageStorage,
+    ) -> FileResult<Source> {
+        self.source.get_or_init(
+            || read(self.id, project_root, package_storage),
+            |data, prev| {
+                let name = if prev.is_some() {
+                    "reparsing file"
+                } else {
+                    "parsing file"
+                };
+                let _scope = TimingScope::new(name);
+                let text = decode_utf8(&data)?;
+                if let Some(mut prev) = prev {
+                    prev.replace(text);
+                    Ok(prev)
+                } else {
+                    Ok(Source::new(self.id, text.into()))
+                }
+            },
+        )
+    }
+
+    fn file(&mut self, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Bytes> {
+        self.file.get_or_init(
+            || read(self.id, project_root, package_storage),
+            |data, _| Ok(Bytes::new(data)),
+        )
+    }
+}
+
+struct SlotCell<T> {
+    data: Option<FileResult<T>>,
+    fingerprint: u128,
+    accessed: bool,
+}
+
+impl<T: Clone> SlotCell<T> {
+    fn new() -> Self {
+        Self {
+            data: None,
+            fingerprint: 0,
+            accessed: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.accessed = false;
+    }
+
+    fn get_or_init(
+        &mut self,
+        load: impl FnOnce() -> FileResult<Vec<u8>>,
+        f: impl FnOnce(Vec<u8>, Option<T>) -> FileResult<T>,
     ) -> FileResult<T> {
         if mem::replace(&mut self.accessed, true) {
             if let Some(data) = &self.data {
                 return data.clone();
             }
-        }
+        }
+
+        let result = timed!("loading file", load());
+        let fingerprint = timed!("hashing file", typst::utils::hash128(&result));
+
+        if mem::replace(&mut self.fingerprint, fingerprint) == fingerprint {
+            if let Some(data) = &self.data {
+                return data.clone();
+            }
+        }
+
+        let prev = self.data.take().and_then(Result::ok);
+        let value = result.and_then(|data| f(data, prev));
+        self.data = Some(value.clone());
+
+        value
+    }
+}
+
+pub struct SilentDownloadProgress<T>(pub T);
+
+impl<T: Display> Progress for SilentDownloadProgress<T> {
+    fn print_start(&mut self) {}
+    fn print_progress(&mut self, _state: &DownloadState) {}
+    fn print_finish(&mut self, _state: &DownloadState) {}
+}
+
+fn system_path(
+    project_root: &Path,
+    id: FileId,
+    package_storage: &PackageStorage,
+) -> FileResult<PathBuf> {
+    let buf;
+    let mut root = project_root;
+    if let Some(spec) = id.package() {
+        buf = package_storage.prepare_package(spec, &mut SilentDownloadProgress(&spec))?;
+        root = &buf;
+    }
+
+    id.vpath().resolve(root).ok_or(FileError::AccessDenied)
+}
+
+fn read(id: FileId, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Vec<u8>> {
+    read_from_disk(&system_path(project_root, id, package_storage)?)
+}
+
+fn read_from_disk(path: &Path) -> FileResult<Vec<u8>> {
+    let f = |e| FileError::from_io(e, path);
+    if fs::metadata(path).map_err(f)?.is_dir() {
+        Err(FileError::IsDirectory)
+    } else {
+        fs::read(path).map_err(f)
+    }
+}
+
+fn decode_utf8(buf: &[u8]) -> FileResult<&str> {
+    Ok(std::str::from_utf8(
+        buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buf),
+    )?)
+}
+
+enum Now {
+    #[allow(dead_code)]
+    Fixed(DateTime<Utc>),
+    System(OnceLock<DateTime<Utc>>),
+}
+
+/// A compiled HTML document along with the warnings from its compilation.
+struct CompiledHtml {
+    document: HtmlDocument,
+    warnings: EcoVec<SourceDiagnostic>,
+}
+
+pub struct TypstContext {
+    world: Mutex<SystemWorld>,
+    document: Mutex<Option<PagedDocument>>,
+    html: Mutex<Option<CompiledHtml>>,
+    /// Whether the paged document was redacted since the last compile.
+    redacted: AtomicBool,
+}
+
+impl TypstContext {
+    /// Drop all compiled documents so they are rebuilt on the next compile.
+    fn invalidate(&self) {
+        *self.document.lock() = None;
+        *self.html.lock() = None;
+        self.redacted.store(false, Ordering::SeqCst);
+    }
+
+    /// Refuse HTML exports after a redaction, since the HTML document is
+    /// compiled from the markup and would contain the original text.
+    fn check_unredacted(&self, format: &str) -> Result<(), CompileErrorNif> {
+        if self.redacted.load(Ordering::SeqCst) {
+            return Err(simple_error(&format!(
+                "The document was redacted; {} would contain the original text.",
+                format
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl CompiledHtml {
+    /// Return the cached HTML document, compiling it first if needed.
+    fn get_or_compile<'a>(
+        cache: &'a mut Option<CompiledHtml>,
+        world: &mut SystemWorld,
+    ) -> Result<&'a CompiledHtml, CompileErrorNif> {
+        if cache.is_none() {
+            world.reset();
+            let result = typst::compile::<HtmlDocument>(&*world);
+            match result.output {
+                Ok(document) => {
+                    *cache = Some(CompiledHtml {
+                        document,
+                        warnings: result.warnings,
+                    });
+                }
+                Err(errors) => {
+                    return Err(CompileErrorNif {
+                        diagnostics: diagnostics_to_vec(errors, world),
+                    });
+                }
+            }
+        }
+        Ok(cache.as_ref().unwrap())
+    }
+}
+
+impl UnwindSafe for TypstContext {}
+impl RefUnwindSafe for TypstContext {}
+
+#[rustler::resource_impl]
+impl rustler::Resource for TypstContext {}
+
+fn resolve_line_column(
+    span: typst::syntax::Span,
+    byte_offset: usize,
+    world: &SystemWorld,
+) -> (Option<usize>, Option<usize>) {
+    span.id()
+        .and_then(|id| world.source(id).ok())
+        .map(|source| {
+            let lines = source.lines();
+            let line = lines.byte_to_line(byte_offset).map(|l| l + 1);
+            let column = lines.byte_to_column(byte_offset).map(|c| c + 1);
+            (line, column)
+        })
+        .unwrap_or((None, None))
+}
+
+fn span_to_nif(span: typst::syntax::Span, world: &SystemWorld) -> Option<SpanNif> {
+    span.range().map(|range| {
+        let (line, column) = resolve_line_column(span, range.start, world);
+        SpanNif {
+            start: range.start,
+            end: range.end,
+            line,
+            column,
+        }
+    })
+}
+
+fn span_to_nif_simple(span: typst::syntax::Span) -> Option<SpanNif> {
+    span.range().map(|range| SpanNif {
+        start: range.start,
+        end: range.end,
+        line: None,
+        column: None,
+    })
+}
+
+fn diagnostic_to_nif(d: &SourceDiagnostic, world: &SystemWorld) -> DiagnosticNif {
+    DiagnosticNif {
+        severity: d.severity.into(),
+        message: d.message.to_string(),
+        span: span_to_nif(d.span, world),
+        trace: d
+            .trace
+            .iter()
+            .map(|item| TraceItemNif {
+                span: span_to_nif(item.span, world),
+                message: item.v.to_string(),
+            })
+            .collect(),
+        hints: d.hints.iter().map(|h| h.to_string()).collect(),
+    }
+}
+
+fn diagnostics_to_vec(
+    diagnostics: EcoVec<SourceDiagnostic>,
+    world: &SystemWorld,
+) -> Vec<DiagnosticNif> {
+    diagnostics
+        .iter()
+        .map(|d| diagnostic_to_nif(d, world))
+        .collect()
+}
+
+fn diagnostics_to_vec_simple(diagnostics: EcoVec<SourceDiagnostic>) -> Vec<DiagnosticNif> {
+    diagnostics
+        .iter()
+        .map(|d| DiagnosticNif {
+            severity: d.severity.into(),
+            message: d.message.to_string(),
+            span: span_to_nif_simple(d.span),
+            trace: d
+                .trace
+                .iter()
+                .map(|item| TraceItemNif {
+                    span: span_to_nif_simple(item.span),
+                    message: item.v.to_string(),
+                })
+                .collect(),
+            hints: d.hints.iter().map(|h| h.to_string()).collect(),
+        })
+        .collect()
+}
+
+fn simple_error(message: &str) -> CompileErrorNif {
+    CompileErrorNif {
+        diagnostics: vec![DiagnosticNif {
+            severity: SeverityNif::Error,
+            message: message.to_string(),
+            span: None,
+            trace: vec![],
+            hints: vec![],
+        }],
+    }
+}
+
+fn compiled_document(document: &Option<PagedDocument>) -> Result<&PagedDocument, CompileErrorNif> {
+    document
+        .as_ref()
+        .ok_or_else(|| simple_error("No compiled document. Call compile() first."))
+}
+
+fn document_page(document: &PagedDocument, page: usize) -> Result<&Page, CompileErrorNif> {
+    document.pages.get(page).ok_or_else(|| {
+        simple_error(&format!(
+            "Page index {} out of bounds (document has {} pages)",
+            page,
+            document.pages.len()
+        ))
+    })
+}
+
+/// Cut a rectangle, given in points from the page's top-left corner, out of
+/// a page. The result is a page of the rectangle's size showing only that
+/// part of the original.
+fn page_region(page: &Page, region: (f64, f64, f64, f64)) -> Result<Page, CompileErrorNif> {
+    let (x, y, width, height) = region;
+    let size = page.frame.size();
+    let (pw, ph) = (size.x.to_pt(), size.y.to_pt());
+
+    let valid = [x, y, width, height].iter().all(|v| v.is_finite())
+        && x >= 0.0
+        && y >= 0.0
+        && width > 0.0
+        && height > 0.0
+        && x + width <= pw + 1e-6
+        && y + height <= ph + 1e-6;
+    if !valid {
+        return Err(simple_error(&format!(
+            "Region {}x{}+{}+{} is outside the page ({}x{} pt)",
+            width, height, x, y, pw, ph
+        )));
+    }
+
+    let size = Size::new(Abs::pt(width), Abs::pt(height));
+    let mut frame = Frame::hard(size);
+    frame.push_frame(Point::new(Abs::pt(-x), Abs::pt(-y)), page.frame.clone());
+    frame.clip(Curve::rect(size));
+
+    let mut cropped = page.clone();
+    cropped.frame = frame;
+    Ok(cropped)
+}
+
+/// The largest width or height, in pixels, of a raster export.
+const MAX_RASTER_SIDE: f64 = 16384.0;
+
+impl RasterOptionsNif {
+    /// Check the options and return the page fill to render with: `None`
+    /// keeps the page's own fill.
+    fn page_fill(&self) -> Result<Option<Option<Paint>>, String> {
+        match self.quality {
+            Some(_) if self.format != RasterFormatNif::Jpeg => {
+                return Err(format!(
+                    "Quality only applies to JPEG ({} output is lossless)",
+                    self.format.name()
+                ));
+            }
+            Some(quality) if !(1..=100).contains(&quality) => {
+                return Err(format!(
+                    "Invalid quality: {} (must be between 1 and 100)",
+                    quality
+                ));
+            }
+            _ => {}
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("Raster width and height must be positive".to_string());
+        }
+
+        match self.background.as_deref() {
+            None => Ok(None),
+            Some("transparent") if self.format == RasterFormatNif::Jpeg => {
+                Err("JPEG does not support a transparent background".to_string())
+            }
+            Some("transparent") => Ok(Some(None)),
+            Some(hex) => {
+                let color = Color::from_str(hex)
+                    .map_err(|_| format!("Invalid background colour: {}", hex))?;
+                Ok(Some(Some(Paint::Solid(color))))
+            }
+        }
+    }
+
+    /// The render scale in pixels per point and, when both a width and a
+    /// height are given, the exact output size to fit the render into.
+    fn scale(&self, page: &Page) -> Result<(f64, Option<(u32, u32)>), String> {
+        let size = page.frame.size();
+        let (pw, ph) = (size.x.to_pt(), size.y.to_pt());
+
+        let (scale, target) = match (self.width, self.height) {
+            (None, None) => (self.pixel_per_pt, None),
+            (Some(w), None) => (w as f64 / pw, None),
+            (None, Some(h)) => (h as f64 / ph, None),
+            (Some(w), Some(h)) => {
+                let (sx, sy) = (w as f64 / pw, h as f64 / ph);
+                match self.fit {
+                    RasterFitNif::Contain => (sx.min(sy), None),
+                    RasterFitNif::Cover | RasterFitNif::Stretch => (sx.max(sy), Some((w, h))),
+                }
+            }
+        };
+
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(format!(
+                "Invalid pixel_per_pt: {} (must be positive)",
+                scale
+            ));
+        }
+        if (pw * scale).ceil() > MAX_RASTER_SIDE || (ph * scale).ceil() > MAX_RASTER_SIDE {
+            return Err(format!(
+                "Raster output of {}x{} pixels exceeds the maximum of {} per side",
+                (pw * scale).ceil(),
+                (ph * scale).ceil(),
+                MAX_RASTER_SIDE
+            ));
+        }
+
+        Ok((scale, target))
+    }
+}
+
+/// Rasterize a page and encode it according to the options.
+fn render_raster(page: &Page, opts: &RasterOptionsNif) -> Result<Vec<u8>, CompileErrorNif> {
+    let fill = opts.page_fill().map_err(|e| simple_error(&e))?;
+    let (scale, target) = opts.scale(page).map_err(|e| simple_error(&e))?;
+
+    let mut page = page.clone();
+    if let Some(fill) = fill {
+        page.fill = Smart::Custom(fill);
+    }
+
+    let pixmap = typst_render::render(&page, scale as f32);
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    let mut image = RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixels)
+        .ok_or_else(|| simple_error("Failed to convert the rendered page"))?;
+
+    if let Some((w, h)) = target {
+        if opts.fit == RasterFitNif::Cover {
+            let x = image.width().saturating_sub(w) / 2;
+            let y = image.height().saturating_sub(h) / 2;
+            image = imageops::crop_imm(&image, x, y, w, h).to_image();
+        }
+        if image.dimensions() != (w, h) {
+            image = imageops::resize(&image, w, h, FilterType::Lanczos3);
+        }
+    }
+
+    let (w, h) = image.dimensions();
+    let mut out = Vec::new();
+    let result = match opts.format {
+        RasterFormatNif::Png => {
+            PngEncoder::new(&mut out).write_image(image.as_raw(), w, h, ExtendedColorType::Rgba8)
+        }
+        RasterFormatNif::Webp => WebPEncoder::new_lossless(&mut out).write_image(
+            image.as_raw(),
+            w,
+            h,
+            ExtendedColorType::Rgba8,
+        ),
+        RasterFormatNif::Jpeg => {
+            // JPEG has no alpha channel, so composite onto white.
+            let rgb: Vec<u8> = image
+                .pixels()
+                .flat_map(|p| {
+                    let [r, g, b, a] = p.0;
+                    let blend =
+                        |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+                    [blend(r), blend(g), blend(b)]
+                })
+                .collect();
+            JpegEncoder::new_with_quality(&mut out, opts.quality.unwrap_or(85) as u8).write_image(
+                &rgb,
+                w,
+                h,
+                ExtendedColorType::Rgb8,
+            )
+        }
+    };
+
+    result.map_err(|e| simple_error(&format!("Failed to encode image: {}", e)))?;
+    Ok(out)
+}
+
+/// Write export output to `path`, returning its size and SHA-256 checksum.
+///
+/// The data goes to a temporary file next to `path` that is renamed over it
+/// once complete, so readers never see a partially written file.
+fn write_output(
+    path: &str,
+    data: &[u8],
+    warnings: Vec<DiagnosticNif>,
+) -> Result<WriteResultNif, CompileErrorNif> {
+    static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let target = Path::new(path);
+    let name = target
+        .file_name()
+        .ok_or_else(|| simple_error(&format!("Failed to write {}: not a file path", path)))?;
+    let temp = target.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let write = || -> std::io::Result<()> {
+        let mut file = BufWriter::new(fs::File::create(&temp)?);
+        file.write_all(data)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(&temp, target)
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        simple_error(&format!("Failed to write {}: {}", path, e))
+    })?;
+
+    let sha256 = Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(WriteResultNif {
+        path: path.to_string(),
+        size: data.len() as u64,
+        sha256,
+        warnings,
+    })
+}
+
+/// Parse "1-3,5,7-9" into PageRanges (1-indexed inclusive ranges using NonZeroUsize).
+fn parse_page_ranges(pages: &str, total: usize) -> Result<PageRanges, String> {
+    use std::ops::RangeInclusive;
+
+    let mut ranges: Vec<RangeInclusive<Option<NonZeroUsize>>> = Vec::new();
+    for part in pages.split(',') {
+        let part = part.trim();
+        if part.contains('-') {
+            let mut iter = part.splitn(2, '-');
+            let start: usize = iter
+                .next()
+                .unwrap()
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid page number in range: {}", part))?;
+            let end: usize = iter
+                .next()
+                .unwrap()
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid page number in range: {}", part))?;
+            if start < 1 || end < 1 || start > total || end > total || start > end {
+                return Err(format!("Page range out of bounds: {}", part));
+            }
+            ranges.push(NonZeroUsize::new(start)..=NonZeroUsize::new(end));
+        } else {
+            let page: usize = part
+                .parse()
+                .map_err(|_| format!("Invalid page number: {}", part))?;
+            if page < 1 || page > total {
+                return Err(format!("Page number out of bounds: {}", page));
+            }
+            let nz = NonZeroUsize::new(page);
+            ranges.push(nz..=nz);
+        }
+    }
+    Ok(PageRanges::new(ranges))
+}
+
+/// Collect every font used by text in the given frame, along with the span of
+/// its first glyph so diagnostics can point back at the source.
+fn collect_fonts(frame: &Frame, fonts: &mut Vec<(Font, Span)>) {
+    for (_, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => collect_fonts(&group.frame, fonts),
+            FrameItem::Text(text) if !fonts.iter().any(|(font, _)| *font == text.font) => {
+                let span = text
+                    .glyphs
+                    .first()
+                    .map(|g| g.span.0)
+                    .unwrap_or_else(Span::detached);
+                fonts.push((text.font.clone(), span));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Inspect the OS/2 `fsType` embedding permissions of the fonts used on the
+/// exported pages. Returns the font report and a diagnostic for every font
+/// whose license forbids embedding it in the PDF.
+fn check_font_embedding(
+    document: &PagedDocument,
+    page_ranges: Option<&PageRanges>,
+    severity: SeverityNif,
+) -> (Vec<FontEmbeddingNif>, Vec<DiagnosticNif>) {
+    let mut fonts = Vec::new();
+    for (i, page) in document.pages.iter().enumerate() {
+        let included = page_ranges
+            .map(|ranges| ranges.includes_page(NonZeroUsize::new(i + 1).unwrap()))
+            .unwrap_or(true);
+        if included {
+            collect_fonts(&page.frame, &mut fonts);
+        }
+    }
+
+    let mut report = Vec::with_capacity(fonts.len());
+    let mut diagnostics = Vec::new();
+    for (font, span) in fonts {
+        let family = font.info().family.clone();
+        let permission = FontPermissionNif::from(font.ttf().permissions());
+        let subsetting_allowed =
+            permission == FontPermissionNif::Unknown || font.ttf().is_subsetting_allowed();
+
+        let problem = if permission == FontPermissionNif::Restricted {
+            Some("its license restricts embedding (OS/2 fsType: restricted)")
+        } else if !subsetting_allowed {
+            Some("its license forbids subsetting (OS/2 fsType: no subsetting)")
+        } else {
+            None
+        };
+
+        if let Some(problem) = problem {
+            diagnostics.push(DiagnosticNif {
+                severity,
+                message: format!("Font \"{}\" cannot be embedded: {}", family, problem),
+                span: span_to_nif_simple(span),
+                trace: vec![],
+                hints: vec![
+                    "use a font that permits embedding or obtain an embedding license".to_string(),
+                ],
+            });
+        }
+
+        report.push(FontEmbeddingNif {
+            family,
+            permission,
+            subsetting_allowed,
+        });
+    }
+
+    (report, diagnostics)
+}
+
+/// The PDF bytes, the font report, the export warnings and, when optimizing,
+/// the size report.
+type PdfExport = (
+    Vec<u8>,
+    Vec<FontEmbeddingNif>,
+    Vec<DiagnosticNif>,
+    Option<PdfOptimizationNif>,
+);
+
+/// Export the document as PDF, checking font embedding permissions first.
+fn build_pdf(
+    document: &PagedDocument,
+    opts: &PdfOptionsNif,
+    stamps: &[Stamp],
+) -> Result<PdfExport, CompileErrorNif> {
+    let mut pdf_opts = opts.to_pdf_options().map_err(|e| simple_error(&e))?;
+
+    // With imposition the page ranges select sheets, so they are checked
+    // against the imposed document.
+    let document = export_document(document, stamps, opts.impose.as_ref())?;
+    pdf_opts.page_ranges = match opts.pages {
+        Some(ref pages_str) => {
+            Some(parse_page_ranges(pages_str, document.pages.len()).map_err(|e| simple_error(&e))?)
+        }
+        None => None,
+    };
+
+    let (fonts, font_diagnostics) = match opts.font_embedding {
+        FontEmbeddingPolicyNif::Ignore => (vec![], vec![]),
+        FontEmbeddingPolicyNif::Warn => check_font_embedding(
+            &document,
+            pdf_opts.page_ranges.as_ref(),
+            SeverityNif::Warning,
+        ),
+        FontEmbeddingPolicyNif::Error => {
+            check_font_embedding(&document, pdf_opts.page_ranges.as_ref(), SeverityNif::Error)
+        }
+    };
+
+    if opts.font_embedding == FontEmbeddingPolicyNif::Error && !font_diagnostics.is_empty() {
+        return Err(CompileErrorNif {
+            diagnostics: font_diagnostics,
+        });
+    }
+
+    let document = match opts.metadata {
+        Some(ref metadata) => Cow::Owned(metadata.apply(&document)),
+        None => document,
+    };
+    let document = match opts.print {
+        Some(ref print) => Cow::Owned(print.apply(&document)),
+        None => document,
+    };
+    let mut warnings = font_diagnostics;
+    let pdf_a = opts
+        .pdf_standards
+        .iter()
+        .any(|s| matches!(s, PdfStandardNif::PdfA2b | PdfStandardNif::PdfA3b));
+    let mut color_markers = HashMap::new();
+    let document = match opts.color {
+        Some(ref color) => {
+            let (prepared, markers, color_warnings) = color.prepare(&document, pdf_a);
+            warnings.extend(color_warnings);
+            color_markers = markers;
+            Cow::Owned(prepared)
+        }
+        None => document,
+    };
+    let (document, mut optimization) = match opts.optimize {
+        Some(ref optimize) => {
+            let (optimized, report) = optimize.apply(&document).map_err(|e| simple_error(&e))?;
+            (Cow::Owned(optimized), Some(report))
+        }
+        None => (document, None),
+    };
+    let document = document.as_ref();
+
+    let mut pdf_bytes = typst_pdf::pdf(document, &pdf_opts).map_err(|e| CompileErrorNif {
+        diagnostics: diagnostics_to_vec_simple(e),
+    })?;
+
+    if let Some(ref color) = opts.color {
+        pdf_bytes = color
+            .convert_pdf(&pdf_bytes, &color_markers, pdf_a)
+            .map_err(|e| simple_error(&e))?;
+    }
+
+    if let (Some(optimize), Some(report)) = (&opts.optimize, &mut optimization) {
+        report.original_size = pdf_bytes.len();
+        if let Some(level) = optimize.compression {
+            pdf_bytes = compress_pdf(&pdf_bytes, level).map_err(|e| simple_error(&e))?;
+        }
+    }
+
+    if let Some(ref metadata) = opts.metadata {
+        if !metadata.xmp.is_empty() {
+            pdf_bytes = rewrite_pdf_metadata(&pdf_bytes, metadata).map_err(|e| simple_error(&e))?;
+        }
+    }
+
+    if let Some(ref print) = opts.print {
+        pdf_bytes = set_page_boxes(&pdf_bytes, print).map_err(|e| simple_error(&e))?;
+    }
+
+    if let Some(ref signature) = opts.signature {
+        let placement = signature
+            .placement(document, pdf_opts.page_ranges.as_ref())
+            .map_err(|e| simple_error(&e))?;
+        let plain_appearance = pdf_a;
+        pdf_bytes = sign_pdf(&pdf_bytes, signature, placement, plain_appearance)
+            .map_err(|e| simple_error(&e))?;
+    }
+
+    if let Some(ref encryption) = opts.encryption {
+        pdf_bytes = encrypt_pdf(&pdf_bytes, encryption).map_err(|e| simple_error(&e))?;
+    }
+
+    if opts.linearize {
+        pdf_bytes = linearize_pdf(&pdf_bytes).map_err(|e| simple_error(&e))?;
+    }
+
+    if let Some(ref mut report) = optimization {
+        report.size = pdf_bytes.len();
+    }
+
+    Ok((pdf_bytes, fonts, warnings, optimization))
+}
+
+/// The encoded size of a raster image, or zero for vector images.
+fn raster_size(image: &Image) -> usize {
+    match image.kind() {
+        ImageKind::Raster(raster) => raster.data().len(),
+        _ => 0,
+    }
+}
+
+/// Collect the spans of images that have no alternative text.
+fn collect_images_without_alt(frame: &Frame, spans: &mut Vec<Span>) {
+    for (_, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => collect_images_without_alt(&group.frame, spans),
+            FrameItem::Image(image, _, span) if image.alt().is_none() => spans.push(*span),
+            _ => {}
+        }
+    }
+}
+
+/// Check the document for common accessibility problems that keep it from
+/// meeting PDF/UA: a missing title or language and images without
+/// alternative text.
+fn accessibility_findings(document: &PagedDocument, world: &SystemWorld) -> Vec<DiagnosticNif> {
+    let finding = |message: &str, span: Span, hint: &str| DiagnosticNif {
+        severity: SeverityNif::Warning,
+        message: message.to_string(),
+        span: span_to_nif(span, world),
+        trace: vec![],
+        hints: vec![hint.to_string()],
+    };
+
+    let mut findings = vec![];
+    if document.info.title.is_none() {
+        findings.push(finding(
+            "The document has no title",
+            Span::detached(),
+            "set one with `#set document(title: ..)` or the `metadata` PDF option",
+        ));
+    }
+    // typst records the first top-level `text(lang: ..)` rule as the locale;
+    // the house style's language applies before any markup.
+    if !document.info.locale.is_custom() && !world.default_lang {
+        findings.push(finding(
+            "The document language is not set and defaults to English",
+            Span::detached(),
+            "set it with `#set text(lang: ..)` or the `lang` default style",
+        ));
+    }
+
+    let mut images = vec![];
+    for page in &document.pages {
+        collect_images_without_alt(&page.frame, &mut images);
+    }
+    for span in images {
+        findings.push(finding(
+            "Image has no alternative text",
+            span,
+            "describe it with the `alt` argument of `image`",
+        ));
+    }
+
+    findings
+}
+
+/// The document as it is exported, after the export-time page transforms.
+/// Overlays are drawn on the document's own pages, before imposition.
+fn export_document<'d>(
+    document: &'d PagedDocument,
+    stamps: &[Stamp],
+    impose: Option<&ImpositionNif>,
+) -> Result<Cow<'d, PagedDocument>, CompileErrorNif> {
+    let document = if stamps.is_empty() {
+        Cow::Borrowed(document)
+    } else {
+        Cow::Owned(stamp_document(document, stamps).map_err(|e| simple_error(&e))?)
+    };
+    match impose {
+        Some(imposition) => Ok(Cow::Owned(
+            imposition.apply(&document).map_err(|e| simple_error(&e))?,
+        )),
+        None => Ok(document),
+    }
+}
+
+/// What redacted headings and metadata show instead of the matched text.
+const REDACTED: &str = "[redacted]";
+
+impl RedactOptionsNif {
+    /// One pattern matching any of the regexes and literal strings.
+    fn pattern(&self) -> Result<Regex, String> {
+        if self.patterns.is_empty() && self.literals.is_empty() {
+            return Err("Nothing to redact: give at least one pattern or literal".to_string());
+        }
+        for pattern in &self.patterns {
+            Regex::new(pattern).map_err(|e| format!("Invalid redaction pattern: {}", e))?;
+        }
+        let alternatives: Vec<String> = self
+            .patterns
+            .iter()
+            .cloned()
+            .chain(self.literals.iter().map(|literal| regex::escape(literal)))
+            .map(|pattern| format!("(?:{})", pattern))
+            .collect();
+        Regex::new(&alternatives.join("|")).map_err(|e| format!("Invalid redaction pattern: {}", e))
+    }
+
+    /// A copy of the document without the matching text, along with a
+    /// report of every match. Text runs lose the matching glyphs, which are
+    /// covered by a box; heading bodies, which the PDF outline shows, link
+    /// URLs and their alternative text, and the title, authors, description
+    /// and keywords have the matches replaced. The introspector is rebuilt
+    /// from the redacted headings.
+    fn apply(
+        &self,
+        document: &PagedDocument,
+    ) -> Result<(PagedDocument, Vec<RedactionNif>), String> {
+        let color = Color::from_str(&self.color)
+            .map_err(|_| format!("Invalid redaction colour: {}", self.color))?;
+        let mut redactor = Redactor {
+            pattern: self.pattern()?,
+            fill: Paint::Solid(color),
+            page: None,
+            redactions: vec![],
+        };
+
+        let pages: Vec<Page> = document
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(i, page)| {
+                redactor.page = Some(i + 1);
+                let mut redacted = page.clone();
+                redacted.frame = redactor.frame(&page.frame, Transform::identity());
+                redacted
+            })
+            .collect();
+
+        redactor.page = None;
+        let mut info = document.info.clone();
+        let mut replace = |kind, text: &EcoString| -> EcoString {
+            match redactor.replace(kind, None, text) {
+                Some(redacted) => redacted.into(),
+                None => text.clone(),
+            }
+        };
+        info.title = info
+            .title
+            .as_ref()
+            .map(|title| replace(RedactionKindNif::Title, title));
+        info.author = info
+            .author
+            .iter()
+            .map(|author| replace(RedactionKindNif::Author, author))
+            .collect();
+        info.description = info
+            .description
+            .as_ref()
+            .map(|description| replace(RedactionKindNif::Description, description));
+        info.keywords = info
+            .keywords
+            .iter()
+            .map(|keyword| replace(RedactionKindNif::Keywords, keyword))
+            .collect();
+
+        let introspector = introspect_pages(&pages);
+        Ok((
+            PagedDocument {
+                pages,
+                info,
+                introspector,
+            },
+            redactor.redactions,
+        ))
+    }
+}
+
+/// Removes text matching a pattern from frames and covers where it was.
+struct Redactor {
+    pattern: Regex,
+    fill: Paint,
+    /// The page being redacted, or `None` for the document metadata.
+    page: Option<usize>,
+    redactions: Vec<RedactionNif>,
+}
+
+impl Redactor {
+    /// Redact a frame. `ts` maps the frame's coordinates to the page's, for
+    /// the report.
+    fn frame(&mut self, frame: &Frame, ts: Transform) -> Frame {
+        let mut redacted = Frame::new(frame.size(), frame.kind());
+        if frame.has_baseline() {
+            redacted.set_baseline(frame.baseline());
+        }
+
+        for (point, item) in frame.items() {
+            match item {
+                FrameItem::Group(group) => {
+                    let ts = ts
+                        .pre_concat(Transform::translate(point.x, point.y))
+                        .pre_concat(group.transform);
+                    let mut group = group.clone();
+                    group.frame = self.frame(&group.frame, ts);
+                    redacted.push(*point, FrameItem::Group(group));
+                }
+                FrameItem::Text(text) => self.text(&mut redacted, *point, text, ts),
+                FrameItem::Link(Destination::Url(url), size) => {
+                    let url = self.url(url, point.transform(ts), *size);
+                    redacted.push(*point, FrameItem::Link(Destination::Url(url), *size));
+                }
+                FrameItem::Tag(Tag::Start(..)) => {
+                    let mut item = item.clone();
+                    if let FrameItem::Tag(Tag::Start(content, ..)) = &mut item {
+                        self.tag(content);
+                    }
+                    redacted.push(*point, item);
+                }
+                _ => redacted.push(*point, item.clone()),
+            }
+        }
+        redacted
+    }
+
+    /// Push the glyphs of a text run that are not part of a match, split
+    /// into runs of their own, and a box over each match.
+    fn text(&mut self, frame: &mut Frame, pos: Point, text: &TextItem, ts: Transform) {
+        let matches: Vec<Range<usize>> = self
+            .pattern
+            .find_iter(&text.text)
+            .filter(|m| !m.is_empty())
+            .map(|m| m.range())
+            .collect();
+        if matches.is_empty() {
+            frame.push(pos, FrameItem::Text(text.clone()));
+            return;
+        }
+
+        let overlaps = |glyph: &Glyph, range: &Range<usize>| {
+            let glyph = glyph.range();
+            glyph.start < range.end && range.start < glyph.end
+        };
+        let mut xs = Vec::with_capacity(text.glyphs.len() + 1);
+        let mut x = Abs::zero();
+        for glyph in &text.glyphs {
+            xs.push(x);
+            x += glyph.x_advance.at(text.size);
+        }
+        xs.push(x);
+
+        let mut start = 0;
+        for (i, glyph) in text.glyphs.iter().enumerate() {
+            if matches.iter().any(|range| overlaps(glyph, range)) {
+                if start < i {
+                    frame.push(pos + Point::with_x(xs[start]), Self::run(text, start..i));
+                }
+                start = i + 1;
+            }
+        }
+        if start < text.glyphs.len() {
+            frame.push(
+                pos + Point::with_x(xs[start]),
+                Self::run(text, start..text.glyphs.len()),
+            );
+        }
+
+        let metrics = text.font.metrics();
+        let (ascender, descender) = (
+            metrics.ascender.at(text.size),
+            metrics.descender.at(text.size),
+        );
+        for range in matches {
+            let glyphs: Vec<usize> = (0..text.glyphs.len())
+                .filter(|&i| overlaps(&text.glyphs[i], &range))
+                .collect();
+            let (Some(&first), Some(&last)) = (glyphs.iter().min(), glyphs.iter().max()) else {
+                continue;
+            };
+            let at = pos + Point::new(xs[first], -ascender);
+            let size = Size::new(xs[last + 1] - xs[first], ascender - descender);
+            frame.push(
+                at,
+                FrameItem::Shape(
+                    Geometry::Rect(size).filled(self.fill.clone()),
+                    text.glyphs[first].span.0,
+                ),
+            );
+
+            let origin = at.transform(ts);
+            self.redactions.push(RedactionNif {
+                kind: RedactionKindNif::Text,
+                page: self.page,
+                text: text.text[range].to_string(),
+                x: Some(origin.x.to_pt()),
+                y: Some(origin.y.to_pt()),
+                width: Some(size.x.to_pt()),
+                height: Some(size.y.to_pt()),
+            });
+        }
+    }
+
+    /// A text run of some of the glyphs, with only their text.
+    fn run(text: &TextItem, glyphs: Range<usize>) -> FrameItem {
+        let glyphs = &text.glyphs[glyphs];
+        let start = glyphs.iter().map(|g| g.range.start).min().unwrap_or(0);
+        let end = glyphs.iter().map(|g| g.range.end).max().unwrap_or(0);
+        let mut run = text.clone();
+        run.text = text.text[start as usize..end as usize].into();
+        run.glyphs = glyphs
+            .iter()
+            .map(|glyph| {
+                let mut glyph = glyph.clone();
+                glyph.range = glyph.range.start - start..glyph.range.end - start;
+                glyph
+            })
+            .collect();
+        FrameItem::Text(run)
+    }
+
+    /// Replace matches in text outside the glyphs, reporting each one.
+    /// Returns `None` when nothing matches.
+    fn replace(
+        &mut self,
+        kind: RedactionKindNif,
+        bounds: Option<(Point, Size)>,
+        text: &str,
+    ) -> Option<String> {
+        let before = self.redactions.len();
+        for m in self.pattern.find_iter(text).filter(|m| !m.is_empty()) {
+            self.redactions.push(RedactionNif {
+                kind,
+                page: self.page,
+                text: m.as_str().to_string(),
+                x: bounds.map(|(at, _)| at.x.to_pt()),
+                y: bounds.map(|(at, _)| at.y.to_pt()),
+                width: bounds.map(|(_, size)| size.x.to_pt()),
+                height: bounds.map(|(_, size)| size.y.to_pt()),
+            });
+        }
+        (self.redactions.len() > before)
+            .then(|| self.pattern.replace_all(text, REDACTED).into_owned())
+    }
+
+    /// Replace matches in a link target, which PDF viewers show on hover.
+    /// `at` is the link's top-left corner on the page, for the report.
+    fn url(&mut self, url: &Url, at: Point, size: Size) -> Url {
+        match self.replace(RedactionKindNif::Url, Some((at, size)), url) {
+            None => url.clone(),
+            // A URL grown past the length limit is dropped as a whole.
+            Some(redacted) => Url::new(redacted).or_else(|_| Url::new(REDACTED)).unwrap(),
+        }
+    }
+
+    /// Replace matches in a heading's body, which the PDF outline shows, and
+    /// in a link's alternative text, which tagged PDFs carry for URLs.
+    fn tag(&mut self, content: &mut Content) {
+        if let Some(heading) = content.to_packed_mut::<HeadingElem>() {
+            let body = heading.body.plain_text();
+            if self.pattern.is_match(&body) {
+                let redacted = self.pattern.replace_all(&body, REDACTED);
+                heading.body = TextElem::packed(redacted.as_ref());
+            }
+        } else if let Some(link) = content.to_packed_mut::<LinkMarker>() {
+            let alt = link.alt.as_ref().map(|alt| alt.to_string());
+            if let Some(redacted) =
+                alt.and_then(|alt| self.replace(RedactionKindNif::Alt, None, &alt))
+            {
+                link.alt = Some(redacted.into());
+            }
+        }
+    }
+}
+
+/// An overlay laid out for export.
+struct Stamp<'o> {
+    overlay: &'o OverlayNif,
+    frame: Frame,
+}
+
+/// Lay out the overlays of an export with the context's fonts and library.
+/// The world is only locked when there are overlays, so plain exports never
+/// wait for a compile. Call this before locking the document.
+fn layout_overlays<'o>(
+    ctx: &TypstContext,
+    overlays: &'o [OverlayNif],
+) -> Result<Vec<Stamp<'o>>, CompileErrorNif> {
+    if overlays.is_empty() {
+        return Ok(vec![]);
+    }
+    let world = ctx.world.lock();
+    overlays
+        .iter()
+        .map(|overlay| {
+            Ok(Stamp {
+                overlay,
+                frame: overlay.layout(&world)?,
+            })
+        })
+        .collect()
+}
+
+/// A copy of the document with each overlay drawn over the pages it
+/// selects. Nothing moves, so the introspector is kept as it is.
+fn stamp_document(document: &PagedDocument, stamps: &[Stamp]) -> Result<PagedDocument, String> {
+    let total = document.pages.len();
+    let mut pages = document.pages.clone();
+    for stamp in stamps {
+        let overlay = stamp.overlay;
+        let ranges = match overlay.pages {
+            Some(ref pages) => Some(parse_page_ranges(pages, total)?),
+            None => None,
+        };
+        let (x, y) = overlay.position.anchor();
+        for (i, page) in pages.iter_mut().enumerate() {
+            let number = NonZeroUsize::new(i + 1).unwrap();
+            if ranges
+                .as_ref()
+                .is_some_and(|ranges| !ranges.includes_page(number))
+            {
+                continue;
+            }
+            let free = page.frame.size() - stamp.frame.size();
+            let at = Point::new(
+                free.x * x + Abs::pt(overlay.dx),
+                free.y * y + Abs::pt(overlay.dy),
+            );
+            page.frame.push_frame(at, stamp.frame.clone());
+        }
+    }
+
+    Ok(PagedDocument {
+        pages,
+        info: document.info.clone(),
+        introspector: document.introspector.clone(),
+    })
+}
+
+impl OverlayNif {
+    fn validate(&self) -> Result<(), String> {
+        if self.text.is_some() == self.image.is_some() {
+            return Err("An overlay needs either text or an image".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err("Overlay opacity must be between 0 and 1".to_string());
+        }
+        if [self.dx, self.dy, self.rotation]
+            .iter()
+            .any(|v| !v.is_finite())
+        {
+            return Err("Overlay offsets and rotation must be finite".to_string());
+        }
+        let mut lengths = std::iter::once(self.size).chain(self.width);
+        if lengths.any(|v| !v.is_finite() || v <= 0.0) {
+            return Err("Overlay size and width must be positive".to_string());
+        }
+        Color::from_str(&self.color)
+            .map_err(|_| format!("Invalid overlay colour: {}", self.color))?;
+        Ok(())
+    }
+
+    /// Typst markup for a page exactly the size of the rotated overlay.
+    fn markup(&self) -> String {
+        let body = match self.text {
+            Some(ref text) => {
+                let mut args = vec![
+                    format!("size: {}pt", self.size),
+                    format!(
+                        "fill: rgb({}).transparentize({}%)",
+                        Str::from(self.color.as_str()).repr(),
+                        (1.0 - self.opacity) * 100.0
+                    ),
+                ];
+                if let Some(ref font) = self.font {
+                    args.push(format!("font: {}", Str::from(font.as_str()).repr()));
+                }
+                format!(
+                    "text({}, {})",
+                    args.join(", "),
+                    Str::from(text.as_str()).repr()
+                )
+            }
+            None => match self.width {
+                Some(width) => format!("image({:?}, width: {}pt)", OVERLAY_IMAGE_PATH, width),
+                None => format!("image({:?})", OVERLAY_IMAGE_PATH),
+            },
+        };
+
+        format!(
+            "#set page(width: auto, height: auto, margin: 0pt, fill: none)\n\
+             #set text(top-edge: \"bounds\", bottom-edge: \"bounds\")\n\
+             #rotate({}deg, reflow: true, {})\n",
+            self.rotation, body
+        )
+    }
+
+    /// The overlay image with its opacity applied. SVG content is wrapped in
+    /// a translucent group; raster images have their alpha channel scaled and
+    /// are re-encoded as PNG.
+    fn image_data(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        if self.opacity >= 1.0 {
+            return Ok(data.to_vec());
+        }
+
+        if let Ok(svg) = std::str::from_utf8(data) {
+            let root = svg.find("<svg").ok_or("Unsupported overlay image")?;
+            let start = root + svg[root..].find('>').ok_or("Invalid SVG overlay image")? + 1;
+            let end = svg.rfind("</svg>").ok_or("Invalid SVG overlay image")?;
+            if end < start {
+                return Ok(data.to_vec());
+            }
+            return Ok(format!(
+                "{}<g opacity=\"{}\">{}</g>{}",
+                &svg[..start],
+                self.opacity,
+                &svg[start..end],
+                &svg[end..]
+            )
+            .into_bytes());
+        }
+
+        let mut image = image::load_from_memory(data)
+            .map_err(|e| format!("Unsupported overlay image: {}", e))?
+            .to_rgba8();
+        for pixel in image.pixels_mut() {
+            pixel[3] = (pixel[3] as f64 * self.opacity).round() as u8;
+        }
+        let mut out = Vec::new();
+        PngEncoder::new(&mut out)
+            .write_image(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                ExtendedColorType::Rgba8,
+            )
+            .map_err(|e| format!("Failed to encode overlay image: {}", e))?;
+        Ok(out)
+    }
+
+    /// Lay the overlay out once as a frame to place on the pages.
+    fn layout(&self, world: &SystemWorld) -> Result<Frame, CompileErrorNif> {
+        self.validate().map_err(|e| simple_error(&e))?;
+        let image = match self.image {
+            Some(ref image) => Some(Bytes::new(
+                self.image_data(&image.0).map_err(|e| simple_error(&e))?,
+            )),
+            None => None,
+        };
+        let overlay_world = OverlayWorld {
+            world,
+            source: Source::new(*OVERLAY_ID, self.markup()),
+            image,
+        };
+
+        let document = typst::compile::<PagedDocument>(&overlay_world)
+            .output
+            .map_err(|errors| CompileErrorNif {
+                diagnostics: diagnostics_to_vec_simple(errors),
+            })?;
+        document
+            .pages
+            .into_iter()
+            .next()
+            .map(|page| page.frame)
+            .ok_or_else(|| simple_error("The overlay laid out no page"))
+    }
+}
+
+/// The world an overlay is laid out in: the context's world with the
+/// overlay's markup as the main file and its image at a fixed path.
+struct OverlayWorld<'w> {
+    world: &'w SystemWorld,
+    source: Source,
+    image: Option<Bytes>,
+}
+
+impl World for OverlayWorld<'_> {
+    fn library(&self) -> &LazyHash<Library> {
+        self.world.library()
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        self.world.book()
+    }
+
+    fn main(&self) -> FileId {
+        self.source.id()
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if id == self.source.id() {
+            return Ok(self.source.clone());
+        }
+        self.world.source(id)
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        if let Some(ref image) = self.image {
+            if id.package().is_none() && id.vpath() == &VirtualPath::new(OVERLAY_IMAGE_PATH) {
+                return Ok(image.clone());
+            }
+        }
+        self.world.file(id)
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.world.font(index)
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        self.world.today(offset)
+    }
+}
+
+impl ImpositionNif {
+    /// The number of columns and rows of pages on each sheet.
+    fn grid(&self) -> (usize, usize) {
+        match self.order {
+            ImpositionOrderNif::Booklet => (2, 1),
+            _ => (self.columns, self.rows),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let (columns, rows) = self.grid();
+        if columns == 0 || rows == 0 {
+            return Err("Imposition needs at least one column and one row".to_string());
+        }
+        let mut lengths = [self.gutter, self.margin]
+            .into_iter()
+            .chain(self.width)
+            .chain(self.height);
+        if lengths.any(|v| !v.is_finite() || v < 0.0) {
+            return Err("Imposition lengths must be zero or positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// The sheet size: the given width and height, then the paper, then
+    /// just large enough for the grid of unscaled pages.
+    fn sheet_size(&self, cell: Size) -> Result<Size, String> {
+        let (columns, rows) = self.grid();
+        let paper = match self.paper {
+            Some(ref paper) => {
+                Some(Paper::from_str(paper).map_err(|_| format!("Unknown paper size: {}", paper))?)
+            }
+            None => None,
+        };
+        let fit = |cell: Abs, count: usize| {
+            cell * count as f64
+                + Abs::pt(self.gutter) * (count - 1) as f64
+                + Abs::pt(self.margin) * 2.0
+        };
+        let width = self
+            .width
+            .map(Abs::pt)
+            .or(paper.map(|p| p.width()))
+            .unwrap_or_else(|| fit(cell.x, columns));
+        let height = self
+            .height
+            .map(Abs::pt)
+            .or(paper.map(|p| p.height()))
+            .unwrap_or_else(|| fit(cell.y, rows));
+        Ok(Size::new(width, height))
+    }
+
+    /// The 0-indexed page in each cell of each sheet, `None` for blanks.
+    /// Booklets are padded to a multiple of four pages and ordered for
+    /// saddle stitching, so folding the stacked sheets yields the pages in
+    /// sequence.
+    fn sheets(&self, total: usize) -> Vec<Vec<Option<usize>>> {
+        let (columns, rows) = self.grid();
+        let page = |i: usize| (i < total).then_some(i);
+
+        if self.order == ImpositionOrderNif::Booklet {
+            let count = total.div_ceil(4) * 4;
+            return (0..count / 2)
+                .map(|side| {
+                    let (left, right) = if side % 2 == 0 {
+                        (count - 1 - side, side)
+                    } else {
+                        (side, count - 1 - side)
+                    };
+                    vec![page(left), page(right)]
+                })
+                .collect();
+        }
+
+        let per_sheet = columns * rows;
+        (0..total.div_ceil(per_sheet))
+            .map(|sheet| {
+                (0..per_sheet)
+                    .map(|cell| {
+                        // Map the cell in reading order to the page filling it.
+                        let (column, row) = (cell % columns, cell / columns);
+                        let slot = match self.order {
+                            ImpositionOrderNif::Columns => column * rows + row,
+                            _ => cell,
+                        };
+                        page(sheet * per_sheet + slot)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Lay the pages out on sheets. Each page is scaled to fit its cell,
+    /// keeping its aspect ratio, centered and clipped to its own bounds.
+    fn apply(&self, document: &PagedDocument) -> Result<PagedDocument, String> {
+        self.validate()?;
+        let first = document.pages.first().ok_or("The document has no pages")?;
+        let (columns, rows) = self.grid();
+
+        let cell = document
+            .pages
+            .iter()
+            .map(|page| page.frame.size())
+            .fold(Size::zero(), |max, size| max.max(size));
+        let sheet = self.sheet_size(cell)?;
+        let gutter = Abs::pt(self.gutter);
+        let margin = Abs::pt(self.margin);
+        let cell = Size::new(
+            (sheet.x - margin * 2.0 - gutter * (columns - 1) as f64) / columns as f64,
+            (sheet.y - margin * 2.0 - gutter * (rows - 1) as f64) / rows as f64,
+        );
+        if cell.x <= Abs::zero() || cell.y <= Abs::zero() {
+            return Err("The sheet is too small for the imposition grid".to_string());
+        }
+
+        let pages: Vec<Page> = self
+            .sheets(document.pages.len())
+            .into_iter()
+            .enumerate()
+            .map(|(i, slots)| {
+                let mut frame = Frame::hard(sheet);
+                for (slot, index) in slots.into_iter().enumerate() {
+                    let Some(index) = index else { continue };
+                    let page = &document.pages[index];
+                    let size = page.frame.size();
+                    let scale = (cell.x / size.x).min(cell.y / size.y);
+                    let (column, row) = (slot % columns, slot / columns);
+                    let origin = Point::new(
+                        margin + (cell.x + gutter) * column as f64,
+                        margin + (cell.y + gutter) * row as f64,
+                    );
+                    let offset = Point::new(
+                        (cell.x - size.x * scale) / 2.0,
+                        (cell.y - size.y * scale) / 2.0,
+                    );
+
+                    let mut content = Frame::hard(size);
+                    if let Smart::Custom(Some(ref paint)) = page.fill {
+                        content.push(
+                            Point::zero(),
+                            FrameItem::Shape(
+                                Geometry::Rect(size).filled(paint.clone()),
+                                Span::detached(),
+                            ),
+                        );
+                    }
+                    content.push_frame(Point::zero(), page.frame.clone());
+                    content.clip(Curve::rect(size));
+
+                    let mut group = GroupItem::new(content);
+                    group.transform = Transform::scale(Ratio::new(scale), Ratio::new(scale));
+                    frame.push(origin + offset, FrameItem::Group(group));
+                }
+
+                let mut sheet = first.clone();
+                sheet.frame = frame;
+                sheet.fill = Smart::Auto;
+                sheet.number = (i + 1) as u64;
+                sheet
+            })
+            .collect();
+
+        let introspector = introspect_pages(&pages);
+        Ok(PagedDocument {
+            pages,
+            info: document.info.clone(),
+            introspector,
+        })
+    }
+}
+
+/// The 1-indexed pages on which an element with the given label appears,
+/// sorted and without duplicates.
+fn label_pages(document: &PagedDocument, name: &str) -> Result<Vec<usize>, String> {
+    let label = Label::new(PicoStr::intern(name)).ok_or("Label names cannot be empty")?;
+    let introspector = &document.introspector;
+
+    let mut pages: Vec<usize> = introspector
+        .query(&Selector::Label(label))
+        .iter()
+        .filter_map(|content| content.location())
+        .map(|location| introspector.page(location).get())
+        .collect();
+    pages.sort_unstable();
+    pages.dedup();
+
+    if pages.is_empty() {
+        return Err(format!("Label <{}> not found in the document", name));
+    }
+    Ok(pages)
+}
+
+/// Split the document's pages into consecutive parts, starting a new part at
+/// each of the given 1-indexed pages. Pages before the first start form a
+/// part of their own.
+fn split_at_pages(total: usize, starts: &[usize]) -> Vec<PageRanges> {
+    let mut bounds: Vec<usize> = starts.to_vec();
+    if bounds.first() != Some(&1) {
+        bounds.insert(0, 1);
+    }
+
+    bounds
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = bounds.get(i + 1).map(|next| next - 1).unwrap_or(total);
+            PageRanges::new(vec![NonZeroUsize::new(start)..=NonZeroUsize::new(end)])
+        })
+        .collect()
+}
+
+/// Extract the pages in `ranges` as a document of their own, with the
+/// introspector rebuilt for just those pages. typst-pdf cannot export tagged
+/// PDFs with page ranges, but it can export such a document in full.
+fn select_pages(document: &PagedDocument, ranges: &PageRanges) -> PagedDocument {
+    let pages: Vec<Page> = document
+        .pages
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| ranges.includes_page(NonZeroUsize::new(i + 1).unwrap()))
+        .map(|(_, page)| page.clone())
+        .collect();
+    let introspector = introspect_pages(&pages);
+    PagedDocument {
+        pages,
+        info: document.info.clone(),
+        introspector,
+    }
+}
+
+/// Combine documents into one, in order. Pages are renumbered continuously so
+/// the PDF page labels run across the whole document, and the introspector is
+/// rebuilt so the outline and links span every part. The first document's
+/// metadata is kept.
+///
+/// Element locations are only unique within one document, and merging the
+/// same document twice repeats all of them, so every later document's
+/// locations are re-keyed by its position. Its links to page positions are
+/// moved past the pages before it.
+fn merge_documents(documents: Vec<PagedDocument>) -> Option<PagedDocument> {
+    let mut documents = documents.into_iter();
+    let first = documents.next()?;
+    let info = first.info.clone();
+
+    let mut pages = first.pages;
+    for (i, document) in documents.enumerate() {
+        let offset = pages.len();
+        pages.extend(document.pages.into_iter().map(|mut page| {
+            page.frame = rekey_frame(&page.frame, i + 1, offset);
+            page
+        }));
+    }
+    for (i, page) in pages.iter_mut().enumerate() {
+        page.number = (i + 1) as u64;
+    }
+
+    let introspector = introspect_pages(&pages);
+    Some(PagedDocument {
+        pages,
+        info,
+        introspector,
+    })
+}
+
+/// Replace every element location in a frame by one derived from it and
+/// `salt`, consistently for tags, frame parents and links, and shift links to
+/// page positions by `offset` pages.
+fn rekey_frame(frame: &Frame, salt: usize, offset: usize) -> Frame {
+    let rekey = |location: Location| Location::new(typst::utils::hash128(&(location.hash(), salt)));
+
+    let mut rekeyed = Frame::new(frame.size(), frame.kind());
+    if frame.has_baseline() {
+        rekeyed.set_baseline(frame.baseline());
+    }
+
+    for (point, item) in frame.items() {
+        let item = match item {
+            FrameItem::Group(group) => {
+                let mut group = group.clone();
+                group.frame = rekey_frame(&group.frame, salt, offset);
+                if let Some(ref mut parent) = group.parent {
+                    parent.location = rekey(parent.location);
+                }
+                FrameItem::Group(group)
+            }
+            FrameItem::Tag(Tag::Start(content, flags)) => {
+                let mut content = content.clone();
+                if let Some(location) = content.location() {
+                    content.set_location(rekey(location));
+                }
+                FrameItem::Tag(Tag::Start(content, *flags))
+            }
+            FrameItem::Tag(Tag::End(location, key, flags)) => {
+                FrameItem::Tag(Tag::End(rekey(*location), *key, *flags))
+            }
+            FrameItem::Link(Destination::Location(location), size) => {
+                FrameItem::Link(Destination::Location(rekey(*location)), *size)
+            }
+            FrameItem::Link(Destination::Position(position), size) => {
+                let mut position = *position;
+                position.page = position.page.saturating_add(offset);
+                FrameItem::Link(Destination::Position(position), *size)
+            }
+            _ => item.clone(),
+        };
+        rekeyed.push(*point, item);
+    }
+    rekeyed
+}
 
-        let result = timed!("loading file", load());
-        let fingerprint = timed!("hashing file", typst::utils::hash128(&result));
+/// Build the introspector of laid out pages, the way typst does after layout.
+fn introspect_pages(pages: &[Page]) -> Introspector {
+    let mut builder = IntrospectorBuilder::new();
+    builder.pages = pages.len();
+    let mut elems = Vec::new();
+    for (i, page) in pages.iter().enumerate() {
+        builder.page_numberings.push(page.numbering.clone());
+        builder.page_supplements.push(page.supplement.clone());
+        builder.discover_in_frame(
+            &mut elems,
+            &page.frame,
+            NonZeroUsize::new(i + 1).unwrap(),
+            Transform::identity(),
+        );
+    }
+    builder.finalize(elems)
+}
 
-        if mem::replace(&mut self.fingerprint, fingerprint) == fingerprint {
-            if let Some(data) = &self.data {
-                return data.clone();
+/// Decode a base64 `data:` image URL into its file extension and bytes.
+fn decode_data_uri(uri: &str) -> Option<(&'static str, Vec<u8>)> {
+    let (header, payload) = uri.strip_prefix("data:")?.split_once(',')?;
+    let ext = match header.strip_suffix(";base64")? {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        _ => return None,
+    };
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(payload)
+        .ok()?;
+    Some((ext, bytes))
+}
+
+/// Move inlined `data:` images out of the HTML. Each image is named after its
+/// content hash and referenced as `{prefix}{name}` in the returned HTML.
+fn extract_html_images(html: &str, prefix: &str) -> (String, Vec<(String, Vec<u8>)>) {
+    const ATTR: &str = "src=\"";
+
+    let mut out = String::with_capacity(html.len());
+    let mut assets: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find("src=\"data:") {
+        let uri_start = start + ATTR.len();
+        let Some(len) = rest[uri_start..].find('"') else {
+            break;
+        };
+        let uri = &rest[uri_start..uri_start + len];
+        out.push_str(&rest[..uri_start]);
+
+        match decode_data_uri(uri) {
+            Some((ext, bytes)) => {
+                let name = format!("{:032x}.{}", typst::utils::hash128(&bytes), ext);
+                out.push_str(prefix);
+                out.push_str(&name);
+                if !assets.iter().any(|(existing, _)| *existing == name) {
+                    assets.push((name, bytes));
+                }
             }
+            None => out.push_str(uri),
         }
 
-        let prev = self.data.take().and_then(Result::ok);
-        let value = result.and_then(|data| f(data, prev));
-        self.data = Some(value.clone());
-
-        value
+        rest = &rest[uri_start + len..];
     }
+
+    out.push_str(rest);
+    (out, assets)
 }
 
-pub struct SilentDownloadProgress<T>(pub T);
+/// Extract the contents of the `<body>` element.
+fn html_body(html: &str) -> &str {
+    let start = html
+        .find("<body")
+        .and_then(|i| html[i..].find('>').map(|j| i + j + 1))
+        .unwrap_or(0);
+    let end = html.rfind("</body>").unwrap_or(html.len()).max(start);
+    html[start..end].trim()
+}
 
-impl<T: Display> Progress for SilentDownloadProgress<T> {
-    fn print_start(&mut self) {}
-    fn print_progress(&mut self, _state: &DownloadState) {}
-    fn print_finish(&mut self, _state: &DownloadState) {}
+/// Insert extra content at the end of the `<head>` element. Documents that
+/// build their own `<html>` element without a head get one.
+fn inject_html_head(html: &str, head: &str) -> Result<String, CompileErrorNif> {
+    if let Some(i) = html.find("</head>") {
+        return Ok(format!("{}{}\n{}", &html[..i], head, &html[i..]));
+    }
+    match html.find("<html").and_then(|start| tag_end(html, start)) {
+        Some(i) => Ok(format!(
+            "{}\n<head>\n{}\n</head>{}",
+            &html[..i],
+            head,
+            &html[i..]
+        )),
+        None => Err(simple_error(
+            "The head option requires an <html> element in the output.",
+        )),
+    }
 }
 
-fn system_path(
-    project_root: &Path,
-    id: FileId,
-    package_storage: &PackageStorage,
-) -> FileResult<PathBuf> {
-    let buf;
-    let mut root = project_root;
-    if let Some(spec) = id.package() {
-        buf = package_storage.prepare_package(spec, &mut SilentDownloadProgress(&spec))?;
-        root = &buf;
+/// HTML elements that have no closing tag and must be self-closed in XHTML.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Find the end of the tag starting at `start` (the index of its `<`),
+/// skipping over `>` characters inside quoted attribute values.
+fn tag_end(html: &str, start: usize) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html[start..].char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return Some(start + i + 1),
+            _ => {}
+        }
     }
+    None
+}
 
-    id.vpath().resolve(root).ok_or(FileError::AccessDenied)
+/// The lowercase element name of an opening tag such as `<h2 id="x">`.
+fn tag_name(tag: &str) -> Option<String> {
+    let name: String = tag
+        .strip_prefix('<')?
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    (!name.is_empty()).then(|| name.to_ascii_lowercase())
 }
 
-fn read(id: FileId, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Vec<u8>> {
-    read_from_disk(&system_path(project_root, id, package_storage)?)
+/// The value of a double-quoted attribute in an opening tag.
+fn tag_attr<'s>(tag: &'s str, attr: &str) -> Option<&'s str> {
+    let needle = format!(" {}=\"", attr);
+    let start = tag.find(&needle)? + needle.len();
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
 }
 
-fn read_from_disk(path: &Path) -> FileResult<Vec<u8>> {
-    let f = |e| FileError::from_io(e, path);
-    if fs::metadata(path).map_err(f)?.is_dir() {
-        Err(FileError::IsDirectory)
-    } else {
-        fs::read(path).map_err(f)
+/// Strip all tags from an HTML fragment, keeping its (already escaped) text.
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        match tag_end(rest, start) {
+            Some(end) => rest = &rest[end..],
+            None => {
+                rest = "";
+                break;
+            }
+        }
     }
+    text.push_str(rest);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
-fn decode_utf8(buf: &[u8]) -> FileResult<&str> {
-    Ok(std::str::from_utf8(
-        buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buf),
-    )?)
+/// Escape text for use in XML content and attribute values.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
 }
 
-enum Now {
-    #[allow(dead_code)]
-    Fixed(DateTime<Utc>),
-    System(OnceLock<DateTime<Utc>>),
+/// HTML named character references that XML does not predefine, with their
+/// code points.
+const HTML_ENTITIES: &[(&str, u32)] = &[
+    ("nbsp", 0xa0),
+    ("shy", 0xad),
+    ("ensp", 0x2002),
+    ("emsp", 0x2003),
+    ("thinsp", 0x2009),
+    ("zwnj", 0x200c),
+    ("zwj", 0x200d),
+    ("lrm", 0x200e),
+    ("rlm", 0x200f),
+    ("ndash", 0x2013),
+    ("mdash", 0x2014),
+    ("lsquo", 0x2018),
+    ("rsquo", 0x2019),
+    ("sbquo", 0x201a),
+    ("ldquo", 0x201c),
+    ("rdquo", 0x201d),
+    ("bdquo", 0x201e),
+    ("dagger", 0x2020),
+    ("Dagger", 0x2021),
+    ("bull", 0x2022),
+    ("hellip", 0x2026),
+    ("prime", 0x2032),
+    ("Prime", 0x2033),
+    ("laquo", 0xab),
+    ("raquo", 0xbb),
+    ("lsaquo", 0x2039),
+    ("rsaquo", 0x203a),
+    ("copy", 0xa9),
+    ("reg", 0xae),
+    ("trade", 0x2122),
+    ("deg", 0xb0),
+    ("middot", 0xb7),
+    ("sect", 0xa7),
+    ("para", 0xb6),
+    ("times", 0xd7),
+    ("divide", 0xf7),
+    ("minus", 0x2212),
+    ("euro", 0x20ac),
+];
+
+/// Replace HTML named character references with numeric ones, since XHTML
+/// only knows the five XML entities. Unknown names have their `&` escaped.
+fn xhtml_entities(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let name = rest.find(';').map(|len| &rest[..len]).filter(|name| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '#')
+        });
+
+        match name {
+            Some(name)
+                if name.starts_with('#')
+                    || matches!(name, "amp" | "lt" | "gt" | "quot" | "apos") =>
+            {
+                out.push('&');
+            }
+            Some(name) => match HTML_ENTITIES.iter().find(|(entity, _)| *entity == name) {
+                Some((_, code)) => {
+                    out.push_str(&format!("&#x{:x};", code));
+                    rest = &rest[name.len() + 1..];
+                }
+                None => out.push_str("&amp;"),
+            },
+            None => out.push_str("&amp;"),
+        }
+    }
+
+    out.push_str(rest);
+    out
 }
 
-pub struct TypstContext {
-    world: Mutex<SystemWorld>,
-    document: Mutex<Option<PagedDocument>>,
+/// A heading found in the HTML body, used to split chapters and build the
+/// navigation document.
+struct EpubHeading {
+    level: usize,
+    offset: usize,
+    id: String,
+    title: String,
 }
 
-impl UnwindSafe for TypstContext {}
-impl RefUnwindSafe for TypstContext {}
+/// Rewrite an HTML body fragment as XHTML: replace named character references,
+/// self-close void elements and give every heading an `id` so the navigation
+/// document can link to it.
+fn prepare_epub_body(body: &str) -> (String, Vec<EpubHeading>) {
+    let body = xhtml_entities(body);
+    let mut out = String::with_capacity(body.len());
+    let mut headings = Vec::new();
+    let mut rest = body.as_str();
 
-#[rustler::resource_impl]
-impl rustler::Resource for TypstContext {}
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let Some(end) = tag_end(rest, start) else {
+            break;
+        };
+        let tag = &rest[start..end];
+        let name = tag_name(tag).unwrap_or_default();
 
-fn resolve_line_column(
-    span: typst::syntax::Span,
-    byte_offset: usize,
-    world: &SystemWorld,
-) -> (Option<usize>, Option<usize>) {
-    span.id()
-        .and_then(|id| world.source(id).ok())
-        .map(|source| {
-            let lines = source.lines();
-            let line = lines.byte_to_line(byte_offset).map(|l| l + 1);
-            let column = lines.byte_to_column(byte_offset).map(|c| c + 1);
-            (line, column)
-        })
-        .unwrap_or((None, None))
-}
+        let level = match name.as_bytes() {
+            [b'h', n @ b'1'..=b'6'] => Some((n - b'0') as usize),
+            _ => None,
+        };
 
-fn span_to_nif(span: typst::syntax::Span, world: &SystemWorld) -> Option<SpanNif> {
-    span.range().map(|range| {
-        let (line, column) = resolve_line_column(span, range.start, world);
-        SpanNif {
-            start: range.start,
-            end: range.end,
-            line,
-            column,
+        if let Some(level) = level {
+            let offset = out.len();
+            let id = match tag_attr(tag, "id") {
+                Some(id) => {
+                    out.push_str(tag);
+                    id.to_string()
+                }
+                None => {
+                    let id = format!("heading-{}", headings.len() + 1);
+                    out.push_str(&tag[..tag.len() - 1]);
+                    out.push_str(&format!(" id=\"{}\">", id));
+                    id
+                }
+            };
+            let close = format!("</{}>", name);
+            let title = rest[end..]
+                .find(&close)
+                .map(|len| strip_tags(&rest[end..end + len]))
+                .unwrap_or_default();
+            headings.push(EpubHeading {
+                level,
+                offset,
+                id,
+                title,
+            });
+        } else if VOID_ELEMENTS.contains(&name.as_str()) && !tag.ends_with("/>") {
+            out.push_str(tag[..tag.len() - 1].trim_end());
+            out.push_str(" />");
+        } else {
+            out.push_str(tag);
         }
-    })
+
+        rest = &rest[end..];
+    }
+
+    out.push_str(rest);
+    (out, headings)
 }
 
-fn span_to_nif_simple(span: typst::syntax::Span) -> Option<SpanNif> {
-    span.range().map(|range| SpanNif {
-        start: range.start,
-        end: range.end,
-        line: None,
-        column: None,
-    })
+/// One chapter of an EPUB: an XHTML body plus the headings it contains.
+struct EpubChapter {
+    file: String,
+    title: String,
+    body: String,
+    headings: Vec<EpubHeading>,
 }
 
-fn diagnostic_to_nif(d: &SourceDiagnostic, world: &SystemWorld) -> DiagnosticNif {
-    DiagnosticNif {
-        severity: d.severity.into(),
-        message: d.message.to_string(),
-        span: span_to_nif(d.span, world),
-        trace: d
-            .trace
+/// Split an XHTML body into chapters at its top-level headings. Content
+/// before the first heading becomes its own chapter titled `front_title`.
+fn split_epub_chapters(
+    body: &str,
+    headings: Vec<EpubHeading>,
+    front_title: &str,
+) -> Vec<EpubChapter> {
+    let top = headings.iter().map(|h| h.level).min().unwrap_or(1);
+    let mut bounds: Vec<usize> = headings
+        .iter()
+        .filter(|h| h.level == top)
+        .map(|h| h.offset)
+        .collect();
+    if bounds.first() != Some(&0) {
+        bounds.insert(0, 0);
+    }
+
+    let mut chapters: Vec<EpubChapter> = Vec::new();
+    for (i, &start) in bounds.iter().enumerate() {
+        let end = bounds.get(i + 1).copied().unwrap_or(body.len());
+        let raw = &body[start..end];
+        let lead = raw.len() - raw.trim_start().len();
+        let chunk = raw.trim();
+        if chunk.is_empty() {
+            continue;
+        }
+        chapters.push(EpubChapter {
+            file: format!("chapter-{:03}.xhtml", chapters.len() + 1),
+            title: String::new(),
+            body: chunk.to_string(),
+            headings: Vec::new(),
+        });
+        let chapter = chapters.last_mut().unwrap();
+        for heading in headings
             .iter()
-            .map(|item| TraceItemNif {
-                span: span_to_nif(item.span, world),
-                message: item.v.to_string(),
-            })
-            .collect(),
-        hints: d.hints.iter().map(|h| h.to_string()).collect(),
+            .filter(|h| h.offset >= start && h.offset < end)
+        {
+            chapter.headings.push(EpubHeading {
+                level: heading.level,
+                offset: heading.offset - start - lead,
+                id: heading.id.clone(),
+                title: heading.title.clone(),
+            });
+        }
+        chapter.title = match chapter.headings.first() {
+            Some(h) if h.offset == 0 && h.level == top => h.title.clone(),
+            _ => xml_escape(front_title),
+        };
     }
-}
 
-fn diagnostics_to_vec(
-    diagnostics: EcoVec<SourceDiagnostic>,
-    world: &SystemWorld,
-) -> Vec<DiagnosticNif> {
-    diagnostics
+    // Fragment links only resolve within one file, so links to ids in other
+    // chapters must name the file that now holds them.
+    let owners: HashMap<String, String> = chapters
         .iter()
-        .map(|d| diagnostic_to_nif(d, world))
-        .collect()
+        .flat_map(|chapter| {
+            let mut ids = Vec::new();
+            let mut rest = chapter.body.as_str();
+            while let Some(i) = rest.find(" id=\"") {
+                rest = &rest[i + 5..];
+                if let Some(len) = rest.find('"') {
+                    ids.push((rest[..len].to_string(), chapter.file.clone()));
+                }
+            }
+            ids
+        })
+        .collect();
+    for chapter in &mut chapters {
+        let mut out = String::with_capacity(chapter.body.len());
+        let mut rest = chapter.body.as_str();
+        while let Some(i) = rest.find("href=\"#") {
+            out.push_str(&rest[..i + 6]);
+            rest = &rest[i + 6..];
+            let len = rest[1..].find('"').map(|l| l + 1).unwrap_or(rest.len());
+            let id = &rest[1..len];
+            if let Some(file) = owners.get(id).filter(|f| **f != chapter.file) {
+                out.push_str(file);
+            }
+        }
+        out.push_str(rest);
+        chapter.body = out;
+    }
+
+    chapters
 }
 
-fn diagnostics_to_vec_simple(diagnostics: EcoVec<SourceDiagnostic>) -> Vec<DiagnosticNif> {
-    diagnostics
-        .iter()
-        .map(|d| DiagnosticNif {
-            severity: d.severity.into(),
-            message: d.message.to_string(),
-            span: span_to_nif_simple(d.span),
-            trace: d
-                .trace
-                .iter()
-                .map(|item| TraceItemNif {
-                    span: span_to_nif_simple(item.span),
-                    message: item.v.to_string(),
-                })
-                .collect(),
-            hints: d.hints.iter().map(|h| h.to_string()).collect(),
-        })
-        .collect()
+/// Render a navigation list from the chapters' heading outline.
+fn epub_nav_list(chapters: &[EpubChapter]) -> String {
+    let mut nav = String::from("<ol>\n");
+    for chapter in chapters {
+        nav.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>",
+            chapter.file, chapter.title
+        ));
+
+        let top = chapter.headings.first().filter(|h| h.offset == 0);
+        let children: Vec<&EpubHeading> = chapter
+            .headings
+            .iter()
+            .filter(|h| top.is_none_or(|t| h.offset != t.offset))
+            .collect();
+
+        // Nest sub-headings by level, opening and closing lists as the
+        // outline goes deeper or shallower. A heading shallower than the
+        // current list but deeper than the one around it joins the current
+        // list, since a list item may hold only one nested list.
+        let mut stack: Vec<usize> = Vec::new();
+        for heading in children {
+            while let Some(&level) = stack.last() {
+                if level <= heading.level {
+                    break;
+                }
+                if stack.len() >= 2 && stack[stack.len() - 2] >= heading.level {
+                    nav.push_str("</li>\n</ol>\n");
+                    stack.pop();
+                } else {
+                    *stack.last_mut().unwrap() = heading.level;
+                }
+            }
+            if stack.last() == Some(&heading.level) {
+                nav.push_str("</li>\n");
+            } else {
+                nav.push_str("\n<ol>\n");
+                stack.push(heading.level);
+            }
+            nav.push_str(&format!(
+                "<li><a href=\"{}#{}\">{}</a>",
+                chapter.file, heading.id, heading.title
+            ));
+        }
+        for _ in stack {
+            nav.push_str("</li>\n</ol>\n");
+        }
+
+        nav.push_str("</li>\n");
+    }
+    nav.push_str("</ol>");
+    nav
 }
 
-fn simple_error(message: &str) -> CompileErrorNif {
-    CompileErrorNif {
-        diagnostics: vec![DiagnosticNif {
-            severity: SeverityNif::Error,
-            message: message.to_string(),
-            span: None,
-            trace: vec![],
-            hints: vec![],
-        }],
+/// Wrap a body fragment in an XHTML content document.
+fn epub_xhtml(lang: &str, title: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" \
+         xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{lang}\" lang=\"{lang}\">\n\
+         <head>\n\
+         <meta charset=\"utf-8\" />\n\
+         <title>{title}</title>\n\
+         <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\" />\n\
+         </head>\n\
+         <body>\n{body}\n</body>\n\
+         </html>\n"
+    )
+}
+
+/// Media type of an EPUB resource, by file extension.
+fn epub_media_type(name: &str) -> Result<&'static str, String> {
+    match name.rsplit('.').next().unwrap_or_default() {
+        "png" => Ok("image/png"),
+        "jpg" => Ok("image/jpeg"),
+        "gif" => Ok("image/gif"),
+        "webp" => Ok("image/webp"),
+        "svg" => Ok("image/svg+xml"),
+        "otf" => Ok("font/otf"),
+        "ttf" => Ok("font/ttf"),
+        _ => Err(format!("Unsupported EPUB resource type: {}", name)),
     }
 }
 
-/// Parse "1-3,5,7-9" into PageRanges (1-indexed inclusive ranges using NonZeroUsize).
-fn parse_page_ranges(pages: &str, total: usize) -> Result<PageRanges, String> {
-    use std::ops::RangeInclusive;
+/// The fonts embedded in an EPUB: the font files, their stylesheet, and
+/// warnings about document fonts that had to be left out.
+struct EpubFonts {
+    files: Vec<(String, Bytes)>,
+    css: String,
+    warnings: Vec<DiagnosticNif>,
+}
 
-    let mut ranges: Vec<RangeInclusive<Option<NonZeroUsize>>> = Vec::new();
-    for part in pages.split(',') {
-        let part = part.trim();
-        if part.contains('-') {
-            let mut iter = part.splitn(2, '-');
-            let start: usize = iter
-                .next()
-                .unwrap()
-                .trim()
-                .parse()
-                .map_err(|_| format!("Invalid page number in range: {}", part))?;
-            let end: usize = iter
-                .next()
-                .unwrap()
-                .trim()
-                .parse()
-                .map_err(|_| format!("Invalid page number in range: {}", part))?;
-            if start < 1 || end < 1 || start > total || end > total || start > end {
-                return Err(format!("Page range out of bounds: {}", part));
+/// Collect the font files for the requested families and the families the
+/// document uses, along with `@font-face` rules for them. Requested families
+/// must exist; document families that are not installed are skipped, as
+/// typst already fell back for them. Fonts whose license restricts embedding
+/// cannot be embedded, nor can fonts only available inside a collection file:
+/// a requested family with such a font is an error, while a document family
+/// is left out with a warning.
+fn epub_fonts(
+    world: &SystemWorld,
+    requested: &[String],
+    document: &[String],
+) -> Result<EpubFonts, String> {
+    let mut files: Vec<(String, Bytes)> = Vec::new();
+    let mut css = String::new();
+    let mut families: Vec<&String> = Vec::new();
+    let mut warnings = Vec::new();
+
+    let document = document
+        .iter()
+        .filter(|family| !requested.iter().any(|r| r.eq_ignore_ascii_case(family)));
+    for (family, required) in requested
+        .iter()
+        .map(|family| (family, true))
+        .chain(document.map(|family| (family, false)))
+    {
+        let indices: Vec<usize> = world.book.select_family(&family.to_lowercase()).collect();
+        if indices.is_empty() {
+            if required {
+                return Err(format!("Unknown font family: {}", family));
             }
-            ranges.push(NonZeroUsize::new(start)..=NonZeroUsize::new(end));
-        } else {
-            let page: usize = part
-                .parse()
-                .map_err(|_| format!("Invalid page number: {}", part))?;
-            if page < 1 || page > total {
-                return Err(format!("Page number out of bounds: {}", page));
+            continue;
+        }
+
+        let fonts: Vec<Font> = indices.into_iter().filter_map(|i| world.font(i)).collect();
+        let problem = fonts.iter().find_map(|font| {
+            if font.data().starts_with(b"ttcf") {
+                Some(format!(
+                    "Font \"{}\" is part of a font collection and cannot be embedded in an EPUB",
+                    family
+                ))
+            } else if FontPermissionNif::from(font.ttf().permissions())
+                == FontPermissionNif::Restricted
+            {
+                Some(format!(
+                    "Font \"{}\" cannot be embedded: its license restricts embedding (OS/2 fsType: restricted)",
+                    family
+                ))
+            } else {
+                None
+            }
+        });
+        if let Some(message) = problem {
+            if required {
+                return Err(message);
+            }
+            warnings.push(DiagnosticNif {
+                severity: SeverityNif::Warning,
+                message,
+                span: None,
+                trace: vec![],
+                hints: vec!["readers will fall back to one of their own fonts".to_string()],
+            });
+            continue;
+        }
+        families.push(family);
+
+        for font in fonts {
+            let data = font.data();
+            let ext = if data.starts_with(b"OTTO") {
+                "otf"
+            } else {
+                "ttf"
+            };
+            let name = format!("fonts/{:032x}.{}", typst::utils::hash128(data), ext);
+            let variant = font.info().variant;
+            let style = match variant.style {
+                FontStyle::Normal => "normal",
+                FontStyle::Italic => "italic",
+                FontStyle::Oblique => "oblique",
+            };
+            css.push_str(&format!(
+                "@font-face {{\n  font-family: \"{}\";\n  font-style: {};\n  font-weight: {};\n  src: url(\"{}\");\n}}\n",
+                family,
+                style,
+                variant.weight.to_number(),
+                name
+            ));
+            if !files.iter().any(|(existing, _)| *existing == name) {
+                files.push((name, data.clone()));
             }
-            let nz = NonZeroUsize::new(page);
-            ranges.push(nz..=nz);
         }
     }
-    Ok(PageRanges::new(ranges))
+
+    if !families.is_empty() {
+        let list: Vec<String> = families.iter().map(|f| format!("\"{}\"", f)).collect();
+        css.push_str(&format!(
+            "body {{\n  font-family: {};\n}}\n",
+            list.join(", ")
+        ));
+    }
+
+    Ok(EpubFonts {
+        files,
+        css,
+        warnings,
+    })
+}
+
+/// Derive a stable `urn:uuid:` identifier from the book's content.
+fn epub_identifier(title: &str, chapters: &[EpubChapter]) -> String {
+    let bodies: Vec<&str> = chapters.iter().map(|c| c.body.as_str()).collect();
+    let hex = format!("{:032x}", typst::utils::hash128(&(title, bodies)));
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Write the entries to an in-memory ZIP archive, in order. Entries for which
+/// `store` returns true are stored uncompressed.
+fn write_zip(entries: Vec<(String, Vec<u8>)>, store: impl Fn(&str) -> bool) -> ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for (name, data) in entries {
+        let options = if store(&name) { stored } else { deflated };
+        zip.start_file(name, options)?;
+        zip.write_all(&data)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
-fn context_new(opts: ContextOptionsNif) -> ResourceArc<TypstContext> {
+fn context_new(opts: ContextOptionsNif) -> Result<ResourceArc<TypstContext>, CompileErrorNif> {
     let root = PathBuf::from(&opts.root);
     let font_paths: Vec<PathBuf> = opts.font_paths.iter().map(PathBuf::from).collect();
-    let world = SystemWorld::new(root, font_paths, opts.ignore_system_fonts);
-    ResourceArc::new(TypstContext {
+    let features = parse_features(&opts.features).map_err(|e| simple_error(&e))?;
+    let mut world = SystemWorld::new(root, font_paths, opts.ignore_system_fonts, features);
+    world
+        .set_default_styles(&opts.default_styles)
+        .map_err(|e| simple_error(&e))?;
+    Ok(ResourceArc::new(TypstContext {
         world: Mutex::new(world),
         document: Mutex::new(None),
-    })
+        html: Mutex::new(None),
+        redacted: AtomicBool::new(false),
+    }))
 }
 
 #[rustler::nif]
@@ -660,81 +6127,506 @@ fn context_set_markup(ctx: ResourceArc<TypstContext>, markup: String) -> Atom {
     let mut world = ctx.world.lock();
     world.markup = markup;
     world.reset();
-    *ctx.document.lock() = None;
+    ctx.invalidate();
     ok()
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
-fn context_compile(ctx: ResourceArc<TypstContext>) -> Result<CompileResultNif, CompileErrorNif> {
+fn context_compile(
+    ctx: ResourceArc<TypstContext>,
+    target: CompileTargetNif,
+) -> Result<CompileResultNif, CompileErrorNif> {
     let mut world_guard = ctx.world.lock();
     world_guard.reset();
-    let result = typst::compile::<PagedDocument>(&*world_guard);
-    match result.output {
-        Ok(document) => {
-            let page_count = document.pages.len();
-            let warnings = diagnostics_to_vec(result.warnings, &*world_guard);
-            *ctx.document.lock() = Some(document);
-            Ok(CompileResultNif {
-                page_count,
-                warnings,
-            })
+    ctx.invalidate();
+
+    let mut page_count = 0;
+    let mut warnings: EcoVec<SourceDiagnostic> = EcoVec::new();
+
+    if target.includes_paged() {
+        let result = typst::compile::<PagedDocument>(&*world_guard);
+        match result.output {
+            Ok(document) => {
+                page_count = document.pages.len();
+                warnings.extend(result.warnings);
+                *ctx.document.lock() = Some(document);
+            }
+            Err(errors) => {
+                return Err(CompileErrorNif {
+                    diagnostics: diagnostics_to_vec(errors, &world_guard),
+                });
+            }
         }
-        Err(errors) => {
-            let diagnostics = diagnostics_to_vec(errors, &*world_guard);
-            *ctx.document.lock() = None;
-            Err(CompileErrorNif { diagnostics })
+    }
+
+    if target.includes_html() {
+        let result = typst::compile::<HtmlDocument>(&*world_guard);
+        match result.output {
+            Ok(document) => {
+                for warning in &result.warnings {
+                    if !warnings.contains(warning) {
+                        warnings.push(warning.clone());
+                    }
+                }
+                *ctx.html.lock() = Some(CompiledHtml {
+                    document,
+                    warnings: result.warnings,
+                });
+            }
+            Err(errors) => {
+                *ctx.document.lock() = None;
+                return Err(CompileErrorNif {
+                    diagnostics: diagnostics_to_vec(errors, &world_guard),
+                });
+            }
         }
     }
+
+    Ok(CompileResultNif {
+        page_count,
+        warnings: diagnostics_to_vec(warnings, &world_guard),
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn context_render_svg(
     ctx: ResourceArc<TypstContext>,
     page: usize,
+    opts: SvgOptionsNif,
 ) -> Result<String, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
     let doc_guard = ctx.document.lock();
-    let document = doc_guard
-        .as_ref()
-        .ok_or_else(|| simple_error("No compiled document. Call compile() first."))?;
+    let document = export_document(
+        compiled_document(&doc_guard)?,
+        &stamps,
+        opts.impose.as_ref(),
+    )?;
+    Ok(typst_svg::svg(document_page(&document, page)?))
+}
 
-    if page >= document.pages.len() {
-        return Err(simple_error(&format!(
-            "Page index {} out of bounds (document has {} pages)",
-            page,
-            document.pages.len()
-        )));
-    }
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_export_pdf<'a>(
+    env: Env<'a>,
+    ctx: ResourceArc<TypstContext>,
+    opts: PdfOptionsNif,
+) -> Result<PdfResultNif<'a>, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = compiled_document(&doc_guard)?;
+    let (pdf_bytes, fonts, warnings, optimization) = build_pdf(document, &opts, &stamps)?;
+
+    let mut binary = NewBinary::new(env, pdf_bytes.len());
+    binary.as_mut_slice().copy_from_slice(&pdf_bytes);
+    Ok(PdfResultNif {
+        data: binary.into(),
+        warnings,
+        fonts,
+        optimization,
+    })
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_redact(
+    ctx: ResourceArc<TypstContext>,
+    opts: RedactOptionsNif,
+) -> Result<Vec<RedactionNif>, CompileErrorNif> {
+    let mut doc_guard = ctx.document.lock();
+    let document = compiled_document(&doc_guard)?;
+    let (redacted, redactions) = opts.apply(document).map_err(|e| simple_error(&e))?;
+    *doc_guard = Some(redacted);
+    drop(doc_guard);
 
-    Ok(typst_svg::svg(&document.pages[page]))
+    // The HTML document is compiled separately and still holds the text.
+    *ctx.html.lock() = None;
+    ctx.redacted.store(true, Ordering::SeqCst);
+    Ok(redactions)
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
-fn context_export_pdf<'a>(
+fn context_accessibility_report(
+    ctx: ResourceArc<TypstContext>,
+) -> Result<Vec<DiagnosticNif>, CompileErrorNif> {
+    let world = ctx.world.lock();
+    let doc_guard = ctx.document.lock();
+    let document = compiled_document(&doc_guard)?;
+    Ok(accessibility_findings(document, &world))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_split_pdf<'a>(
     env: Env<'a>,
     ctx: ResourceArc<TypstContext>,
+    ranges: Vec<String>,
+    label: Option<String>,
     opts: PdfOptionsNif,
-) -> Result<Binary<'a>, CompileErrorNif> {
+) -> Result<Vec<PdfResultNif<'a>>, CompileErrorNif> {
+    if opts.pages.is_some() {
+        return Err(simple_error(
+            "The pages option cannot be combined with splitting.",
+        ));
+    }
+
+    let mut opts = opts;
+
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
     let doc_guard = ctx.document.lock();
-    let document = doc_guard
-        .as_ref()
-        .ok_or_else(|| simple_error("No compiled document. Call compile() first."))?;
+    let document = compiled_document(&doc_guard)?;
+    let total = document.pages.len();
 
-    let mut pdf_opts = opts.to_pdf_options().map_err(|e| simple_error(&e))?;
+    let parts = match label {
+        Some(ref name) => {
+            let starts = label_pages(document, name).map_err(|e| simple_error(&e))?;
+            split_at_pages(total, &starts)
+        }
+        None => ranges
+            .iter()
+            .map(|range| parse_page_ranges(range, total))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| simple_error(&e))?,
+    };
 
-    if let Some(ref pages_str) = opts.pages {
-        pdf_opts.page_ranges =
-            Some(parse_page_ranges(pages_str, document.pages.len()).map_err(|e| simple_error(&e))?);
+    if parts.is_empty() {
+        return Err(simple_error("No page ranges or label to split at."));
     }
 
-    let pdf_bytes = typst_pdf::pdf(document, &pdf_opts).map_err(|e| CompileErrorNif {
-        diagnostics: diagnostics_to_vec_simple(e),
-    })?;
+    // Every part is a PDF of its own, so each gets its own identifier.
+    let base_id = opts.document_id.clone().unwrap_or_else(|| {
+        let info = &document.info;
+        let authors: Vec<&str> = info.author.iter().map(|a| a.as_str()).collect();
+        format!(
+            "{} {}",
+            info.title.as_deref().unwrap_or_default(),
+            authors.join(", ")
+        )
+    });
+
+    let mut results = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        opts.document_id = Some(format!("{}#part-{}", base_id, i + 1));
+        let part = select_pages(document, part);
+        let (pdf_bytes, fonts, warnings, optimization) = build_pdf(&part, &opts, &stamps)?;
+        let mut binary = NewBinary::new(env, pdf_bytes.len());
+        binary.as_mut_slice().copy_from_slice(&pdf_bytes);
+        results.push(PdfResultNif {
+            data: binary.into(),
+            warnings,
+            fonts,
+            optimization,
+        });
+    }
+    Ok(results)
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn merge_pdf<'a>(
+    env: Env<'a>,
+    contexts: Vec<ResourceArc<TypstContext>>,
+    opts: PdfOptionsNif,
+) -> Result<PdfResultNif<'a>, CompileErrorNif> {
+    // Clone each document under its own lock so merging never holds two
+    // contexts' locks at once.
+    let mut documents = Vec::with_capacity(contexts.len());
+    for (i, ctx) in contexts.iter().enumerate() {
+        let doc_guard = ctx.document.lock();
+        let document = doc_guard.as_ref().ok_or_else(|| {
+            simple_error(&format!(
+                "No compiled document in context {}. Call compile() first.",
+                i
+            ))
+        })?;
+        documents.push(document.clone());
+    }
+
+    let document =
+        merge_documents(documents).ok_or_else(|| simple_error("No documents to merge."))?;
+    // Overlays are laid out with the first context's fonts and library.
+    let stamps = match contexts.first() {
+        Some(ctx) => layout_overlays(ctx, &opts.overlays)?,
+        None => vec![],
+    };
+    let (pdf_bytes, fonts, warnings, optimization) = build_pdf(&document, &opts, &stamps)?;
 
     let mut binary = NewBinary::new(env, pdf_bytes.len());
     binary.as_mut_slice().copy_from_slice(&pdf_bytes);
+    Ok(PdfResultNif {
+        data: binary.into(),
+        warnings,
+        fonts,
+        optimization,
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn context_write_pdf(
+    ctx: ResourceArc<TypstContext>,
+    path: String,
+    opts: PdfOptionsNif,
+) -> Result<WriteResultNif, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = compiled_document(&doc_guard)?;
+    let (pdf_bytes, _fonts, warnings, _optimization) = build_pdf(document, &opts, &stamps)?;
+    drop(doc_guard);
+
+    write_output(&path, &pdf_bytes, warnings)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn context_write_svg(
+    ctx: ResourceArc<TypstContext>,
+    path: String,
+    page: usize,
+    opts: SvgOptionsNif,
+) -> Result<WriteResultNif, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = export_document(
+        compiled_document(&doc_guard)?,
+        &stamps,
+        opts.impose.as_ref(),
+    )?;
+    let svg = typst_svg::svg(document_page(&document, page)?);
+    drop(document);
+    drop(doc_guard);
+
+    write_output(&path, svg.as_bytes(), vec![])
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_render_raster<'a>(
+    env: Env<'a>,
+    ctx: ResourceArc<TypstContext>,
+    page: usize,
+    opts: RasterOptionsNif,
+) -> Result<Binary<'a>, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = export_document(
+        compiled_document(&doc_guard)?,
+        &stamps,
+        opts.impose.as_ref(),
+    )?;
+    let data = render_raster(document_page(&document, page)?, &opts)?;
+    drop(document);
+    drop(doc_guard);
+
+    let mut binary = NewBinary::new(env, data.len());
+    binary.as_mut_slice().copy_from_slice(&data);
+    Ok(binary.into())
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_render_region_svg(
+    ctx: ResourceArc<TypstContext>,
+    page: usize,
+    region: (f64, f64, f64, f64),
+    opts: SvgOptionsNif,
+) -> Result<String, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = export_document(
+        compiled_document(&doc_guard)?,
+        &stamps,
+        opts.impose.as_ref(),
+    )?;
+    let cropped = page_region(document_page(&document, page)?, region)?;
+    Ok(typst_svg::svg(&cropped))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_render_region_raster<'a>(
+    env: Env<'a>,
+    ctx: ResourceArc<TypstContext>,
+    page: usize,
+    region: (f64, f64, f64, f64),
+    opts: RasterOptionsNif,
+) -> Result<Binary<'a>, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = export_document(
+        compiled_document(&doc_guard)?,
+        &stamps,
+        opts.impose.as_ref(),
+    )?;
+    let cropped = page_region(document_page(&document, page)?, region)?;
+    drop(document);
+    drop(doc_guard);
+
+    let data = render_raster(&cropped, &opts)?;
+    let mut binary = NewBinary::new(env, data.len());
+    binary.as_mut_slice().copy_from_slice(&data);
     Ok(binary.into())
 }
 
+#[rustler::nif(schedule = "DirtyIo")]
+fn context_write_raster(
+    ctx: ResourceArc<TypstContext>,
+    path: String,
+    page: usize,
+    opts: RasterOptionsNif,
+) -> Result<WriteResultNif, CompileErrorNif> {
+    let stamps = layout_overlays(&ctx, &opts.overlays)?;
+    let doc_guard = ctx.document.lock();
+    let document = export_document(
+        compiled_document(&doc_guard)?,
+        &stamps,
+        opts.impose.as_ref(),
+    )?;
+    let data = render_raster(document_page(&document, page)?, &opts)?;
+    drop(document);
+    drop(doc_guard);
+
+    write_output(&path, &data, vec![])
+}
+
+impl BundleOutputNif {
+    /// The overlays of the output, taken from the options for its format.
+    fn overlays(&self) -> &[OverlayNif] {
+        let overlays = match self.format {
+            BundleFormatNif::Pdf => self.pdf_options.as_ref().map(|opts| &opts.overlays),
+            BundleFormatNif::Svg => self.svg_options.as_ref().map(|opts| &opts.overlays),
+            BundleFormatNif::Raster(_) => self.raster_options.as_ref().map(|opts| &opts.overlays),
+        };
+        match overlays {
+            Some(overlays) => overlays,
+            None => &[],
+        }
+    }
+
+    /// The imposition of a page output, taken from its SVG or raster options.
+    fn impose(&self) -> Option<&ImpositionNif> {
+        match self.format {
+            BundleFormatNif::Pdf => None,
+            BundleFormatNif::Svg => self.svg_options.as_ref()?.impose.as_ref(),
+            BundleFormatNif::Raster(_) => self.raster_options.as_ref()?.impose.as_ref(),
+        }
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_export_bundle<'a>(
+    env: Env<'a>,
+    ctx: ResourceArc<TypstContext>,
+    outputs: Vec<BundleOutputNif>,
+    archive: bool,
+) -> Result<BundleResultNif<'a>, CompileErrorNif> {
+    let stamps = outputs
+        .iter()
+        .map(|output| layout_overlays(&ctx, output.overlays()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let doc_guard = ctx.document.lock();
+    let document = compiled_document(&doc_guard)?;
+
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut push = |name: String, data: Vec<u8>| {
+        if files.iter().any(|(existing, _)| *existing == name) {
+            return Err(simple_error(&format!("Duplicate output name: {}", name)));
+        }
+        files.push((name, data));
+        Ok(())
+    };
+
+    for (output, stamps) in outputs.iter().zip(&stamps) {
+        if output.format == BundleFormatNif::Pdf {
+            let opts = output
+                .pdf_options
+                .as_ref()
+                .ok_or_else(|| simple_error("PDF outputs require pdf_options"))?;
+            let (data, _fonts, pdf_warnings, _optimization) = build_pdf(document, opts, stamps)?;
+            warnings.extend(pdf_warnings);
+            let name = output.name.clone().unwrap_or_else(|| "document.pdf".into());
+            push(name, data)?;
+            continue;
+        }
+
+        if let (BundleFormatNif::Raster(format), Some(raster)) =
+            (output.format, &output.raster_options)
+        {
+            if raster.format != format {
+                return Err(simple_error(&format!(
+                    "Output format {} does not match its raster_options format {}",
+                    format.name(),
+                    raster.format.name()
+                )));
+            }
+        }
+
+        let document = export_document(document, stamps, output.impose())?;
+        let ext = match output.format {
+            BundleFormatNif::Raster(format) => format.extension(),
+            _ => "svg",
+        };
+        let template = output
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("page-{{page}}.{}", ext));
+        let pages: Vec<usize> = match output.pages {
+            Some(ref pages) => {
+                let ranges =
+                    parse_page_ranges(pages, document.pages.len()).map_err(|e| simple_error(&e))?;
+                (0..document.pages.len())
+                    .filter(|&i| ranges.includes_page(NonZeroUsize::new(i + 1).unwrap()))
+                    .collect()
+            }
+            None => (0..document.pages.len()).collect(),
+        };
+        if pages.len() > 1 && !template.contains("{page}") {
+            return Err(simple_error(&format!(
+                "Output name {:?} must contain {{page}} when exporting several pages",
+                template
+            )));
+        }
+
+        for i in pages {
+            let page = &document.pages[i];
+            let data = match output.raster_options {
+                Some(ref raster) if output.format != BundleFormatNif::Svg => {
+                    render_raster(page, raster)?
+                }
+                None if output.format != BundleFormatNif::Svg => {
+                    return Err(simple_error("Raster outputs require raster_options"));
+                }
+                _ => typst_svg::svg(page).into_bytes(),
+            };
+            push(template.replace("{page}", &(i + 1).to_string()), data)?;
+        }
+    }
+
+    drop(doc_guard);
+
+    if archive {
+        let data = write_zip(files, |name| {
+            [".png", ".jpg", ".webp"]
+                .iter()
+                .any(|ext| name.ends_with(ext))
+        })
+        .map_err(|e| simple_error(&format!("Failed to write ZIP archive: {}", e)))?;
+        let mut binary = NewBinary::new(env, data.len());
+        binary.as_mut_slice().copy_from_slice(&data);
+        return Ok(BundleResultNif {
+            files: HashMap::new(),
+            archive: Some(binary.into()),
+            warnings,
+        });
+    }
+
+    let files = files
+        .into_iter()
+        .map(|(name, data)| {
+            let mut binary = NewBinary::new(env, data.len());
+            binary.as_mut_slice().copy_from_slice(&data);
+            (name, binary.into())
+        })
+        .collect();
+
+    Ok(BundleResultNif {
+        files,
+        archive: None,
+        warnings,
+    })
+}
+
 #[rustler::nif]
 fn context_font_families(ctx: ResourceArc<TypstContext>) -> Vec<String> {
     let world = ctx.world.lock();
@@ -749,7 +6641,7 @@ fn context_font_families(ctx: ResourceArc<TypstContext>) -> Vec<String> {
 fn context_set_virtual_file(ctx: ResourceArc<TypstContext>, path: String, content: String) -> Atom {
     let mut world = ctx.world.lock();
     world.virtual_files.insert(path, content.into_bytes());
-    *ctx.document.lock() = None;
+    ctx.invalidate();
     ok()
 }
 
@@ -765,6 +6657,7 @@ fn context_append_virtual_file(
         .entry(path)
         .or_default()
         .extend_from_slice(chunk.as_bytes());
+    ctx.invalidate();
     ok()
 }
 
@@ -772,15 +6665,41 @@ fn context_append_virtual_file(
 fn context_clear_virtual_file(ctx: ResourceArc<TypstContext>, path: String) -> Atom {
     let mut world = ctx.world.lock();
     world.virtual_files.remove(&path);
-    *ctx.document.lock() = None;
+    world.plugins.remove(&path);
+    ctx.invalidate();
     ok()
 }
 
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_set_plugin(
+    ctx: ResourceArc<TypstContext>,
+    path: String,
+    wasm: Binary,
+) -> Result<Atom, CompileErrorNif> {
+    let mut world = ctx.world.lock();
+    let wasm = Bytes::new(wasm.as_slice().to_vec());
+    let mut scope = Scope::new();
+    scope.define("wasm", wasm.clone());
+    if let Err(errors) = world.eval_code("plugin(wasm)", scope) {
+        let message = errors.first().map_or("", |e| e.message.as_str());
+        return Err(simple_error(&format!(
+            "Invalid WebAssembly plugin {}: {}",
+            path, message
+        )));
+    }
+
+    world.virtual_files.remove(&path);
+    world.plugins.insert(path, wasm);
+    ctx.invalidate();
+    Ok(ok())
+}
+
 #[rustler::nif]
 fn context_set_input(ctx: ResourceArc<TypstContext>, key: String, value: String) -> Atom {
     let mut world = ctx.world.lock();
     world.inputs.insert(key, value);
     world.rebuild_library();
+    ctx.invalidate();
     ok()
 }
 
@@ -789,25 +6708,300 @@ fn context_set_inputs(ctx: ResourceArc<TypstContext>, inputs: HashMap<String, St
     let mut world = ctx.world.lock();
     world.inputs = inputs;
     world.rebuild_library();
+    ctx.invalidate();
+    ok()
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_set_global(
+    ctx: ResourceArc<TypstContext>,
+    name: String,
+    code: String,
+) -> Result<Atom, CompileErrorNif> {
+    check_global_name(&name)?;
+    let mut world = ctx.world.lock();
+    let value = world
+        .eval_code(&code, Scope::new())
+        .map_err(|errors| CompileErrorNif {
+            diagnostics: diagnostics_to_vec(errors, &world),
+        })?;
+    world.globals.insert(name, value);
+    world.rebuild_library();
+    ctx.invalidate();
+    Ok(ok())
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_set_global_module(
+    ctx: ResourceArc<TypstContext>,
+    name: Option<String>,
+    source: String,
+) -> Result<Atom, CompileErrorNif> {
+    if let Some(name) = &name {
+        check_global_name(name)?;
+    }
+    let mut world = ctx.world.lock();
+    let module_name = name.as_deref().unwrap_or("globals");
+    let module = world
+        .eval_module(module_name, source)
+        .map_err(|errors| CompileErrorNif {
+            diagnostics: diagnostics_to_vec(errors, &world),
+        })?;
+
+    match name {
+        Some(name) => {
+            world.globals.insert(name, Value::Module(module));
+        }
+        None => {
+            for (key, binding) in module.scope().iter() {
+                world
+                    .globals
+                    .insert(key.to_string(), binding.read().clone());
+            }
+        }
+    }
+    world.rebuild_library();
+    ctx.invalidate();
+    Ok(ok())
+}
+
+/// Globals are referenced by name from markup, so the name must be a valid
+/// Typst identifier.
+fn check_global_name(name: &str) -> Result<(), CompileErrorNif> {
+    if typst::syntax::is_ident(name) {
+        Ok(())
+    } else {
+        Err(simple_error(&format!(
+            "Invalid global name {:?}: not a Typst identifier",
+            name
+        )))
+    }
+}
+
+#[rustler::nif]
+fn context_clear_globals(ctx: ResourceArc<TypstContext>) -> Atom {
+    let mut world = ctx.world.lock();
+    world.globals.clear();
+    world.global_sources.clear();
+    world.rebuild_library();
+    ctx.invalidate();
     ok()
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
-fn context_export_html(ctx: ResourceArc<TypstContext>) -> Result<String, CompileErrorNif> {
+fn context_export_html<'a>(
+    env: Env<'a>,
+    ctx: ResourceArc<TypstContext>,
+    opts: HtmlOptionsNif,
+) -> Result<HtmlResultNif<'a>, CompileErrorNif> {
+    if opts.body_only && opts.head.is_some() {
+        return Err(simple_error(
+            "The head option cannot be combined with body_only.",
+        ));
+    }
+    ctx.check_unredacted("HTML")?;
+
     let mut world_guard = ctx.world.lock();
-    world_guard.reset();
-    let result = typst::compile::<HtmlDocument>(&*world_guard);
-    match result.output {
-        Ok(html_doc) => match typst_html::html(&html_doc) {
-            Ok(html_string) => Ok(html_string),
-            Err(errors) => Err(CompileErrorNif {
-                diagnostics: diagnostics_to_vec(errors, &*world_guard),
-            }),
-        },
-        Err(errors) => Err(CompileErrorNif {
-            diagnostics: diagnostics_to_vec(errors, &*world_guard),
-        }),
+    let mut html_guard = ctx.html.lock();
+
+    let compiled = CompiledHtml::get_or_compile(&mut html_guard, &mut world_guard)?;
+    let mut data = typst_html::html(&compiled.document).map_err(|errors| CompileErrorNif {
+        diagnostics: diagnostics_to_vec(errors, &world_guard),
+    })?;
+
+    let mut assets = HashMap::new();
+    if opts.images == HtmlImagesNif::Assets {
+        let (html, images) = extract_html_images(&data, &opts.asset_prefix);
+        data = html;
+        for (name, bytes) in images {
+            let mut binary = NewBinary::new(env, bytes.len());
+            binary.as_mut_slice().copy_from_slice(&bytes);
+            assets.insert(name, binary.into());
+        }
+    }
+
+    if let Some(ref head) = opts.head {
+        data = inject_html_head(&data, head)?;
+    }
+
+    if opts.body_only {
+        data = html_body(&data).to_string();
+    }
+
+    Ok(HtmlResultNif {
+        data,
+        warnings: diagnostics_to_vec(compiled.warnings.clone(), &world_guard),
+        assets,
+    })
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn context_export_epub<'a>(
+    env: Env<'a>,
+    ctx: ResourceArc<TypstContext>,
+    opts: EpubOptionsNif,
+) -> Result<EpubResultNif<'a>, CompileErrorNif> {
+    ctx.check_unredacted("EPUB")?;
+    let mut world_guard = ctx.world.lock();
+    let mut html_guard = ctx.html.lock();
+
+    let compiled = CompiledHtml::get_or_compile(&mut html_guard, &mut world_guard)?;
+    let html = typst_html::html(&compiled.document).map_err(|errors| CompileErrorNif {
+        diagnostics: diagnostics_to_vec(errors, &world_guard),
+    })?;
+
+    let info = &compiled.document.info;
+    let title = opts
+        .title
+        .clone()
+        .or_else(|| info.title.as_ref().map(|t| t.to_string()))
+        .unwrap_or_else(|| "Untitled".to_string());
+    let authors: Vec<String> = if opts.authors.is_empty() {
+        info.author.iter().map(|a| a.to_string()).collect()
+    } else {
+        opts.authors.clone()
+    };
+    let lang = opts
+        .language
+        .clone()
+        .or_else(|| {
+            let start = html.find("<html")?;
+            let end = tag_end(&html, start)?;
+            tag_attr(&html[start..end], "lang").map(str::to_string)
+        })
+        .unwrap_or_else(|| "en".to_string());
+
+    let (body, images) = extract_html_images(html_body(&html), "images/");
+    let (body, headings) = prepare_epub_body(&body);
+    let chapters = split_epub_chapters(&body, headings, &title);
+
+    let document_fonts =
+        world_guard
+            .document_font_families()
+            .map_err(|errors| CompileErrorNif {
+                diagnostics: diagnostics_to_vec(errors, &world_guard),
+            })?;
+    let fonts =
+        epub_fonts(&world_guard, &opts.fonts, &document_fonts).map_err(|e| simple_error(&e))?;
+    let mut css = fonts.css;
+    if let Some(ref stylesheet) = opts.stylesheet {
+        css.push_str(stylesheet);
+        css.push('\n');
+    }
+
+    let identifier = opts
+        .identifier
+        .clone()
+        .unwrap_or_else(|| epub_identifier(&title, &chapters));
+    let modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ");
+
+    let mut metadata = format!(
+        "<dc:identifier id=\"uid\">{}</dc:identifier>\n\
+         <dc:title>{}</dc:title>\n\
+         <dc:language>{}</dc:language>\n",
+        xml_escape(&identifier),
+        xml_escape(&title),
+        xml_escape(&lang)
+    );
+    for author in &authors {
+        metadata.push_str(&format!(
+            "<dc:creator>{}</dc:creator>\n",
+            xml_escape(author)
+        ));
+    }
+    metadata.push_str(&format!(
+        "<meta property=\"dcterms:modified\">{}</meta>\n",
+        modified
+    ));
+
+    let mut manifest = String::from(
+        "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />\n\
+         <item id=\"css\" href=\"style.css\" media-type=\"text/css\" />\n",
+    );
+    let mut spine = String::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        manifest.push_str(&format!(
+            "<item id=\"chapter-{}\" href=\"{}\" media-type=\"application/xhtml+xml\" />\n",
+            i + 1,
+            chapter.file
+        ));
+        spine.push_str(&format!("<itemref idref=\"chapter-{}\" />\n", i + 1));
+    }
+    let resources = images
+        .iter()
+        .map(|(name, _)| format!("images/{}", name))
+        .chain(fonts.files.iter().map(|(name, _)| name.clone()));
+    for (i, href) in resources.enumerate() {
+        manifest.push_str(&format!(
+            "<item id=\"res-{}\" href=\"{}\" media-type=\"{}\" />\n",
+            i + 1,
+            href,
+            epub_media_type(&href).map_err(|e| simple_error(&e))?
+        ));
+    }
+
+    let package = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" \
+         unique-identifier=\"uid\" xml:lang=\"{}\">\n\
+         <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}</metadata>\n\
+         <manifest>\n{}</manifest>\n\
+         <spine>\n{}</spine>\n\
+         </package>\n",
+        xml_escape(&lang),
+        metadata,
+        manifest,
+        spine
+    );
+    let container = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+         <rootfiles>\n\
+         <rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\" />\n\
+         </rootfiles>\n\
+         </container>\n";
+    let nav = epub_xhtml(
+        &xml_escape(&lang),
+        &xml_escape(&title),
+        &format!(
+            "<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n{}\n</nav>",
+            xml_escape(&title),
+            epub_nav_list(&chapters)
+        ),
+    );
+
+    // The `mimetype` entry must come first and uncompressed, as the OCF spec
+    // requires.
+    let mut entries: Vec<(String, Vec<u8>)> = vec![
+        ("mimetype".to_string(), b"application/epub+zip".to_vec()),
+        ("META-INF/container.xml".to_string(), container.into()),
+        ("OEBPS/content.opf".to_string(), package.into_bytes()),
+        ("OEBPS/nav.xhtml".to_string(), nav.into_bytes()),
+        ("OEBPS/style.css".to_string(), css.into_bytes()),
+    ];
+    for chapter in &chapters {
+        let xhtml = epub_xhtml(&xml_escape(&lang), &chapter.title, &chapter.body);
+        entries.push((format!("OEBPS/{}", chapter.file), xhtml.into_bytes()));
+    }
+    for (name, bytes) in images {
+        entries.push((format!("OEBPS/images/{}", name), bytes));
     }
+    for (name, bytes) in fonts.files {
+        entries.push((format!("OEBPS/{}", name), bytes.to_vec()));
+    }
+
+    let data = write_zip(entries, |name| name == "mimetype")
+        .map_err(|e| simple_error(&format!("Failed to write EPUB: {}", e)))?;
+
+    let mut binary = NewBinary::new(env, data.len());
+    binary.as_mut_slice().copy_from_slice(&data);
+
+    let mut warnings = diagnostics_to_vec(compiled.warnings.clone(), &world_guard);
+    warnings.extend(fonts.warnings);
+
+    Ok(EpubResultNif {
+        data: binary.into(),
+        warnings,
+    })
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
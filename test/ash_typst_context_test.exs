@@ -32,130 +32,1420 @@ defmodule AshTypst.ContextTest do
     end
   end
 
+  describe "features" do
+    test "creates a context with extra features" do
+      assert {:ok, ctx} = Context.new(features: [:html, :a11y_extras])
+      :ok = Context.set_markup(ctx, @test_markup)
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "html export requires the :html feature" do
+      {:ok, ctx} = Context.new(features: [])
+      :ok = Context.set_markup(ctx, "= Hello HTML")
+      assert {:error, %AshTypst.CompileError{}} = Context.export_html(ctx)
+    end
+
+    test "unknown feature returns error" do
+      assert {:error, %AshTypst.CompileError{diagnostics: [diag]}} =
+               Context.new(features: [:teleportation])
+
+      assert diag.message =~ "Unknown Typst feature: teleportation"
+    end
+  end
+
+  describe "default_styles" do
+    test "applies house style without a preamble" do
+      {:ok, plain} = Context.new()
+      {:ok, styled} = Context.new(default_styles: [paper: "a5", margin: 20, lang: "de"])
+
+      for ctx <- [plain, styled] do
+        :ok = Context.set_markup(ctx, @test_markup)
+        {:ok, _} = Context.compile(ctx)
+      end
+
+      {:ok, plain_svg} = Context.render_svg(plain)
+      {:ok, styled_svg} = Context.render_svg(styled)
+      assert plain_svg != styled_svg
+    end
+
+    test "accepts a struct" do
+      styles = %AshTypst.DefaultStyles{justify: true, region: "CH", lang: "de"}
+      assert {:ok, ctx} = Context.new(%AshTypst.Context.Options{default_styles: styles})
+      :ok = Context.set_markup(ctx, @test_markup)
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "unknown paper size returns error" do
+      assert {:error, %AshTypst.CompileError{diagnostics: [diag]}} =
+               Context.new(default_styles: [paper: "napkin"])
+
+      assert diag.message =~ "paper"
+    end
+
+    test "unknown font family returns error" do
+      assert {:error, %AshTypst.CompileError{diagnostics: [diag]}} =
+               Context.new(default_styles: [font: ["No Such Font Family"]])
+
+      assert diag.message =~ "No Such Font Family"
+    end
+
+    test "invalid language returns error" do
+      assert {:error, %AshTypst.CompileError{}} = Context.new(default_styles: [lang: "english"])
+    end
+  end
+
   describe "compile + render_svg" do
     test "basic lifecycle: new -> set_markup -> compile -> render_svg" do
       {:ok, ctx} = Context.new()
       :ok = Context.set_markup(ctx, @test_markup)
 
-      assert {:ok, %AshTypst.CompileResult{page_count: 1, warnings: warnings}} =
-               Context.compile(ctx)
+      assert {:ok, %AshTypst.CompileResult{page_count: 1, warnings: warnings}} =
+               Context.compile(ctx)
+
+      assert is_list(warnings)
+      assert {:ok, svg} = Context.render_svg(ctx)
+      assert String.contains?(svg, "<svg")
+      assert String.length(svg) > 1000
+    end
+
+    test "compile returns page count" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @multipage_markup)
+      assert {:ok, %AshTypst.CompileResult{page_count: 3}} = Context.compile(ctx)
+    end
+
+    test "render specific page" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, svg0} = Context.render_svg(ctx, page: 0)
+      assert {:ok, svg2} = Context.render_svg(ctx, page: 2)
+      assert String.contains?(svg0, "<svg")
+      assert String.contains?(svg2, "<svg")
+      assert svg0 != svg2
+    end
+
+    test "render out-of-bounds page returns error" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [_ | _]}} =
+               Context.render_svg(ctx, page: 99)
+    end
+
+    test "render without compile returns error" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [_ | _]}} =
+               Context.render_svg(ctx)
+    end
+
+    test "invalid markup returns compile error with diagnostics" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @invalid_markup)
+      assert {:error, %AshTypst.CompileError{diagnostics: diagnostics}} = Context.compile(ctx)
+      assert diagnostics != []
+
+      [diag | _] = diagnostics
+      assert diag.severity == :error
+      assert is_binary(diag.message)
+    end
+
+    test "re-set markup + recompile produces different SVG" do
+      {:ok, ctx} = Context.new()
+
+      :ok = Context.set_markup(ctx, "= First")
+      {:ok, _} = Context.compile(ctx)
+      {:ok, svg1} = Context.render_svg(ctx)
+
+      :ok = Context.set_markup(ctx, "= Second")
+      {:ok, _} = Context.compile(ctx)
+      {:ok, svg2} = Context.render_svg(ctx)
+
+      assert svg1 != svg2
+    end
+  end
+
+  describe "export_pdf" do
+    test "returns proper PDF binary" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _rest::binary>>, warnings: warnings}} =
+               Context.export_pdf(ctx)
+
+      assert is_list(warnings)
+    end
+
+    test "with page range produces output" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>> = full_pdf}} =
+               Context.export_pdf(ctx)
+
+      # typst-pdf tagged PDF bug may cause page range export to fail
+      case Context.export_pdf(ctx, pages: "1") do
+        {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>> = partial_pdf}} ->
+          assert byte_size(partial_pdf) < byte_size(full_pdf)
+
+        {:error, %AshTypst.CompileError{}} ->
+          :ok
+      end
+    end
+
+    test "with standards" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup_with_date)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>>}} =
+               Context.export_pdf(ctx, pdf_standards: [:pdf_a_2b])
+    end
+
+    test "with document_id" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>>}} =
+               Context.export_pdf(ctx, document_id: "test-42")
+    end
+
+    test "without compile returns error" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+
+      assert {:error, %AshTypst.CompileError{}} = Context.export_pdf(ctx)
+    end
+  end
+
+  describe "export_pdf font embedding" do
+    test "reports embedding permissions of used fonts" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{fonts: [_ | _] = fonts}} = Context.export_pdf(ctx)
+
+      assert Enum.all?(fonts, fn %AshTypst.FontEmbedding{} = font ->
+               is_binary(font.family) and is_boolean(font.subsetting_allowed) and
+                 font.permission in [
+                   :installable,
+                   :restricted,
+                   :preview_and_print,
+                   :editable,
+                   :unknown
+                 ]
+             end)
+    end
+
+    test ":error policy exports fonts that permit embedding" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>>, warnings: []}} =
+               Context.export_pdf(ctx, font_embedding: :error)
+    end
+
+    # Tuffy (public domain), subset and with its OS/2 fsType set to restricted
+    test ":error policy fails on fonts that forbid embedding" do
+      {:ok, ctx} = Context.new(font_paths: ["test/fixtures/fonts"], ignore_system_fonts: true)
+      :ok = Context.set_markup(ctx, ~s(#set text(font: "Tuffy")\nRestricted))
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx, font_embedding: :error)
+
+      assert diagnostic.severity == :error
+      assert diagnostic.message =~ ~s(Font "Tuffy" cannot be embedded)
+
+      assert {:ok, %AshTypst.PDFResult{warnings: [warning], fonts: [font]}} =
+               Context.export_pdf(ctx)
+
+      assert warning.severity == :warning
+      assert %AshTypst.FontEmbedding{family: "Tuffy", permission: :restricted} = font
+    end
+
+    test ":ignore policy skips the check" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_markup(ctx, @test_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{fonts: []}} =
+               Context.export_pdf(ctx, font_embedding: :ignore)
+    end
+  end
+
+  describe "render_raster" do
+    setup do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "#set page(width: 200pt, height: 100pt)\n= Raster")
+      {:ok, _} = Context.compile(ctx)
+      %{ctx: ctx}
+    end
+
+    test "renders PNG at pixel_per_pt by default", %{ctx: ctx} do
+      assert {:ok, png} = Context.render_raster(ctx, pixel_per_pt: 1)
+      assert png_size(png) == {200, 100}
+    end
+
+    test "encodes JPEG and WebP", %{ctx: ctx} do
+      assert {:ok, <<0xFF, 0xD8, _::binary>>} = Context.render_raster(ctx, format: :jpeg)
+
+      assert {:ok, <<"RIFF", _::binary-size(4), "WEBP", _::binary>>} =
+               Context.render_raster(ctx, format: :webp)
+    end
+
+    test "sizes by width or height, keeping the aspect ratio", %{ctx: ctx} do
+      assert {:ok, png} = Context.render_raster(ctx, width: 400)
+      assert png_size(png) == {400, 200}
+
+      assert {:ok, png} = Context.render_raster(ctx, height: 50)
+      assert png_size(png) == {100, 50}
+    end
+
+    test "fits into a box", %{ctx: ctx} do
+      assert {:ok, png} = Context.render_raster(ctx, width: 100, height: 100)
+      assert png_size(png) == {100, 50}
+
+      assert {:ok, png} = Context.render_raster(ctx, width: 100, height: 100, fit: :cover)
+      assert png_size(png) == {100, 100}
+
+      assert {:ok, png} = Context.render_raster(ctx, width: 100, height: 100, fit: :stretch)
+      assert png_size(png) == {100, 100}
+    end
+
+    test "controls the background", %{ctx: ctx} do
+      assert {:ok, _} = Context.render_raster(ctx, background: :transparent)
+      assert {:ok, _} = Context.render_raster(ctx, format: :jpeg, background: "#336699")
+    end
+
+    test "reports invalid options as compile errors", %{ctx: ctx} do
+      for opts <- [
+            [format: :jpeg, background: :transparent],
+            [format: :jpeg, quality: 0],
+            [quality: 80],
+            [format: :webp, quality: 80],
+            [background: "not a colour"],
+            [width: 0],
+            [pixel_per_pt: 1000]
+          ] do
+        assert {:error, %AshTypst.CompileError{diagnostics: [_]}} =
+                 Context.render_raster(ctx, opts)
+      end
+    end
+  end
+
+  describe "render_region" do
+    setup do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "#set page(width: 200pt, height: 100pt)\n= Region")
+      {:ok, _} = Context.compile(ctx)
+      %{ctx: ctx}
+    end
+
+    test "renders a region as SVG sized to the region", %{ctx: ctx} do
+      assert {:ok, svg} = Context.render_region(ctx, {10, 20, 50, 40}, format: :svg)
+      assert svg =~ "<svg"
+      assert svg =~ ~r/viewBox="0 0 50 40"/
+    end
+
+    test "rasterizes a region at the requested resolution", %{ctx: ctx} do
+      assert {:ok, png} = Context.render_region(ctx, {0, 0, 50, 25}, pixel_per_pt: 8)
+      assert png_size(png) == {400, 200}
+
+      assert {:ok, <<0xFF, 0xD8, _::binary>>} =
+               Context.render_region(ctx, {0, 0, 50, 25}, format: :jpeg, width: 100)
+    end
+
+    test "rejects regions outside the page", %{ctx: ctx} do
+      for region <- [{150, 0, 100, 50}, {0, 0, 0, 10}, {-1, 0, 10, 10}] do
+        assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+                 Context.render_region(ctx, region, format: :svg)
+
+        assert diagnostic.message =~ "outside the page"
+      end
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.render_region(ctx, {0, 0, 10, 10}, page: 3)
+    end
+  end
+
+  describe "export_pdf encryption" do
+    test "encrypts the PDF with AES-256" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Payroll")
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>> = pdf}} =
+               Context.export_pdf(ctx,
+                 encryption: [
+                   user_password: "1234",
+                   owner_password: "s3cret",
+                   print: false,
+                   copy: false
+                 ]
+               )
+
+      assert pdf =~ "/Encrypt"
+      assert pdf =~ ~r"/V\s*5"
+      refute pdf =~ "Payroll"
+    end
+
+    test "applies to other PDF exports" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      encryption = %AshTypst.PDFEncryption{user_password: "1234", owner_password: "s3cret"}
+
+      assert {:ok, parts} = Context.split_pdf(ctx, ["1", "2-3"], encryption: encryption)
+      assert Enum.all?(parts, &(&1.data =~ "/Encrypt"))
+    end
+
+    test "rejects PDF/A and weak password settings" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Payroll")
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx,
+                 pdf_standards: [:pdf_a_2b],
+                 encryption: [owner_password: "s3cret"]
+               )
+
+      assert diagnostic.message =~ "PDF/A"
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, encryption: [user_password: "1234", owner_password: ""])
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx, encryption: [owner_password: "s3cret"])
+
+      assert diagnostic.message =~ "user_password"
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, encryption: [owner_password: "s3cret", allow_open: true])
+
+      assert pdf =~ "/Encrypt"
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx,
+                 encryption: [owner_password: "same", user_password: "same"]
+               )
+    end
+  end
+
+  describe "export_pdf metadata" do
+    test "overrides the template's document info" do
+      ctx =
+        compiled("""
+        #set document(title: "Template title", keywords: ("draft",))
+        = Invoice
+        """)
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx,
+                 metadata: [
+                   title: "Invoice 2024-001 for Acme",
+                   authors: ["Billing"],
+                   keywords: ["invoice"],
+                   language: "de-DE"
+                 ]
+               )
+
+      assert pdf =~ "Invoice 2024-001 for Acme"
+      refute pdf =~ "Template title"
+      assert pdf =~ "Billing"
+      assert pdf =~ "draft"
+      assert pdf =~ "invoice"
+      assert pdf =~ "/Lang (de-DE)"
+      assert pdf =~ "<dc:language><rdf:Bag><rdf:li>de-DE</rdf:li>"
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, metadata: [language: "de-Bavaria"])
+    end
+
+    test "adds custom XMP properties" do
+      ctx = compiled("= Invoice")
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx,
+                 pdf_standards: [:pdf_a_2b],
+                 metadata: [xmp: %{InvoiceNumber: "2024-001", Customer: "Acme & Co"}]
+               )
+
+      assert pdf =~ "<ashtypst:InvoiceNumber>2024-001</ashtypst:InvoiceNumber>"
+      assert pdf =~ "<ashtypst:Customer>Acme &amp; Co</ashtypst:Customer>"
+      assert pdf =~ "<pdfaProperty:name>InvoiceNumber</pdfaProperty:name>"
+    end
+
+    test "rejects invalid XMP property names" do
+      ctx = compiled("= Invoice")
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx, metadata: [xmp: %{"invoice number" => "1"}])
+
+      assert diagnostic.message =~ "Invalid XMP property name"
+    end
+  end
+
+  describe "accessibility_report" do
+    @logo_svg ~s(<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"/>)
+
+    test "reports missing title, language and alt text" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "logo.svg", @logo_svg)
+      Context.set_markup(ctx, "= Report\n#image(\"logo.svg\")")
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, findings} = Context.accessibility_report(ctx)
+
+      assert Enum.all?(findings, &(&1.severity == :warning and &1.hints != []))
+      messages = Enum.map(findings, & &1.message)
+      assert "The document has no title" in messages
+      assert Enum.any?(messages, &(&1 =~ "language"))
+
+      assert %AshTypst.Diagnostic{span: %{line: 2}} =
+               Enum.find(findings, &(&1.message =~ "alternative text"))
+    end
+
+    test "is empty for an accessible document" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "logo.svg", @logo_svg)
+
+      Context.set_markup(ctx, """
+      #set document(title: "Report")
+      #set text(lang: "de")
+      = Bericht
+      #image("logo.svg", alt: "Company logo")
+      """)
+
+      {:ok, _} = Context.compile(ctx)
+      assert {:ok, []} = Context.accessibility_report(ctx)
+    end
+
+    test "sees the language set by a template or an explicit English rule" do
+      {:ok, ctx} = Context.new()
+
+      :ok =
+        Context.set_virtual_file(ctx, "template.typ", """
+        #let template(lang: "en", body) = {
+          set text(lang: lang)
+          body
+        }
+        """)
+
+      Context.set_markup(ctx, """
+      #import "template.typ": template
+      #show: template.with(lang: "de")
+      = Bericht
+      """)
+
+      {:ok, _} = Context.compile(ctx)
+      assert {:ok, findings} = Context.accessibility_report(ctx)
+      refute Enum.any?(findings, &(&1.message =~ "language"))
+
+      Context.set_markup(ctx, "#set text(lang: \"en\")\n= Report")
+      {:ok, _} = Context.compile(ctx)
+      assert {:ok, findings} = Context.accessibility_report(ctx)
+      refute Enum.any?(findings, &(&1.message =~ "language"))
+    end
+
+    test "reports a missing language despite foreign text in the body" do
+      {:ok, ctx} = Context.new()
+
+      Context.set_markup(ctx, """
+      #set document(title: "Report")
+      = Report
+      The customer wrote:
+      #block[#set text(lang: "de"); Vielen Dank für die schnelle Lieferung.]
+      """)
+
+      {:ok, _} = Context.compile(ctx)
+      assert {:ok, [finding]} = Context.accessibility_report(ctx)
+      assert finding.message =~ "language"
+    end
+
+    test "ignores language rules in files the document does not use" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "unused.typ", "#set text(lang: \"de\")")
+      Context.set_markup(ctx, "= Report")
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, findings} = Context.accessibility_report(ctx)
+      assert Enum.any?(findings, &(&1.message =~ "language"))
+    end
+
+    test "tagging can be disabled" do
+      ctx = compiled("= Report")
+
+      assert {:ok, %AshTypst.PDFResult{data: tagged}} = Context.export_pdf(ctx)
+      assert {:ok, %AshTypst.PDFResult{data: untagged}} = Context.export_pdf(ctx, tagged: false)
+      assert tagged =~ "/StructTreeRoot"
+      refute untagged =~ "/StructTreeRoot"
+    end
+  end
+
+  describe "export_pdf print" do
+    test "extends pages by the bleed and slug and sets the page boxes" do
+      ctx =
+        compiled("""
+        #set page(width: 100pt, height: 100pt, fill: aqua)
+        Flyer
+        """)
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, print: [bleed: 10, crop_marks: true, mark_length: 15])
+
+      assert page_box(pdf, "MediaBox") == [0.0, 0.0, 162.0, 162.0]
+      assert page_box(pdf, "BleedBox") == [21.0, 21.0, 141.0, 141.0]
+      assert page_box(pdf, "TrimBox") == [31.0, 31.0, 131.0, 131.0]
+    end
+
+    test "bleed without marks adds no slug" do
+      ctx = compiled("#set page(width: 100pt, height: 100pt)\nFlyer")
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, print: [bleed: 8.5, registration_marks: false])
+
+      assert page_box(pdf, "BleedBox") == [0.0, 0.0, 117.0, 117.0]
+      assert page_box(pdf, "TrimBox") == [8.5, 8.5, 108.5, 108.5]
+    end
+
+    test "normalizes the nested options of a PDFOptions struct" do
+      ctx = compiled("#set page(width: 100pt, height: 100pt)\nFlyer")
+      opts = %AshTypst.PDFOptions{print: %AshTypst.PDFPrint{bleed: 10}}
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = Context.export_pdf(ctx, opts)
+      assert page_box(pdf, "TrimBox") == [10.0, 10.0, 110.0, 110.0]
+
+      opts = %{opts | encryption: [user_password: "1234", owner_password: "s3cret"]}
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = Context.merge_pdf([ctx, ctx], opts)
+      assert pdf =~ "/Encrypt"
+      assert {:ok, [_, _]} = Context.split_pdf(ctx, ["1", "1"], opts)
+    end
+
+    test "rejects a negative bleed" do
+      ctx = compiled("Flyer")
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, print: [bleed: -1])
+    end
+  end
+
+  describe "export_pdf optimize" do
+    setup do
+      photo = compiled("#set page(width: 100pt, height: 100pt, fill: gradient.linear(red, blue))")
+      {:ok, png} = Context.render_raster(photo, pixel_per_pt: 4.0)
+      {:ok, jpeg} = Context.render_raster(photo, pixel_per_pt: 4.0, format: :jpeg, quality: 100)
+
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "photo.png", png)
+      :ok = Context.set_virtual_file(ctx, "photo.jpg", jpeg)
+
+      :ok =
+        Context.set_markup(ctx, """
+        #image("photo.png", width: 1in)
+        #image("photo.jpg", width: 1in)
+        """)
+
+      {:ok, _} = Context.compile(ctx)
+      %{ctx: ctx}
+    end
+
+    test "downsamples images above the maximum DPI", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf, optimization: optimization}} =
+               Context.export_pdf(ctx, optimize: [max_dpi: 72])
+
+      assert %AshTypst.PDFOptimization{images: 2} = optimization
+      assert optimization.image_size < optimization.original_image_size
+      assert optimization.size == byte_size(pdf)
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf, optimization: optimization}} =
+               Context.export_pdf(ctx, optimize: [max_dpi: 72], linearize: true)
+
+      assert optimization.size == byte_size(pdf)
+
+      assert {:ok, %AshTypst.PDFResult{optimization: %{images: 0}}} =
+               Context.export_pdf(ctx, optimize: [max_dpi: 600])
+
+      assert {:ok, %AshTypst.PDFResult{optimization: nil}} = Context.export_pdf(ctx)
+    end
+
+    test "re-encodes JPEG photos", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{optimization: optimization}} =
+               Context.export_pdf(ctx, optimize: [jpeg_quality: 20])
+
+      assert optimization.images == 1
+      assert optimization.image_size < optimization.original_image_size
+    end
+
+    test "sets the stream compression level", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{optimization: optimization}} =
+               Context.export_pdf(ctx, optimize: [compression: 0])
+
+      assert optimization.size > optimization.original_size
+
+      assert {:ok, %AshTypst.PDFResult{}} =
+               Context.export_pdf(ctx,
+                 pdf_standards: [:pdf_a_2b],
+                 optimize: [max_dpi: 72, compression: 9]
+               )
+    end
+
+    test "rejects invalid settings", %{ctx: ctx} do
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, optimize: [jpeg_quality: 0])
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, optimize: [compression: 10])
+
+      assert {:error, %AshTypst.CompileError{}} = Context.export_pdf(ctx, optimize: [max_dpi: 0])
+    end
+  end
+
+  describe "export_pdf linearize" do
+    setup do
+      %{ctx: compiled("= Statement\n\nFirst page\n#pagebreak()\nSecond\n#pagebreak()\nThird")}
+    end
+
+    test "writes the linearization dictionary first", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = Context.export_pdf(ctx, linearize: true)
+
+      head = binary_part(pdf, 0, 1024)
+      assert [_, length] = Regex.run(~r|/Linearized 1 /L (\d+)|, head)
+      assert String.to_integer(length) == byte_size(pdf)
+      assert head =~ ~r|/H \[ \d+\s+\d+\s+\]|
+      assert head =~ ~r|/N 3 |
+
+      assert {:ok, %AshTypst.PDFResult{data: plain}} = Context.export_pdf(ctx)
+      refute plain =~ "/Linearized"
+    end
+
+    test "keeps PDF/A conformance settings", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, linearize: true, pdf_standards: [:pdf_a_2b])
+
+      assert binary_part(pdf, 0, 1024) =~ "/Linearized 1"
+      assert pdf =~ "pdfaid:part"
+      assert pdf =~ "/ID"
+    end
+
+    @tag :qpdf
+    @tag :tmp_dir
+    test "passes qpdf's linearization check", %{tmp_dir: tmp_dir} do
+      ctx =
+        compiled("""
+        #outline()
+        = One <one>
+        First #link("https://example.com")[page]
+        #pagebreak()
+        = Two
+        *Bold* text linking #link(<one>)[back]
+        #pagebreak()
+        = Three
+        More *bold* text
+        """)
+
+      path = Path.join(tmp_dir, "linearized.pdf")
+      assert {:ok, %AshTypst.WriteResult{}} = Context.write_pdf(ctx, path, linearize: true)
+
+      assert {output, 0} =
+               System.cmd("qpdf", ["--check-linearization", path], stderr_to_stdout: true)
+
+      assert output =~ "no linearization errors"
+    end
+
+    test "rejects encryption and signatures", %{ctx: ctx} do
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx,
+                 linearize: true,
+                 encryption: [user_password: "1234", owner_password: "s3cret"]
+               )
+
+      {key, certificates} = self_signed()
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx,
+                 linearize: true,
+                 signature: [key: key, certificates: certificates]
+               )
+    end
+  end
+
+  describe "export_pdf color" do
+    @cmyk_profile File.read!("test/fixtures/cmyk.icc")
+
+    test "embeds the profile as output intent" do
+      ctx = compiled(~s|#rect(fill: blue)[Flyer]|)
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf, warnings: []}} =
+               Context.export_pdf(ctx, color: [profile: @cmyk_profile, intent: :perceptual])
+
+      assert pdf =~ "/GTS_PDFX"
+      assert pdf =~ "(AshTypst Test CMYK)"
+      refute pdf =~ "/GTS_PDFA1"
+    end
+
+    test "works with PDF/A and names the output condition" do
+      ctx =
+        compiled("""
+        #set document(title: "Flyer", date: datetime(year: 2025, month: 1, day: 1))
+        #text(fill: red)[Flyer] #text(fill: cmyk(0%, 60%, 100%, 0%))[Ink]
+        #rect(fill: luma(40%))
+        """)
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf, warnings: []}} =
+               Context.export_pdf(ctx,
+                 pdf_standards: [:pdf_a_2b],
+                 color: [profile: @cmyk_profile, output_condition: "FOGRA39"]
+               )
+
+      assert pdf =~ "/GTS_PDFA1"
+      assert pdf =~ "(FOGRA39)"
+      assert pdf =~ ~r"/cmyk0 \d+ 0 R"
+    end
+
+    test "warns about raster images that are left unconverted" do
+      {:ok, png} = Context.render_raster(compiled("Logo"), pixel_per_pt: 0.1)
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "logo.png", png)
+      Context.set_markup(ctx, ~s|#image("logo.png")|)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, %AshTypst.PDFResult{warnings: [warning]}} =
+               Context.export_pdf(ctx, color: [profile: @cmyk_profile])
+
+      assert warning.message =~ "Image colours"
+    end
+
+    test "rejects invalid profiles" do
+      ctx = compiled("Flyer")
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx, color: [profile: "not a profile"])
+
+      assert diagnostic.message =~ "ICC profile"
+    end
+  end
+
+  describe "imposition" do
+    setup do
+      pages = Enum.map_join(1..5, "\n#pagebreak()\n", &"Page #{&1}")
+      %{ctx: compiled("#set page(width: 100pt, height: 150pt)\n" <> pages)}
+    end
+
+    test "places pages in a 2-up grid", %{ctx: ctx} do
+      assert {:ok, svg} = Context.render_svg(ctx, impose: [columns: 2, gutter: 10])
+      assert svg =~ ~s(width="210pt")
+      assert svg =~ ~s(height="150pt")
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, impose: [columns: 2, rows: 2])
+
+      assert page_box(pdf, "MediaBox") == [0.0, 0.0, 200.0, 300.0]
+      assert length(Regex.scan(~r"/Type\s*/Page\b", pdf)) == 2
+    end
+
+    test ":pages selects sheets of the imposed document", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, impose: [columns: 2, rows: 2], pages: "2")
+
+      assert length(Regex.scan(~r"/Type\s*/Page\b", pdf)) == 1
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, impose: [columns: 2, rows: 2], pages: "3-5")
+    end
+
+    test "scales pages to the sheet", %{ctx: ctx} do
+      assert {:ok, png} =
+               Context.render_raster(ctx,
+                 pixel_per_pt: 1.0,
+                 impose: [columns: 2, rows: 2, paper: "a4", margin: 20]
+               )
+
+      assert png_size(png) == {595, 842}
+    end
+
+    test "orders a booklet for saddle stitching", %{ctx: ctx} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, impose: [order: :booklet])
+
+      # 5 pages pad to 8, giving four sheet sides of two pages each
+      assert length(Regex.scan(~r"/Type\s*/Page\b", pdf)) == 4
+      assert page_box(pdf, "MediaBox") == [0.0, 0.0, 200.0, 150.0]
+    end
+
+    test "rejects an empty grid", %{ctx: ctx} do
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.render_svg(ctx, impose: [columns: 0])
+    end
+  end
+
+  describe "redact" do
+    setup do
+      %{
+        ctx:
+          compiled("""
+          = Statement
+          Account DE44500105175407324931 belongs to Ann.
+          #pagebreak()
+          National ID: X-123-456. Account DE44500105175407324931.
+          """)
+      }
+    end
+
+    test "removes matching text and reports it", %{ctx: ctx} do
+      assert {:ok, plain} = Context.render_svg(ctx)
+      assert {:ok, redactions} = Context.redact(ctx, [~r/DE\d{20}/, "X-123-456"])
+
+      assert [
+               %AshTypst.Redaction{page: 1, text: "DE44500105175407324931"} = first,
+               %AshTypst.Redaction{page: 2, text: "X-123-456"},
+               %AshTypst.Redaction{page: 2, text: "DE44500105175407324931"}
+             ] = redactions
+
+      assert first.width > 0 and first.height > 0
+      assert {:ok, redacted} = Context.render_svg(ctx)
+      assert redacted != plain
+
+      # The glyphs are gone, so nothing is left to match
+      assert {:ok, []} = Context.redact(ctx, [~r/DE\d{20}/, "X-123-456"])
+      assert {:ok, %AshTypst.PDFResult{}} = Context.export_pdf(ctx)
+    end
+
+    test "keeps the rest of the run", %{ctx: ctx} do
+      assert {:ok, [_, _]} = Context.redact(ctx, ["DE44500105175407324931"])
+      assert {:ok, [_]} = Context.redact(ctx, ["Ann"])
+    end
+
+    test "honours regex modifiers", %{ctx: ctx} do
+      assert {:ok, [_, _]} = Context.redact(ctx, [~r/de\d{20}/i])
+      assert {:ok, [_]} = Context.redact(ctx, [~r/x - \d{3} - \d{3} # national id/ix])
+      assert_raise ArgumentError, fn -> Context.redact(ctx, [~r/Ann/f]) end
+    end
+
+    test "redacts link targets and authors" do
+      ctx =
+        compiled("""
+        #set document(author: "ann@example.com")
+        Write #link("mailto:ann@example.com")[to us].
+        """)
+
+      assert {:ok, [link, author]} = Context.redact(ctx, ["ann@example.com"])
+      assert %AshTypst.Redaction{kind: :url, page: 1, text: "ann@example.com"} = link
+      assert link.width > 0 and link.height > 0
+
+      assert %AshTypst.Redaction{kind: :author, page: nil, text: "ann@example.com", x: nil} =
+               author
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = Context.export_pdf(ctx)
+      assert pdf =~ "mailto:[redacted]"
+      refute pdf =~ "ann@example.com"
+    end
+
+    test "refuses HTML and EPUB exports until the next compile", %{ctx: ctx} do
+      assert {:ok, _} = Context.redact(ctx, [~r/DE\d{20}/])
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_html(ctx)
+
+      assert diagnostic.message =~ "original text"
+      assert {:error, %AshTypst.CompileError{}} = Context.export_epub(ctx)
+
+      {:ok, _} = Context.compile(ctx)
+      assert {:ok, %AshTypst.HTMLResult{}} = Context.export_html(ctx)
+    end
+
+    test "rejects missing and invalid patterns", %{ctx: ctx} do
+      assert {:error, %AshTypst.CompileError{}} = Context.redact(ctx, [])
+      assert {:error, %AshTypst.CompileError{}} = Context.redact(ctx, [~r/(?<=a)b/])
+      assert {:error, %AshTypst.CompileError{}} = Context.redact(ctx, ["Ann"], color: "red!")
+    end
+  end
+
+  describe "overlays" do
+    setup do
+      %{ctx: compiled("#set page(width: 200pt, height: 200pt)\nFirst\n#pagebreak()\nSecond")}
+    end
+
+    test "stamps text on every page", %{ctx: ctx} do
+      overlay = [text: "DRAFT", rotation: -45, opacity: 0.2]
+
+      for page <- [0, 1] do
+        assert {:ok, plain} = Context.render_svg(ctx, page: page)
+        assert {:ok, stamped} = Context.render_svg(ctx, page: page, overlays: [overlay])
+        assert stamped != plain
+      end
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = Context.export_pdf(ctx, overlays: [overlay])
+      assert length(Regex.scan(~r"/Type\s*/Page\b", pdf)) == 2
+    end
+
+    test "stamps only the selected pages", %{ctx: ctx} do
+      opts = [overlays: [[text: "COPY", position: :bottom_right, dx: -10, dy: -10, pages: "2"]]]
+
+      assert {:ok, plain} = Context.render_svg(ctx, page: 0)
+      assert Context.render_svg(ctx, [page: 0] ++ opts) == {:ok, plain}
+      assert {:ok, plain} = Context.render_svg(ctx, page: 1)
+      assert {:ok, stamped} = Context.render_svg(ctx, [page: 1] ++ opts)
+      assert stamped != plain
+    end
+
+    test "applies SVG options to regions and bundle outputs", %{ctx: ctx} do
+      overlay = [text: "DRAFT", rotation: -45, opacity: 0.2]
+      region = {0, 0, 200, 200}
+
+      assert {:ok, plain} = Context.render_region(ctx, region, format: :svg)
+
+      assert {:ok, stamped} =
+               Context.render_region(ctx, region, format: :svg, overlays: [overlay])
+
+      assert stamped != plain
+
+      assert {:ok, sheet} =
+               Context.render_region(ctx, {300, 0, 50, 50}, format: :svg, impose: [columns: 2])
+
+      assert sheet =~ ~r/viewBox="0 0 50 50"/
+
+      assert {:ok, %AshTypst.BundleResult{files: files}} =
+               Context.export_bundle(ctx,
+                 svg: [name: "plain-{page}.svg"],
+                 svg: [name: "stamped-{page}.svg", overlays: [overlay]],
+                 svg: [name: "sheet-{page}.svg", impose: [columns: 2]]
+               )
+
+      assert files["stamped-1.svg"] != files["plain-1.svg"]
+      assert Map.has_key?(files, "sheet-1.svg")
+      refute Map.has_key?(files, "sheet-2.svg")
+    end
+
+    test "draws SVG and PNG images with opacity", %{ctx: ctx} do
+      logo =
+        ~s(<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">) <>
+          ~s(<rect width="20" height="20" fill="red"/></svg>)
+
+      assert {:ok, svg} =
+               Context.render_svg(ctx, overlays: [[image: logo, opacity: 0.25, width: 40]])
+
+      [data] = Regex.run(~r"data:image/svg\+xml;base64,([^\"]+)", svg, capture: :all_but_first)
+      assert Base.decode64!(data) =~ ~s(<g opacity="0.25">)
+
+      assert {:ok, png} = Context.render_raster(ctx, pixel_per_pt: 0.1)
+
+      assert {:ok, stamped} =
+               Context.render_raster(ctx, overlays: [[image: png, position: :top_left]])
+
+      assert {:ok, plain} = Context.render_raster(ctx)
+      assert stamped != plain
+    end
+
+    test "rejects invalid overlays", %{ctx: ctx} do
+      assert {:error, %AshTypst.CompileError{}} = Context.render_svg(ctx, overlays: [[]])
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.render_svg(ctx, overlays: [[text: "A", image: "<svg/>"]])
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, overlays: [[text: "A", opacity: 2]])
+    end
+  end
+
+  describe "export_pdf signature" do
+    setup do
+      {key, certificates} = self_signed()
+
+      ctx =
+        compiled("""
+        = Service Agreement
+        Signed by the parties below.
+
+        #box(width: 6cm, height: 2cm) <signature>
+        """)
+
+      %{ctx: ctx, signature: [key: key, certificates: certificates]}
+    end
+
+    test "signs the file with a detached CMS signature", %{ctx: ctx, signature: signature} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx,
+                 signature: signature ++ [reason: "Contract approval", location: "Berlin"]
+               )
+
+      assert pdf =~ "/ETSI.CAdES.detached"
+      assert pdf =~ "(Contract approval)"
+      assert pdf =~ "(Berlin)"
+
+      [a, b, c] =
+        ~r"/ByteRange\s*\[\s*0\s+(\d+)\s+(\d+)\s+(\d+)\s*\]"
+        |> Regex.run(pdf, capture: :all_but_first)
+        |> Enum.map(&String.to_integer/1)
+
+      assert b + c == byte_size(pdf)
+      assert binary_part(pdf, a, 5) == "<3082"
+      assert binary_part(pdf, b - 1, 1) == ">"
+    end
+
+    test "draws a visible signature at a label", %{ctx: ctx, signature: signature} do
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx,
+                 signature: signature ++ [label: "signature", width: 170, height: 56]
+               )
+
+      assert pdf =~ ~r"/FT\s*/Sig"
+      assert pdf =~ ~r"/AP\s*<<\s*/N"
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx, signature: signature ++ [label: "missing"])
+
+      assert diagnostic.message =~ "<missing>"
+    end
+
+    test "writes the signer text in the appearance unless PDF/A forbids it", %{ctx: ctx} do
+      {key, certificates} = self_signed("Doe, John")
+      signature = [key: key, certificates: certificates, label: "signature"]
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, signature: signature, pdf_standards: [:pdf_1_7])
+
+      assert pdf =~ "(Digitally signed by Doe, John) Tj"
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, signature: signature ++ [name: "Jörg Müller"])
+
+      assert pdf =~ "(Digitally signed by J\\366rg M\\374ller) Tj"
+
+      ctx =
+        compiled("""
+        #set document(date: datetime(year: 2025, month: 1, day: 1))
+        #box(width: 6cm, height: 2cm) <signature>
+        """)
+
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} =
+               Context.export_pdf(ctx, signature: signature, pdf_standards: [:pdf_a_2b])
+
+      refute pdf =~ "Digitally signed by"
+    end
+
+    test "rejects incomplete credentials and encryption", %{ctx: ctx, signature: signature} do
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx, signature: [key: signature[:key]])
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_pdf(ctx,
+                 signature: signature,
+                 encryption: [user_password: "1234", owner_password: "s3cret"]
+               )
+    end
+
+    test "rejects a key that does not belong to the certificate", %{
+      ctx: ctx,
+      signature: signature
+    } do
+      {other_key, _} = self_signed()
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_pdf(ctx, signature: Keyword.put(signature, :key, other_key))
+
+      assert diagnostic.message =~ "does not match"
+    end
+  end
+
+  describe "split_pdf" do
+    @letters_markup """
+    #for name in ("Ann", "Bob", "Cid") [
+      #pagebreak(weak: true)
+      #metadata(name) <letter-start>
+      Dear #name,
+      #if name == "Bob" { pagebreak() }
+      Regards
+    ]
+    """
+
+    test "splits by page ranges" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, [%AshTypst.PDFResult{data: first}, %AshTypst.PDFResult{data: second}]} =
+               Context.split_pdf(ctx, ["1", "2-3"])
+
+      assert <<"%PDF", _::binary>> = first
+      assert <<"%PDF", _::binary>> = second
+    end
+
+    test "splits at each occurrence of a label" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @letters_markup)
+      assert {:ok, %AshTypst.CompileResult{page_count: 4}} = Context.compile(ctx)
+
+      assert {:ok, parts} = Context.split_pdf(ctx, {:label, "letter-start"})
+      assert length(parts) == 3
+      assert Enum.all?(parts, &match?(%AshTypst.PDFResult{data: <<"%PDF", _::binary>>}, &1))
+    end
+
+    # typst-pdf fails to export tagged PDFs with page ranges, so each part is
+    # exported as a document of its own.
+    test "keeps parts tagged" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:ok, parts} = Context.split_pdf(ctx, ["1", "2", "3"], tagged: true)
+      assert length(parts) == 3
+      assert Enum.all?(parts, &(&1.data =~ "/StructTreeRoot"))
+    end
+
+    test "gives each part its own document ID" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @letters_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      for opts <- [[], [document_id: "mail-merge"]] do
+        assert {:ok, parts} = Context.split_pdf(ctx, {:label, "letter-start"}, opts)
+
+        ids =
+          Enum.map(parts, fn %AshTypst.PDFResult{data: data} ->
+            [_, id] = Regex.run(~r{/ID\s*\[\s*[(<]([^)>]*)[)>]}, data)
+            id
+          end)
+
+        assert length(Enum.uniq(ids)) == 3
+      end
+    end
+
+    test "rejects unknown labels, bad ranges and the pages option" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @letters_markup)
+      {:ok, _} = Context.compile(ctx)
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.split_pdf(ctx, {:label, "missing"})
+
+      assert diagnostic.message =~ "not found"
+
+      assert {:error, %AshTypst.CompileError{}} = Context.split_pdf(ctx, ["1", "9"])
+      assert {:error, %AshTypst.CompileError{}} = Context.split_pdf(ctx, ["1"], pages: "1")
+    end
+  end
+
+  describe "merge_pdf" do
+    test "merges documents from several contexts" do
+      cover = compiled("#set document(title: \"Packet\")\n= Cover Letter")
+      invoice = compiled(@multipage_markup)
+      terms = compiled("#set page(numbering: \"i\")\n= Terms")
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>> = pdf}} =
+               Context.merge_pdf([cover, invoice, terms])
+
+      assert pdf =~ "Packet"
+      assert pdf =~ "/PageLabels"
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>>}} =
+               Context.merge_pdf([cover, terms], pages: "2")
+    end
+
+    test "the same context can appear more than once" do
+      ctx = compiled("#outline()\n= Repeated <repeated>\n#link(<repeated>)[Back]")
+
+      assert {:ok, %AshTypst.PDFResult{data: <<"%PDF", _::binary>> = pdf}} =
+               Context.merge_pdf([ctx, ctx])
+
+      assert pdf =~ "/Outlines"
+    end
+
+    test "moves links to page positions with their document" do
+      first = compiled("= One\n#pagebreak()\n= Two")
+      second = compiled("= Three\n#link((page: 1, x: 0pt, y: 0pt))[Top]")
 
-      assert is_list(warnings)
-      assert {:ok, svg} = Context.render_svg(ctx)
-      assert String.contains?(svg, "<svg")
-      assert String.length(svg) > 1000
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = Context.merge_pdf([first, second])
+
+      [_, kids] = Regex.run(~r"/Kids\s*\[([^\]]*)\]", pdf)
+      pages = Regex.scan(~r"(\d+) 0 R", kids, capture: :all_but_first)
+      [_, dest] = Regex.run(~r"/Subtype /Link.*?/Dest (\d+) 0 R"s, pdf)
+      [_, target] = Regex.run(~r"\b#{dest} 0 obj\s*\[\s*(\d+) 0 R", pdf)
+      assert [target] == Enum.at(pages, 2)
     end
 
-    test "compile returns page count" do
-      {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @multipage_markup)
-      assert {:ok, %AshTypst.CompileResult{page_count: 3}} = Context.compile(ctx)
+    test "requires compiled documents" do
+      {:ok, uncompiled} = Context.new()
+      Context.set_markup(uncompiled, "= Not compiled")
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.merge_pdf([compiled("= Ok"), uncompiled])
+
+      assert diagnostic.message =~ "context 1"
+      assert {:error, %AshTypst.CompileError{}} = Context.merge_pdf([])
     end
+  end
 
-    test "render specific page" do
+  describe "export_bundle" do
+    test "returns every requested output from one compile" do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @multipage_markup)
+      Context.set_markup(ctx, @multipage_markup)
       {:ok, _} = Context.compile(ctx)
 
-      assert {:ok, svg0} = Context.render_svg(ctx, page: 0)
-      assert {:ok, svg2} = Context.render_svg(ctx, page: 2)
-      assert String.contains?(svg0, "<svg")
-      assert String.contains?(svg2, "<svg")
-      assert svg0 != svg2
+      assert {:ok, %AshTypst.BundleResult{files: files, archive: nil, warnings: []}} =
+               Context.export_bundle(ctx, [
+                 :pdf,
+                 png: [name: "thumbnail.png", pages: "1", pixel_per_pt: 0.5],
+                 jpeg: [name: "thumbnail.jpg", pages: "1", width: 160],
+                 svg: []
+               ])
+
+      assert Enum.sort(Map.keys(files)) ==
+               [
+                 "document.pdf",
+                 "page-1.svg",
+                 "page-2.svg",
+                 "page-3.svg",
+                 "thumbnail.jpg",
+                 "thumbnail.png"
+               ]
+
+      assert <<"%PDF", _::binary>> = files["document.pdf"]
+      assert <<0x89, "PNG", _::binary>> = files["thumbnail.png"]
+      assert <<0xFF, 0xD8, _::binary>> = files["thumbnail.jpg"]
+      assert files["page-3.svg"] =~ "<svg"
     end
 
-    test "render out-of-bounds page returns error" do
+    test "packages outputs as a ZIP archive" do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @test_markup)
+      Context.set_markup(ctx, @multipage_markup)
       {:ok, _} = Context.compile(ctx)
 
-      assert {:error, %AshTypst.CompileError{diagnostics: [_ | _]}} =
-               Context.render_svg(ctx, page: 99)
+      assert {:ok, %AshTypst.BundleResult{files: files, archive: archive}} =
+               Context.export_bundle(ctx, [pdf: [name: "doc.pdf"], svg: [pages: "2"]], zip: true)
+
+      assert files == %{}
+      assert {:ok, entries} = :zip.unzip(archive, [:memory])
+      assert Enum.sort(Enum.map(entries, &to_string(elem(&1, 0)))) == ["doc.pdf", "page-2.svg"]
     end
 
-    test "render without compile returns error" do
+    test "rejects ambiguous and duplicate names" do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @test_markup)
+      Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
 
-      assert {:error, %AshTypst.CompileError{diagnostics: [_ | _]}} =
-               Context.render_svg(ctx)
-    end
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_bundle(ctx, svg: [name: "page.svg"])
 
-    test "invalid markup returns compile error with diagnostics" do
-      {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @invalid_markup)
-      assert {:error, %AshTypst.CompileError{diagnostics: diagnostics}} = Context.compile(ctx)
-      assert diagnostics != []
+      assert diagnostic.message =~ "{page}"
 
-      [diag | _] = diagnostics
-      assert diag.severity == :error
-      assert is_binary(diag.message)
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_bundle(ctx, [:pdf, pdf: [pages: "1"]])
+
+      assert diagnostic.message =~ "Duplicate output name"
     end
 
-    test "re-set markup + recompile produces different SVG" do
+    test "names files after the output format" do
       {:ok, ctx} = Context.new()
-
-      :ok = Context.set_markup(ctx, "= First")
+      Context.set_markup(ctx, @multipage_markup)
       {:ok, _} = Context.compile(ctx)
-      {:ok, svg1} = Context.render_svg(ctx)
 
-      :ok = Context.set_markup(ctx, "= Second")
-      {:ok, _} = Context.compile(ctx)
-      {:ok, svg2} = Context.render_svg(ctx)
+      assert {:ok, %AshTypst.BundleResult{files: files}} =
+               Context.export_bundle(ctx, webp: [pages: "1", format: :png])
 
-      assert svg1 != svg2
+      assert <<"RIFF", _::binary-size(4), "WEBP", _::binary>> = files["page-1.webp"]
+
+      output = %AshTypst.BundleOutput{
+        format: :webp,
+        raster_options: %AshTypst.RasterOptions{format: :png}
+      }
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_bundle(ctx, [output])
+
+      assert diagnostic.message =~ "does not match"
     end
-  end
 
-  describe "export_pdf" do
-    test "returns proper PDF binary" do
+    test "requires a compiled document" do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @test_markup)
-      {:ok, _} = Context.compile(ctx)
+      Context.set_markup(ctx, "= Not compiled")
 
-      assert {:ok, <<"%PDF", _rest::binary>>} = Context.export_pdf(ctx)
+      assert {:error, %AshTypst.CompileError{}} = Context.export_bundle(ctx, [:pdf])
     end
+  end
 
-    test "with page range produces output" do
+  describe "write to file" do
+    @describetag :tmp_dir
+
+    test "write_pdf writes the PDF and returns size and checksum", %{tmp_dir: tmp_dir} do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @multipage_markup)
+      Context.set_markup(ctx, @multipage_markup)
       {:ok, _} = Context.compile(ctx)
 
-      assert {:ok, <<"%PDF", _::binary>> = full_pdf} = Context.export_pdf(ctx)
+      path = Path.join(tmp_dir, "out.pdf")
 
-      # typst-pdf tagged PDF bug may cause page range export to fail
-      case Context.export_pdf(ctx, pages: "1") do
-        {:ok, <<"%PDF", _::binary>> = partial_pdf} ->
-          assert byte_size(partial_pdf) < byte_size(full_pdf)
+      assert {:ok, %AshTypst.WriteResult{path: ^path, size: size, sha256: sha256, warnings: []}} =
+               Context.write_pdf(ctx, path, pages: "1-2")
 
-        {:error, %AshTypst.CompileError{}} ->
-          :ok
-      end
+      data = File.read!(path)
+      assert <<"%PDF", _::binary>> = data
+      assert size == byte_size(data)
+      assert sha256 == Base.encode16(:crypto.hash(:sha256, data), case: :lower)
     end
 
-    test "with standards" do
+    test "write_svg writes the requested page", %{tmp_dir: tmp_dir} do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @test_markup_with_date)
+      Context.set_markup(ctx, @multipage_markup)
       {:ok, _} = Context.compile(ctx)
 
-      assert {:ok, <<"%PDF", _::binary>>} =
-               Context.export_pdf(ctx, pdf_standards: [:pdf_a_2b])
+      path = Path.join(tmp_dir, "page.svg")
+      assert {:ok, %AshTypst.WriteResult{size: size}} = Context.write_svg(ctx, path, page: 1)
+      assert {:ok, svg} = Context.render_svg(ctx, page: 1)
+      assert File.read!(path) == svg
+      assert size == byte_size(svg)
     end
 
-    test "with document_id" do
+    test "write_raster writes a PNG image", %{tmp_dir: tmp_dir} do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @test_markup)
+      Context.set_markup(ctx, "= Hello PNG")
       {:ok, _} = Context.compile(ctx)
 
-      assert {:ok, <<"%PDF", _::binary>>} =
-               Context.export_pdf(ctx, document_id: "test-42")
+      path = Path.join(tmp_dir, "page.png")
+      assert {:ok, %AshTypst.WriteResult{}} = Context.write_raster(ctx, path, pixel_per_pt: 1)
+      assert <<0x89, "PNG", _::binary>> = File.read!(path)
     end
 
-    test "without compile returns error" do
+    test "replaces existing files without leaving temporary files", %{tmp_dir: tmp_dir} do
       {:ok, ctx} = Context.new()
-      :ok = Context.set_markup(ctx, @test_markup)
+      Context.set_markup(ctx, @multipage_markup)
+      {:ok, _} = Context.compile(ctx)
 
-      assert {:error, %AshTypst.CompileError{}} = Context.export_pdf(ctx)
+      path = Path.join(tmp_dir, "page.svg")
+      File.write!(path, "stale")
+
+      assert {:ok, %AshTypst.WriteResult{}} = Context.write_svg(ctx, path, page: 2)
+      assert {:ok, svg} = Context.render_svg(ctx, page: 2)
+      assert File.read!(path) == svg
+      assert File.ls!(tmp_dir) == ["page.svg"]
+    end
+
+    test "returns errors for bad pages, missing documents and unwritable paths", %{
+      tmp_dir: tmp_dir
+    } do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Errors")
+      path = Path.join(tmp_dir, "out.pdf")
+
+      assert {:error, %AshTypst.CompileError{}} = Context.write_pdf(ctx, path)
+
+      {:ok, _} = Context.compile(ctx)
+      assert {:error, %AshTypst.CompileError{}} = Context.write_svg(ctx, path, page: 5)
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.write_pdf(ctx, Path.join([tmp_dir, "missing", "out.pdf"]))
+
+      assert diagnostic.message =~ "Failed to write"
     end
   end
 
@@ -245,6 +1535,28 @@ defmodule AshTypst.ContextTest do
     end
   end
 
+  describe "plugins" do
+    # (module (memory (export "memory") 1))
+    @empty_wasm <<0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01,
+                  0x07, 0x0A, 0x01, 0x06, "memory", 0x02, 0x00>>
+
+    test "set_plugin makes the plugin loadable from templates" do
+      {:ok, ctx} = Context.new()
+      assert :ok = Context.set_plugin(ctx, "empty.wasm", @empty_wasm)
+      :ok = Context.set_markup(ctx, "#let p = plugin(\"empty.wasm\")\nLoaded")
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "set_plugin rejects invalid WebAssembly" do
+      {:ok, ctx} = Context.new()
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diag]}} =
+               Context.set_plugin(ctx, "bad.wasm", "not wasm")
+
+      assert diag.message =~ "bad.wasm"
+    end
+  end
+
   describe "enhanced diagnostics" do
     test "diagnostics include line/column" do
       {:ok, ctx} = Context.new()
@@ -277,13 +1589,81 @@ defmodule AshTypst.ContextTest do
     end
   end
 
+  describe "globals" do
+    test "set_global is available without an import" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_global(ctx, "company", %{name: "Acme Corp", vat: 0.19})
+      :ok = Context.set_markup(ctx, "#company.name (#company.vat)")
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "set_global_module exposes top-level bindings" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_global_module(ctx, "#let money(x) = str(calc.round(x, digits: 2))")
+      :ok = Context.set_markup(ctx, "#money(12.345)")
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "set_global_module with :as binds the module by name" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_global_module(ctx, "#let shout(x) = upper(x)", as: "fmt")
+      :ok = Context.set_markup(ctx, "#fmt.shout(\"hi\")")
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "globals survive input changes" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_global(ctx, "answer", 42)
+      :ok = Context.set_input(ctx, "title", "Hello")
+      :ok = Context.set_markup(ctx, "#answer #sys.inputs.title")
+      assert {:ok, _} = Context.compile(ctx)
+    end
+
+    test "invalid module source returns diagnostics" do
+      {:ok, ctx} = Context.new()
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic | _]}} =
+               Context.set_global_module(ctx, "#let ok = 1\n#let broken = ")
+
+      assert %AshTypst.Span{line: 2, column: column} = diagnostic.span
+      assert is_integer(column)
+    end
+
+    test "global names must be Typst identifiers" do
+      {:ok, ctx} = Context.new()
+
+      for name <- ["1x", "has space", ""] do
+        assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+                 Context.set_global(ctx, name, 1)
+
+        assert diagnostic.message =~ "not a Typst identifier"
+      end
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.set_global_module(ctx, "#let x = 1", as: "1fmt")
+    end
+
+    test "clear_globals removes bindings" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_global(ctx, "answer", 42)
+      :ok = Context.set_markup(ctx, "#answer")
+      assert {:ok, _} = Context.compile(ctx)
+
+      :ok = Context.clear_globals(ctx)
+      assert {:error, %AshTypst.CompileError{}} = Context.compile(ctx)
+    end
+  end
+
   describe "export_html" do
     test "returns HTML string" do
       {:ok, ctx} = Context.new()
       Context.set_markup(ctx, "= Hello HTML")
 
-      assert {:ok, html} = Context.export_html(ctx)
+      assert {:ok, %AshTypst.HTMLResult{data: html, warnings: warnings}} =
+               Context.export_html(ctx)
+
       assert is_binary(html)
+      assert is_list(warnings)
       assert String.contains?(html, "<!DOCTYPE html>") or String.contains?(html, "<html")
     end
 
@@ -291,8 +1671,353 @@ defmodule AshTypst.ContextTest do
       {:ok, ctx} = Context.new()
       Context.set_markup(ctx, "= No prior compile needed")
 
-      assert {:ok, html} = Context.export_html(ctx)
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
       assert is_binary(html)
     end
+
+    test "uses the HTML document from compile target: :html" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Cached HTML")
+
+      assert {:ok, %AshTypst.CompileResult{page_count: 0}} = Context.compile(ctx, target: :html)
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Cached HTML"
+
+      assert {:error, %AshTypst.CompileError{}} = Context.render_svg(ctx)
+    end
+
+    test "target: :both builds paged and HTML output in one compile" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, @multipage_markup)
+
+      assert {:ok, %AshTypst.CompileResult{page_count: 3}} = Context.compile(ctx, target: :both)
+      assert {:ok, svg} = Context.render_svg(ctx, page: 2)
+      assert svg =~ "<svg"
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Page Three"
+    end
+
+    test "returns warnings from the HTML compile" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "#place[Ignored in HTML]")
+
+      assert {:ok, %AshTypst.HTMLResult{warnings: [_ | _] = warnings}} = Context.export_html(ctx)
+      assert Enum.all?(warnings, &(&1.severity == :warning))
+    end
+
+    test "set_markup invalidates the cached HTML document" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= First")
+      {:ok, _} = Context.compile(ctx, target: :html)
+
+      Context.set_markup(ctx, "= Second")
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Second"
+    end
+
+    test "inputs, globals, and appended files invalidate the cached HTML document" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_input(ctx, "title", "First")
+      :ok = Context.set_markup(ctx, ~s(#include "body.typ"\n#sys.inputs.title))
+      :ok = Context.set_virtual_file(ctx, "body.typ", "Body")
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "First"
+
+      :ok = Context.set_input(ctx, "title", "Second")
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Second"
+      refute html =~ "First"
+
+      :ok = Context.set_inputs(ctx, %{"title" => "Third"})
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Third"
+
+      :ok = Context.append_virtual_file(ctx, "body.typ", " Appended")
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Body Appended"
+
+      :ok = Context.set_markup(ctx, "#title")
+      :ok = Context.set_global(ctx, "title", "Global")
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Global"
+
+      :ok = Context.set_global(ctx, "title", "Changed")
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx)
+      assert html =~ "Changed"
+
+      :ok = Context.clear_globals(ctx)
+      assert {:error, %AshTypst.CompileError{}} = Context.export_html(ctx)
+    end
+  end
+
+  describe "export_html options" do
+    @svg_image ~s(<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10"/></svg>)
+
+    test "body_only returns a fragment" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Fragment")
+
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx, body_only: true)
+      assert html =~ "Fragment"
+      refute html =~ "<html"
+      refute html =~ "<body"
+    end
+
+    test "head content is injected into <head>" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Styled")
+
+      head = ~s(<link rel="stylesheet" href="/app.css">)
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx, head: head)
+      assert [_, head_section] = String.split(html, "<head>", parts: 2)
+      assert [inner, _] = String.split(head_section, "</head>", parts: 2)
+      assert inner =~ head
+    end
+
+    test "head content gets a <head> when the document builds its own <html>" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "#html.html(html.body[Custom root])")
+
+      head = ~s(<meta name="x">)
+      assert {:ok, %AshTypst.HTMLResult{data: html}} = Context.export_html(ctx, head: head)
+      assert [_, head_section] = String.split(html, "<head>", parts: 2)
+      assert [inner, body] = String.split(head_section, "</head>", parts: 2)
+      assert inner =~ head
+      assert body =~ "Custom root"
+    end
+
+    test "body_only and head cannot be combined" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Both")
+
+      assert {:error, %AshTypst.CompileError{}} =
+               Context.export_html(ctx, body_only: true, head: "<meta name=\"x\">")
+    end
+
+    test "images are inlined as data URIs by default" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "logo.svg", @svg_image)
+      Context.set_markup(ctx, ~s(#image("logo.svg")))
+
+      assert {:ok, %AshTypst.HTMLResult{data: html, assets: assets}} = Context.export_html(ctx)
+      assert html =~ "data:image/svg+xml"
+      assert assets == %{}
+    end
+
+    test "images: :assets extracts images with the given prefix" do
+      {:ok, ctx} = Context.new()
+      :ok = Context.set_virtual_file(ctx, "logo.svg", @svg_image)
+      Context.set_markup(ctx, ~s(#image("logo.svg")))
+
+      assert {:ok, %AshTypst.HTMLResult{data: html, assets: assets}} =
+               Context.export_html(ctx, images: :assets, asset_prefix: "cid:")
+
+      assert [{name, data}] = Map.to_list(assets)
+      assert name =~ ~r/\.svg$/
+      assert data =~ "<svg"
+      assert html =~ ~s(src="cid:#{name}")
+      refute html =~ "data:image/svg+xml"
+    end
+  end
+
+  describe "export_epub" do
+    test "returns a zipped EPUB with the mimetype entry first" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Hello EPUB\nSome text.")
+
+      assert {:ok, %AshTypst.EPUBResult{data: <<"PK", _::binary>> = epub, warnings: warnings}} =
+               Context.export_epub(ctx)
+
+      assert is_list(warnings)
+      assert [{"mimetype", "application/epub+zip"} | rest] = unzip(epub)
+
+      names = Enum.map(rest, &elem(&1, 0))
+      assert "META-INF/container.xml" in names
+      assert "OEBPS/content.opf" in names
+      assert "OEBPS/nav.xhtml" in names
+    end
+
+    test "splits chapters at top-level headings and builds the outline" do
+      {:ok, ctx} = Context.new()
+
+      Context.set_markup(ctx, """
+      = Introduction
+      Welcome.
+      == Scope
+      What is covered.
+      = Usage
+      How to use it.
+      """)
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} = Context.export_epub(ctx)
+      files = Map.new(unzip(epub))
+
+      assert files["OEBPS/chapter-001.xhtml"] =~ "Welcome."
+      refute files["OEBPS/chapter-001.xhtml"] =~ "How to use it."
+      assert files["OEBPS/chapter-002.xhtml"] =~ "How to use it."
+      refute Map.has_key?(files, "OEBPS/chapter-003.xhtml")
+
+      nav = files["OEBPS/nav.xhtml"]
+      assert nav =~ ~s(<a href="chapter-001.xhtml">Introduction</a>)
+      assert nav =~ ~r/<a href="chapter-001.xhtml#[^"]+">Scope<\/a>/
+      assert nav =~ ~s(<a href="chapter-002.xhtml">Usage</a>)
+
+      opf = files["OEBPS/content.opf"]
+      assert opf =~ ~s(<itemref idref="chapter-1" />)
+      assert opf =~ ~s(<itemref idref="chapter-2" />)
+    end
+
+    test "nests skipped heading levels without doubling lists" do
+      {:ok, ctx} = Context.new()
+
+      Context.set_markup(ctx, """
+      = Chapter
+      == Section
+      ==== Deep
+      === Middle
+      ==== Deeper
+      == Next
+      """)
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} = Context.export_epub(ctx)
+      nav = Map.new(unzip(epub))["OEBPS/nav.xhtml"]
+
+      items = String.split(nav, "<li>")
+      refute Enum.any?(items, &(length(String.split(&1, "<ol>")) > 2))
+      assert length(String.split(nav, "<ol>")) == length(String.split(nav, "</ol>"))
+      assert nav =~ ~r/>Middle<\/a>\n<ol>\n<li><a [^>]+>Deeper</
+    end
+
+    test "uses document metadata unless overridden" do
+      {:ok, ctx} = Context.new()
+
+      Context.set_markup(ctx, """
+      #set document(title: "Manual", author: "Jane Doe")
+      #set text(lang: "de")
+      = Kapitel
+      """)
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} = Context.export_epub(ctx)
+      opf = Map.new(unzip(epub))["OEBPS/content.opf"]
+      assert opf =~ "<dc:title>Manual</dc:title>"
+      assert opf =~ "<dc:creator>Jane Doe</dc:creator>"
+      assert opf =~ "<dc:language>de</dc:language>"
+      assert opf =~ ~r/<dc:identifier id="uid">urn:uuid:[0-9a-f-]{36}<\/dc:identifier>/
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} =
+               Context.export_epub(ctx, title: "Handbook", identifier: "urn:isbn:9780000000000")
+
+      opf = Map.new(unzip(epub))["OEBPS/content.opf"]
+      assert opf =~ "<dc:title>Handbook</dc:title>"
+      assert opf =~ "urn:isbn:9780000000000"
+    end
+
+    test "embeds images" do
+      {:ok, ctx} = Context.new()
+
+      :ok =
+        Context.set_virtual_file(
+          ctx,
+          "logo.svg",
+          ~s(<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10"/></svg>)
+        )
+
+      Context.set_markup(ctx, ~s(= Logo\n#image("logo.svg")))
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} = Context.export_epub(ctx)
+      files = Map.new(unzip(epub))
+
+      assert [image] = Enum.filter(Map.keys(files), &String.starts_with?(&1, "OEBPS/images/"))
+      assert files["OEBPS/chapter-001.xhtml"] =~ ~s(src="#{String.trim_leading(image, "OEBPS/")}")
+      assert files["OEBPS/content.opf"] =~ ~s(media-type="image/svg+xml")
+    end
+
+    test "embeds requested font families" do
+      {:ok, ctx} = Context.new(ignore_system_fonts: true)
+      Context.set_markup(ctx, "= Fonts")
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} =
+               Context.export_epub(ctx, fonts: ["Libertinus Serif"])
+
+      files = Map.new(unzip(epub))
+      assert Enum.any?(Map.keys(files), &String.starts_with?(&1, "OEBPS/fonts/"))
+      assert files["OEBPS/style.css"] =~ "@font-face"
+      assert files["OEBPS/style.css"] =~ ~s(font-family: "Libertinus Serif")
+    end
+
+    test "embeds the font families the document uses" do
+      {:ok, ctx} = Context.new(ignore_system_fonts: true)
+      Context.set_markup(ctx, ~s(= Fonts\n#text(font: "DejaVu Sans Mono")[Code]))
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub}} = Context.export_epub(ctx)
+
+      files = Map.new(unzip(epub))
+      fonts = Enum.filter(Map.keys(files), &String.starts_with?(&1, "OEBPS/fonts/"))
+      assert length(fonts) >= 2
+      assert files["OEBPS/style.css"] =~ ~r/font-family: "libertinus serif"/i
+      assert files["OEBPS/style.css"] =~ ~r/font-family: "dejavu sans mono"/i
+      assert files["OEBPS/content.opf"] =~ ~s(media-type="font/)
+    end
+
+    # Tuffy (public domain), subset and with its OS/2 fsType set to restricted
+    test "leaves out document fonts that cannot be embedded, with a warning" do
+      {:ok, ctx} = Context.new(font_paths: ["test/fixtures/fonts"])
+      Context.set_markup(ctx, ~s(= Fonts\n#text(font: "Tuffy")[Restricted]))
+
+      assert {:ok, %AshTypst.EPUBResult{data: epub, warnings: warnings}} =
+               Context.export_epub(ctx)
+
+      assert Enum.any?(warnings, &(&1.severity == :warning and &1.message =~ ~s("Tuffy")))
+      refute Map.new(unzip(epub))["OEBPS/style.css"] =~ "Tuffy"
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_epub(ctx, fonts: ["Tuffy"])
+
+      assert diagnostic.message =~ "restricts embedding"
+    end
+
+    test "rejects unknown font families" do
+      {:ok, ctx} = Context.new()
+      Context.set_markup(ctx, "= Fonts")
+
+      assert {:error, %AshTypst.CompileError{diagnostics: [diagnostic]}} =
+               Context.export_epub(ctx, fonts: ["No Such Font"])
+
+      assert diagnostic.message =~ "Unknown font family"
+    end
+  end
+
+  defp unzip(epub) do
+    {:ok, files} = :zip.unzip(epub, [:memory])
+    Enum.map(files, fn {name, data} -> {to_string(name), data} end)
+  end
+
+  defp png_size(<<0x89, "PNG", _::binary-size(12), w::32, h::32, _::binary>>), do: {w, h}
+
+  defp compiled(markup) do
+    {:ok, ctx} = Context.new()
+    Context.set_markup(ctx, markup)
+    {:ok, _} = Context.compile(ctx)
+    ctx
+  end
+
+  defp self_signed(name \\ "AshTypst Test Signer") do
+    %{cert: cert, key: key} =
+      :public_key.pkix_test_root_cert(to_charlist(name), key: {:rsa, 2048, 65_537})
+
+    key_pem = :public_key.pem_encode([:public_key.pem_entry_encode(:RSAPrivateKey, key)])
+    certificate_pem = :public_key.pem_encode([{:Certificate, cert, :not_encrypted}])
+    {key_pem, certificate_pem}
+  end
+
+  defp page_box(pdf, name) do
+    [numbers] = Regex.run(~r"/#{name}\s*\[([^\]]*)\]", pdf, capture: :all_but_first)
+
+    numbers
+    |> String.split()
+    |> Enum.map(fn number ->
+      {value, ""} = Float.parse(number)
+      Float.round(value, 2)
+    end)
   end
 end
@@ -54,6 +54,28 @@ defmodule AshTypst.ResourceTest do
         template(:simple)
         format(:pdf)
       end
+
+      render :render_epub_greeting do
+        template(:greeting)
+        format(:epub)
+
+        argument :name, :string, allow_nil?: false
+
+        epub_options do
+          title "Greeting"
+        end
+      end
+
+      render :render_html_fragment do
+        template(:greeting)
+        format(:html)
+
+        argument :name, :string, allow_nil?: false
+
+        html_options do
+          body_only true
+        end
+      end
     end
   end
 
@@ -78,6 +100,25 @@ defmodule AshTypst.ResourceTest do
     end
   end
 
+  defmodule StyledResource do
+    use Ash.Resource,
+      domain: AshTypst.ResourceTest.TestDomain,
+      extensions: [AshTypst.Resource]
+
+    typst do
+      default_styles(paper: "a5", margin: 20, lang: "de", justify: true)
+
+      template :doc do
+        markup("= Styled")
+      end
+
+      render :render_svg do
+        template(:doc)
+        format(:svg)
+      end
+    end
+  end
+
   # --- Tests ---
 
   describe "DSL compilation" do
@@ -117,6 +158,9 @@ defmodule AshTypst.ResourceTest do
 
       assert {:ok, false} =
                AshTypst.Resource.Info.typst_ignore_system_fonts(InlineTemplateResource)
+
+      assert {:ok, []} = AshTypst.Resource.Info.typst_default_styles(InlineTemplateResource)
+      assert {:ok, [:html]} = AshTypst.Resource.Info.typst_features(InlineTemplateResource)
     end
   end
 
@@ -197,6 +241,27 @@ defmodule AshTypst.ResourceTest do
       assert is_binary(doc.data)
     end
 
+    test "renders inline template as EPUB" do
+      input =
+        Ash.ActionInput.for_action(InlineTemplateResource, :render_epub_greeting, %{name: "EPUB"})
+
+      assert {:ok, %AshTypst.Document{format: :epub, data: <<"PK", _::binary>>}} =
+               Ash.run_action(input)
+    end
+
+    test "renders an HTML fragment with html_options" do
+      input =
+        Ash.ActionInput.for_action(InlineTemplateResource, :render_html_fragment, %{
+          name: "Fragment"
+        })
+
+      assert {:ok, %AshTypst.Document{format: :html, data: html, assets: %{}}} =
+               Ash.run_action(input)
+
+      assert html =~ "Hello Fragment"
+      refute html =~ "<html"
+    end
+
     test "renders template with no arguments (no read)" do
       input = Ash.ActionInput.for_action(InlineTemplateResource, :render_simple, %{})
 
@@ -207,6 +272,16 @@ defmodule AshTypst.ResourceTest do
       assert <<"%PDF", _::binary>> = doc.data
     end
 
+    test "renders with default_styles from the typst section" do
+      assert {:ok, styles} = AshTypst.Resource.Info.typst_default_styles(StyledResource)
+      assert styles[:paper] == "a5"
+
+      input = Ash.ActionInput.for_action(StyledResource, :render_svg, %{})
+
+      assert {:ok, %AshTypst.Document{format: :svg, data: svg}} = Ash.run_action(input)
+      assert svg =~ "<svg"
+    end
+
     test "renders PDF with pdf_options" do
       input = Ash.ActionInput.for_action(PdfOptionsResource, :render_pdf, %{})
 
@@ -301,6 +376,66 @@ defmodule AshTypst.ResourceTest do
 
       assert warnings =~ "`pdf_options` is only valid"
     end
+
+    test "ValidateFormatOptions catches html_options with non-html format" do
+      warnings =
+        ExUnit.CaptureIO.capture_io(:stderr, fn ->
+          Code.compile_string("""
+          defmodule AshTypst.ResourceTest.BadHtmlOptions do
+            use Ash.Resource,
+              domain: AshTypst.ResourceTest.TestDomain,
+              extensions: [AshTypst.Resource]
+
+            typst do
+              template :doc do
+                markup "= Test"
+              end
+
+              render :bad_html_opts do
+                template :doc
+                format :pdf
+
+                html_options do
+                  body_only true
+                end
+              end
+            end
+          end
+          """)
+        end)
+
+      assert warnings =~ "`html_options` is only valid"
+    end
+  end
+
+  describe "features" do
+    test "ValidateFormatOptions catches html format without the :html feature" do
+      warnings =
+        ExUnit.CaptureIO.capture_io(:stderr, fn ->
+          Code.compile_string("""
+          defmodule AshTypst.ResourceTest.MissingHtmlFeature do
+            use Ash.Resource,
+              domain: AshTypst.ResourceTest.TestDomain,
+              extensions: [AshTypst.Resource]
+
+            typst do
+              features []
+
+              template :doc do
+                markup "= Test"
+              end
+
+              render :html do
+                template :doc
+                format :html
+              end
+            end
+          end
+          """)
+        end)
+
+      assert warnings =~ "requires the `:html` feature"
+    end
   end
 
   describe "file-based template" do
@@ -322,7 +457,7 @@ defmodule AshTypst.ResourceTest do
       :ok = AshTypst.Context.set_virtual_file(ctx, "data.typ", data)
 
       assert {:ok, %AshTypst.CompileResult{page_count: 1}} = AshTypst.Context.compile(ctx)
-      assert {:ok, pdf} = AshTypst.Context.export_pdf(ctx)
+      assert {:ok, %AshTypst.PDFResult{data: pdf}} = AshTypst.Context.export_pdf(ctx)
       assert <<"%PDF", _::binary>> = pdf
     end
   end